@@ -0,0 +1,399 @@
+use crate::boid::Boid;
+use crate::config::SimulationConfig;
+use crate::species::{Interaction, SpeciesId};
+use glam::Vec2;
+use std::fmt;
+
+/// Rules that steer towards a target velocity close this fraction of the gap
+/// per 1/`STEERING_RATE` seconds, so their factors keep the meaning they had
+/// when the simulation ran one step per 60 Hz frame.
+pub const STEERING_RATE: f32 = 60.0;
+
+/// Neighbours used by starlings in the topological flocking studies.
+pub const TOPOLOGICAL_NEIGHBORS: usize = 7;
+
+/// Which neighbours a boid interacts with.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum NeighborMode {
+    /// Everyone within the species' perception region.
+    #[default]
+    Metric,
+    /// The `k` nearest boids it can see, however far away. Their distances
+    /// stretch the follow radius so cohesion and alignment use all of them;
+    /// separation still only reacts within `avoid_radius`.
+    Topological { k: usize },
+}
+
+/// A boid seen by another boid during `navigate`.
+#[derive(Clone)]
+pub struct Neighbor {
+    pub boid: Boid,
+    /// Vector from the steering boid to this neighbour.
+    pub offset: Vec2,
+    pub distance_squared: f32,
+    /// How the steering boid's species treats this neighbour's species.
+    /// Neighbours it ignores are never passed to behaviours.
+    pub interaction: Interaction,
+    /// How much this neighbour counts, from the species' distance and angle
+    /// weighting; 1 when weighting is off.
+    pub weight: f32,
+}
+
+impl Neighbor {
+    /// Describes `other`, at `offset` from `boid`, as seen by `boid`, or
+    /// returns `None` if it falls in the blind spot outside `boid`'s view
+    /// cone. A boid standing still has no heading and sees all round.
+    pub fn perceive(
+        boid: &Boid,
+        other: Boid,
+        offset: Vec2,
+        interaction: Interaction,
+        config: &SimulationConfig,
+    ) -> Option<Self> {
+        let distance_squared = offset.length_squared();
+        let mut weight = 1.0;
+        let directional = config.view_angle < 360.0 || config.angle_weighting > 0.0;
+        let speed_squared = boid.velocity.length_squared();
+        if directional && distance_squared > 0.0 && speed_squared > 0.0 {
+            let heading = boid.velocity / speed_squared.sqrt();
+            let half_angle = (config.view_angle * 0.5).to_radians();
+            let cos = heading.dot(offset) / distance_squared.sqrt();
+            if config.view_angle < 360.0 && cos < half_angle.cos() {
+                return None;
+            }
+            if config.angle_weighting > 0.0 {
+                let angle = cos.clamp(-1.0, 1.0).acos();
+                weight *= 1.0 - config.angle_weighting * (angle / half_angle).min(1.0);
+            }
+        }
+        if config.distance_weighting > 0.0 && config.follow_radius > 0.0 {
+            let distance = distance_squared.sqrt();
+            weight *= 1.0 - config.distance_weighting * (distance / config.follow_radius).min(1.0);
+        }
+        Some(Neighbor {
+            boid: other,
+            offset,
+            distance_squared,
+            interaction,
+            weight,
+        })
+    }
+}
+
+/// Weighted sums over the neighbours a boid perceives: everything the
+/// built-in rules steer by. Neighbours of ignored species, and any the rules
+/// wouldn't react to, add nothing.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct NeighborSums {
+    /// Negated offsets to flockmates within `avoid_radius` and to avoided
+    /// species within `follow_radius`.
+    pub separation: Vec2,
+    pub separation_weight: f32,
+    /// Offsets to flockmates within `follow_radius`.
+    pub cohesion: Vec2,
+    /// Velocities of the same flockmates.
+    pub alignment: Vec2,
+    pub flock_weight: f32,
+}
+
+impl NeighborSums {
+    pub fn from_neighbors(neighbors: &[Neighbor], config: &SimulationConfig) -> Self {
+        let avoid_radius_sq = config.avoid_radius * config.avoid_radius;
+        let follow_radius_sq = config.follow_radius * config.follow_radius;
+        let mut sums = NeighborSums::default();
+        for neighbor in neighbors {
+            let separation_radius_sq = match neighbor.interaction {
+                Interaction::Flock => avoid_radius_sq,
+                Interaction::Avoid => follow_radius_sq,
+                Interaction::Ignore => continue,
+            };
+            if neighbor.distance_squared <= separation_radius_sq {
+                sums.separation -= neighbor.offset * neighbor.weight;
+                sums.separation_weight += neighbor.weight;
+            }
+            if neighbor.interaction == Interaction::Flock
+                && neighbor.distance_squared <= follow_radius_sq
+            {
+                sums.cohesion += neighbor.offset * neighbor.weight;
+                sums.alignment += neighbor.boid.velocity * neighbor.weight;
+                sums.flock_weight += neighbor.weight;
+            }
+        }
+        sums
+    }
+}
+
+/// A steering rule: looks at a boid and the neighbours it perceives (inside
+/// its perception region and view cone) and returns the force it wants to
+/// apply, as an acceleration in units per second squared. `config` holds the
+/// parameters of the steering boid's species.
+///
+/// Behaviours are run from rayon workers, so they must be `Send + Sync`.
+pub trait SteeringBehavior: Send + Sync {
+    fn steer(&self, boid: &Boid, neighbors: &[Neighbor], config: &SimulationConfig) -> Vec2;
+
+    /// Farthest distance at which this behaviour reacts to a neighbour.
+    /// `Simulation::check_coverage` compares it against the perception
+    /// radius, so a behaviour that ignores neighbours should return zero and
+    /// one that only uses what it is given `config.perception_radius()`.
+    fn radius(&self, config: &SimulationConfig) -> f32;
+
+    /// Whether `steer_sums` gives the same force as `steer`. When it does for
+    /// every registered behaviour, `navigate` gathers `NeighborSums` with a
+    /// SIMD kernel straight from the boid arrays and never builds neighbour
+    /// lists.
+    fn uses_sums(&self) -> bool {
+        false
+    }
+
+    /// The force `steer` would return, from sums over the neighbours instead
+    /// of the neighbours themselves. Only called if `uses_sums` is true.
+    fn steer_sums(&self, _boid: &Boid, _sums: &NeighborSums, _config: &SimulationConfig) -> Vec2 {
+        Vec2::new(0.0, 0.0)
+    }
+}
+
+/// Steer away from flockmates closer than `avoid_radius` and from avoided
+/// species closer than `follow_radius`, at `seperation_factor` times the max
+/// force.
+pub struct Separation;
+
+impl SteeringBehavior for Separation {
+    fn steer(&self, boid: &Boid, neighbors: &[Neighbor], config: &SimulationConfig) -> Vec2 {
+        self.steer_sums(
+            boid,
+            &NeighborSums::from_neighbors(neighbors, config),
+            config,
+        )
+    }
+
+    fn radius(&self, config: &SimulationConfig) -> f32 {
+        config.avoid_radius.max(config.follow_radius)
+    }
+
+    fn uses_sums(&self) -> bool {
+        true
+    }
+
+    fn steer_sums(&self, _boid: &Boid, sums: &NeighborSums, config: &SimulationConfig) -> Vec2 {
+        if sums.separation_weight <= 0.0 {
+            return Vec2::new(0.0, 0.0);
+        }
+        let separation = sums.separation / sums.separation_weight;
+        separation.normalize_or_zero() * config.seperation_factor * config.max_force
+    }
+}
+
+/// Steer towards the centre of flockmates within `follow_radius`, at
+/// `cohesion_factor` times the max force.
+pub struct Cohesion;
+
+impl SteeringBehavior for Cohesion {
+    fn steer(&self, boid: &Boid, neighbors: &[Neighbor], config: &SimulationConfig) -> Vec2 {
+        self.steer_sums(
+            boid,
+            &NeighborSums::from_neighbors(neighbors, config),
+            config,
+        )
+    }
+
+    fn radius(&self, config: &SimulationConfig) -> f32 {
+        config.follow_radius
+    }
+
+    fn uses_sums(&self) -> bool {
+        true
+    }
+
+    fn steer_sums(&self, _boid: &Boid, sums: &NeighborSums, config: &SimulationConfig) -> Vec2 {
+        if sums.flock_weight <= 0.0 {
+            return Vec2::new(0.0, 0.0);
+        }
+        let center = sums.cohesion / sums.flock_weight;
+        center.normalize_or_zero() * config.cohesion_factor * config.max_force
+    }
+}
+
+/// Fly at full speed along the mean heading of flockmates within
+/// `follow_radius`.
+pub struct Alignment;
+
+impl SteeringBehavior for Alignment {
+    fn steer(&self, boid: &Boid, neighbors: &[Neighbor], config: &SimulationConfig) -> Vec2 {
+        self.steer_sums(
+            boid,
+            &NeighborSums::from_neighbors(neighbors, config),
+            config,
+        )
+    }
+
+    fn radius(&self, config: &SimulationConfig) -> f32 {
+        config.follow_radius
+    }
+
+    fn uses_sums(&self) -> bool {
+        true
+    }
+
+    fn steer_sums(&self, boid: &Boid, sums: &NeighborSums, config: &SimulationConfig) -> Vec2 {
+        if sums.flock_weight <= 0.0 {
+            return Vec2::new(0.0, 0.0);
+        }
+        let desired = sums.alignment.normalize_or_zero() * config.max_velocity;
+        (desired - boid.velocity) * config.alignment_factor * STEERING_RATE
+    }
+}
+
+/// A behaviour registered on a `Simulation` together with the weight its
+/// force is scaled by.
+pub struct WeightedBehavior {
+    pub weight: f32,
+    pub behavior: Box<dyn SteeringBehavior>,
+}
+
+impl WeightedBehavior {
+    pub fn new(weight: f32, behavior: impl SteeringBehavior + 'static) -> Self {
+        WeightedBehavior {
+            weight,
+            behavior: Box::new(behavior),
+        }
+    }
+}
+
+/// A behaviour that reacts to neighbours farther away than a species ever
+/// queries, so some of them are never seen. Reported by
+/// `Simulation::check_coverage`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CoverageIssue {
+    pub species: SpeciesId,
+    /// Index into `Simulation::behaviors`.
+    pub behavior: usize,
+    pub radius: f32,
+    pub query_radius: f32,
+}
+
+impl fmt::Display for CoverageIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "species {} behaviour {} reacts up to {} but neighbours are only queried up to {}",
+            self.species, self.behavior, self.radius, self.query_radius
+        )
+    }
+}
+
+/// The classic separation, cohesion and alignment rules. Their strengths come
+/// from the config factors, so each is registered with a weight of one.
+pub fn flocking() -> Vec<WeightedBehavior> {
+    vec![
+        WeightedBehavior::new(1.0, Separation),
+        WeightedBehavior::new(1.0, Cohesion),
+        WeightedBehavior::new(1.0, Alignment),
+    ]
+}
+
+/// Sum of every behaviour's weighted force. Behaviours that use sums share
+/// one pass over the neighbours.
+pub fn steering_force(
+    behaviors: &[WeightedBehavior],
+    boid: &Boid,
+    neighbors: &[Neighbor],
+    config: &SimulationConfig,
+) -> Vec2 {
+    let mut sums = None;
+    behaviors.iter().fold(Vec2::new(0.0, 0.0), |net, weighted| {
+        let force = if weighted.behavior.uses_sums() {
+            let sums = sums.get_or_insert_with(|| NeighborSums::from_neighbors(neighbors, config));
+            weighted.behavior.steer_sums(boid, sums, config)
+        } else {
+            weighted.behavior.steer(boid, neighbors, config)
+        };
+        net + force * weighted.weight
+    })
+}
+
+/// Sum of every behaviour's weighted force, for behaviours that all use
+/// sums.
+pub fn steering_force_from_sums(
+    behaviors: &[WeightedBehavior],
+    boid: &Boid,
+    sums: &NeighborSums,
+    config: &SimulationConfig,
+) -> Vec2 {
+    behaviors.iter().fold(Vec2::new(0.0, 0.0), |net, weighted| {
+        net + weighted.behavior.steer_sums(boid, sums, config) * weighted.weight
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::id::BoidId;
+    use glam::vec2;
+
+    fn boid(velocity: Vec2) -> Boid {
+        let config = SimulationConfig::default();
+        Boid {
+            position: vec2(0.0, 0.0),
+            velocity,
+            acceleration: vec2(0.0, 0.0),
+            max_speed: config.max_velocity,
+            max_force: config.max_force,
+            radius: config.radius,
+            id: BoidId::from_raw(0, 0),
+            species: 0,
+        }
+    }
+
+    fn sees(config: &SimulationConfig, velocity: Vec2, offset: Vec2) -> Option<f32> {
+        let boid = boid(velocity);
+        Neighbor::perceive(&boid, boid.clone(), offset, Interaction::Flock, config)
+            .map(|neighbor| neighbor.weight)
+    }
+
+    #[test]
+    fn the_blind_spot_hides_neighbours_behind() {
+        let config = SimulationConfig {
+            view_angle: 270.0,
+            ..SimulationConfig::default()
+        };
+        let heading = vec2(1.0, 0.0);
+        assert_eq!(sees(&config, heading, vec2(5.0, 0.0)), Some(1.0));
+        assert_eq!(sees(&config, heading, vec2(0.0, -5.0)), Some(1.0));
+        // 135° either side of the heading is the edge of the cone.
+        assert!(sees(&config, heading, vec2(-5.0, 5.5)).is_some());
+        assert!(sees(&config, heading, vec2(-5.0, 4.5)).is_none());
+        assert!(sees(&config, heading, vec2(-5.0, 0.0)).is_none());
+
+        // Turning the boid turns its cone.
+        assert!(sees(&config, vec2(-1.0, 0.0), vec2(-5.0, 0.0)).is_some());
+        assert!(sees(&config, vec2(-1.0, 0.0), vec2(5.0, 0.0)).is_none());
+
+        // A boid standing still has no heading and sees all round, and a
+        // full circle has no blind spot.
+        assert!(sees(&config, vec2(0.0, 0.0), vec2(-5.0, 0.0)).is_some());
+        let config = SimulationConfig::default();
+        assert_eq!(sees(&config, heading, vec2(-5.0, 0.0)), Some(1.0));
+    }
+
+    #[test]
+    fn weighting_fades_far_and_peripheral_neighbours() {
+        let config = SimulationConfig {
+            view_angle: 180.0,
+            angle_weighting: 0.5,
+            distance_weighting: 0.5,
+            follow_radius: 10.0,
+            ..SimulationConfig::default()
+        };
+        let heading = vec2(1.0, 0.0);
+        let weight = |offset| sees(&config, heading, offset).unwrap();
+        // Right next to the boid and dead ahead counts in full.
+        assert!((weight(vec2(1e-3, 0.0)) - 1.0).abs() < 1e-3);
+        // At the follow radius, straight ahead: half from distance.
+        assert!((weight(vec2(10.0, 0.0)) - 0.5).abs() < 1e-5);
+        // Just inside the edge of the cone, one unit away: half from angle
+        // and a twentieth off that from distance.
+        assert!((weight(vec2(1e-3, 1.0)) - 0.5 * 0.95).abs() < 1e-3);
+        assert!(weight(vec2(5.0, 5.0)) < weight(vec2(5.0, 0.0)));
+    }
+}
@@ -1,209 +1,476 @@
-use std::time::Instant;
-use nannou::prelude::*;
-use nannou::rand;
-use rayon::prelude::*;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::process;
+use std::time::Duration;
 
-use steering::boid::*;
-use steering::spatial_hash::{Rectangle, SpatialHashGrid};
-use steering::quadtree::QuadTree;
+use serde::{Deserialize, Serialize};
+use steering::simulation::{SimulationBuilder, SortOrder, StepTimings};
+use steering::spatial_hash::Rectangle;
+use steering::spatial_index::IndexKind;
 
-const NUM_BOIDS: usize = 150_000;
-const NUM_ITERATIONS: usize = 30;
-const WARMUP_ITERATIONS: usize = 5;
+const USAGE: &str = "usage: benchmark [--boids N,...] [--width W] [--height H] [--density D] \
+[--threads N,...] [--backends grid,quadtree,hashed] [--resort N,...] [--cell-order] \
+[--steps N] [--warmup N] [--seed N] [--format text|json|csv] [--output PATH] \
+[--baseline PATH] [--threshold PERCENT]";
+/// `--density` counts boids per square this wide.
+const DENSITY_AREA: f32 = 100.0;
+const PHASES: [&str; 5] = ["sort", "index", "forces", "integration", "total"];
 
-fn main() {
-    let bounds = Rectangle::new(-960.0, -540.0, 1920.0, 1080.0);
-
-    // Generate random boids
-    let mut boids = Vec::with_capacity(NUM_BOIDS);
-    for i in 0..NUM_BOIDS as i32 {
-        let rx = rand::random_range::<f32>(-960.0, 960.0);
-        let ry = rand::random_range::<f32>(-540.0, 540.0);
-        let vx = rand::random_range::<f32>(-2.0, 2.0);
-        let vy = rand::random_range::<f32>(-2.0, 2.0);
-        boids.push(Boid {
-            position: Vec2::new(rx, ry),
-            velocity: Vec2::new(vx, vy),
-            radius: BOID_RADIUS,
-            acceleration: vec2(0.0, 0.0),
-            max_speed: BOID_MAX_VELOCITY,
-            max_force: BOID_MAX_FORCE,
-            index: i,
-        });
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Format {
+    Text,
+    Json,
+    Csv,
+}
+
+struct Args {
+    boids: Vec<usize>,
+    width: f32,
+    height: f32,
+    density: Option<f32>,
+    threads: Vec<usize>,
+    backends: Vec<IndexKind>,
+    resort: Vec<u32>,
+    cell_order: bool,
+    steps: usize,
+    warmup: usize,
+    seed: u64,
+    format: Format,
+    output: Option<String>,
+    baseline: Option<String>,
+    threshold: f64,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        boids: vec![150_000],
+        width: 1920.0,
+        height: 1080.0,
+        density: None,
+        threads: vec![std::thread::available_parallelism().map_or(1, |n| n.get())],
+        backends: vec![IndexKind::Grid, IndexKind::QuadTree, IndexKind::Hashed],
+        resort: vec![0],
+        cell_order: false,
+        steps: 30,
+        warmup: 5,
+        seed: 0x5eed,
+        format: Format::Text,
+        output: None,
+        baseline: None,
+        threshold: 10.0,
+    };
+    let mut iter = std::env::args().skip(1);
+    while let Some(flag) = iter.next() {
+        let mut value = || iter.next().ok_or_else(|| format!("{} needs a value", flag));
+        match flag.as_str() {
+            "--boids" => args.boids = parse_list(&flag, value()?)?,
+            "--width" => args.width = parse(&flag, value()?)?,
+            "--height" => args.height = parse(&flag, value()?)?,
+            "--density" => args.density = Some(parse(&flag, value()?)?),
+            "--threads" => args.threads = parse_list(&flag, value()?)?,
+            "--backends" => {
+                args.backends = value()?
+                    .split(',')
+                    .map(|name| match name {
+                        "grid" => Ok(IndexKind::Grid),
+                        "quadtree" => Ok(IndexKind::QuadTree),
+                        "hashed" => Ok(IndexKind::Hashed),
+                        _ => Err(format!("unknown backend {:?}", name)),
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            "--resort" => args.resort = parse_list(&flag, value()?)?,
+            "--cell-order" => args.cell_order = true,
+            "--steps" => args.steps = parse(&flag, value()?)?,
+            "--warmup" => args.warmup = parse(&flag, value()?)?,
+            "--seed" => args.seed = parse(&flag, value()?)?,
+            "--format" => {
+                args.format = match value()?.as_str() {
+                    "text" => Format::Text,
+                    "json" => Format::Json,
+                    "csv" => Format::Csv,
+                    other => return Err(format!("unknown format {:?}", other)),
+                }
+            }
+            "--output" => args.output = Some(value()?),
+            "--baseline" => args.baseline = Some(value()?),
+            "--threshold" => args.threshold = parse(&flag, value()?)?,
+            "--help" | "-h" => return Err(USAGE.to_string()),
+            _ => return Err(format!("unknown argument {}\n{}", flag, USAGE)),
+        }
     }
+    if args.steps == 0 {
+        return Err("--steps must be at least 1".to_string());
+    }
+    if args.threads.contains(&0) {
+        return Err("--threads must be at least 1".to_string());
+    }
+    if !(positive(args.width) && positive(args.height)) {
+        return Err("--width and --height must be positive".to_string());
+    }
+    if args.density.is_some_and(|density| !positive(density)) {
+        return Err("--density must be positive".to_string());
+    }
+    Ok(args)
+}
 
-    println!("Benchmarking with {} boids", NUM_BOIDS);
-    println!("{} warmup + {} measured iterations each\n", WARMUP_ITERATIONS, NUM_ITERATIONS);
+/// Whether `value` is a finite number above zero.
+fn positive(value: f32) -> bool {
+    value.is_finite() && value > 0.0
+}
 
-    // --- Spatial Hash ---
-    let mut boids_sh = boids.clone();
-    let mut grid = SpatialHashGrid::new(bounds, BOID_BOUNDS_SIZE);
+fn parse<T: std::str::FromStr>(flag: &str, value: String) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value {:?} for {}", value, flag))
+}
 
-    // Warmup
-    for _ in 0..WARMUP_ITERATIONS {
-        run_spatial_hash(&mut boids_sh, &mut grid, bounds);
+fn parse_list<T: std::str::FromStr>(flag: &str, value: String) -> Result<Vec<T>, String> {
+    value
+        .split(',')
+        .map(|item| parse(flag, item.to_string()))
+        .collect()
+}
+
+/// Every scenario's results, as written by `--format json` and read back by
+/// `--baseline`.
+#[derive(Serialize, Deserialize)]
+struct Report {
+    seed: u64,
+    warmup: usize,
+    steps: usize,
+    scenarios: Vec<Scenario>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Scenario {
+    backend: String,
+    boids: usize,
+    threads: usize,
+    width: f32,
+    height: f32,
+    /// Steps between re-sorts; 0 if boids were never re-sorted.
+    resort: u32,
+    /// Order boids were re-sorted into, "morton" or "cell".
+    order: String,
+    phases: Vec<PhaseStats>,
+}
+
+impl Scenario {
+    fn label(&self) -> String {
+        let mut label = format!(
+            "{}, {} boids, {} threads, {}x{}",
+            self.backend, self.boids, self.threads, self.width, self.height
+        );
+        if self.resort > 0 {
+            label.push_str(&format!(
+                ", re-sorted into {} order every {} steps",
+                self.order, self.resort
+            ));
+        }
+        label
     }
 
-    let start = Instant::now();
-    for _ in 0..NUM_ITERATIONS {
-        run_spatial_hash(&mut boids_sh, &mut grid, bounds);
+    /// Whether `other` ran the same flock the same way, so its times can be
+    /// compared with these.
+    fn matches(&self, other: &Scenario) -> bool {
+        self.backend == other.backend
+            && self.boids == other.boids
+            && self.threads == other.threads
+            && self.width == other.width
+            && self.height == other.height
+            && self.resort == other.resort
+            && (self.resort == 0 || self.order == other.order)
     }
-    let sh_time = start.elapsed();
-    let sh_avg = sh_time / NUM_ITERATIONS as u32;
-    let sh_fps = 1.0 / sh_avg.as_secs_f64();
 
-    println!("Spatial Hash:");
-    println!("  Total: {:?}", sh_time);
-    println!("  Avg per frame: {:?}  ({:.1} fps)", sh_avg, sh_fps);
+    fn median_ms(&self, phase: &str) -> Option<f64> {
+        self.phases
+            .iter()
+            .find(|stats| stats.phase == phase)
+            .map(|stats| stats.median_ms)
+    }
+}
 
-    // --- QuadTree ---
-    let mut boids_qt = boids.clone();
+#[derive(Serialize, Deserialize)]
+struct PhaseStats {
+    phase: String,
+    min_ms: f64,
+    median_ms: f64,
+    p95_ms: f64,
+}
 
-    // Warmup
-    for _ in 0..WARMUP_ITERATIONS {
-        run_quadtree(&mut boids_qt, bounds);
+impl PhaseStats {
+    fn new(phase: &str, mut times: Vec<Duration>) -> Self {
+        times.sort_unstable();
+        PhaseStats {
+            phase: phase.to_string(),
+            min_ms: millis(times[0]),
+            median_ms: millis(percentile(&times, 50.0)),
+            p95_ms: millis(percentile(&times, 95.0)),
+        }
     }
+}
+
+/// The nearest-rank `percent`th percentile of `sorted`, which must not be
+/// empty.
+fn percentile(sorted: &[Duration], percent: f64) -> Duration {
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_nanos() as f64 / 1e6
+}
 
-    let start = Instant::now();
-    for _ in 0..NUM_ITERATIONS {
-        run_quadtree(&mut boids_qt, bounds);
+fn order_name(order: SortOrder) -> &'static str {
+    match order {
+        SortOrder::Morton => "morton",
+        SortOrder::Cell => "cell",
     }
-    let qt_time = start.elapsed();
-    let qt_avg = qt_time / NUM_ITERATIONS as u32;
-    let qt_fps = 1.0 / qt_avg.as_secs_f64();
+}
 
-    println!("\nQuadTree:");
-    println!("  Total: {:?}", qt_time);
-    println!("  Avg per frame: {:?}  ({:.1} fps)", qt_avg, qt_fps);
+fn backend_name(kind: IndexKind) -> &'static str {
+    match kind {
+        IndexKind::Grid => "grid",
+        IndexKind::QuadTree => "quadtree",
+        IndexKind::Hashed => "hashed",
+    }
+}
 
-    let speedup = qt_time.as_secs_f64() / sh_time.as_secs_f64();
-    println!("\nSpatial Hash is {:.2}x faster than QuadTree", speedup);
+/// The arena for `boids` boids: the given size, or with `--density` an area
+/// holding them at that density in the same proportions.
+fn arena(args: &Args, boids: usize) -> (f32, f32) {
+    match args.density {
+        Some(density) => {
+            let area = boids as f32 / density * DENSITY_AREA * DENSITY_AREA;
+            let width = (area * args.width / args.height).sqrt();
+            (width, area / width)
+        }
+        None => (args.width, args.height),
+    }
 }
 
-fn run_spatial_hash(boids: &mut Vec<Boid>, grid: &mut SpatialHashGrid, bounds: Rectangle) {
-    grid.clear();
-    for (i, boid) in boids.iter().enumerate() {
-        grid.insert(boid.position, i);
+/// Steps a fresh simulation and collects the time of every measured step,
+/// phase by phase.
+fn run(args: &Args, kind: IndexKind, boids: usize, threads: usize, resort: u32) -> Scenario {
+    let (width, height) = arena(args, boids);
+    let bounds = Rectangle::new(-width / 2.0, -height / 2.0, width, height);
+    // Same seed every run so every backend sees an identical flock
+    let mut builder = SimulationBuilder::new(bounds)
+        .boid_count(boids)
+        .index_kind(kind)
+        .threads(threads)
+        .seed(args.seed);
+    let order = if args.cell_order {
+        SortOrder::Cell
+    } else {
+        SortOrder::Morton
+    };
+    if resort > 0 {
+        builder = builder.resort(order, resort);
     }
+    let mut simulation = builder.build();
 
-    let boids_ref = &*boids;
-    let grid_ref = &*grid;
-    let sep_factor = BOID_SEPERATION_FACTOR;
-    let coh_factor = BOID_COHESION_FACTOR;
-    let ali_factor = BOID_ALIGNMENT_FACTOR;
-    let avoid_radius_sq = BOID_AVOID_RADIUS * BOID_AVOID_RADIUS;
-    let follow_radius_sq = BOID_FOLLOW_RADIUS * BOID_FOLLOW_RADIUS;
+    for _ in 0..args.warmup {
+        simulation.navigate();
+    }
+    let mut timings: Vec<StepTimings> = Vec::with_capacity(args.steps);
+    for _ in 0..args.steps {
+        simulation.navigate();
+        timings.push(simulation.timings());
+    }
 
-    let forces: Vec<Vec2> = (0..boids_ref.len())
-        .into_par_iter()
-        .map(|i| {
-            let boid = &boids_ref[i];
-            let mut separation = Vec2::new(0.0, 0.0);
-            let mut count_separation = 0;
-            let mut cohesion = Vec2::new(0.0, 0.0);
-            let mut count_cohesion = 0;
-            let mut alignment = Vec2::new(0.0, 0.0);
-            let mut count_alignment = 0;
+    let phase = |get: fn(&StepTimings) -> Duration| timings.iter().map(get).collect();
+    let times: [Vec<Duration>; 5] = [
+        phase(|t| t.sort),
+        phase(|t| t.index),
+        phase(|t| t.forces),
+        phase(|t| t.integration),
+        phase(|t| t.total()),
+    ];
+    Scenario {
+        backend: backend_name(kind).to_string(),
+        boids,
+        threads,
+        width,
+        height,
+        resort,
+        order: order_name(order).to_string(),
+        phases: PHASES
+            .iter()
+            .zip(times)
+            .map(|(name, times)| PhaseStats::new(name, times))
+            .collect(),
+    }
+}
 
-            grid_ref.query(boid.get_perception_rect(), |other_idx| {
-                if other_idx == i {
-                    return;
-                }
-                let other = &boids_ref[other_idx];
-                let diff = boid.position - other.position;
-                let dist_sq = diff.length_squared();
-                if dist_sq <= avoid_radius_sq {
-                    separation += diff;
-                    count_separation += 1;
-                }
-                if dist_sq <= follow_radius_sq {
-                    cohesion += other.position;
-                    alignment += other.velocity;
-                    count_cohesion += 1;
-                    count_alignment += 1;
-                }
-            });
+fn write_text(out: &mut impl Write, report: &Report) -> io::Result<()> {
+    writeln!(
+        out,
+        "seed {:#x}, {} warmup + {} measured steps each",
+        report.seed, report.warmup, report.steps
+    )?;
+    for scenario in &report.scenarios {
+        writeln!(out, "\n{}:", scenario.label())?;
+        writeln!(
+            out,
+            "  {:<12} {:>10} {:>10} {:>10}",
+            "phase (ms)", "min", "median", "p95"
+        )?;
+        for stats in &scenario.phases {
+            writeln!(
+                out,
+                "  {:<12} {:>10.3} {:>10.3} {:>10.3}",
+                stats.phase, stats.min_ms, stats.median_ms, stats.p95_ms
+            )?;
+        }
+    }
+    Ok(())
+}
 
-            let mut net = vec2(0.0, 0.0);
-            if count_separation > 0 {
-                separation = separation / count_separation as f32;
-                separation = separation.normalize();
-                net += separation * sep_factor;
-            }
-            if count_cohesion > 0 {
-                cohesion = cohesion / count_cohesion as f32;
-                cohesion = (cohesion - boid.position).normalize();
-                alignment = alignment / count_alignment as f32;
-                alignment *= BOID_MAX_VELOCITY;
-                net += (alignment - boid.velocity) * ali_factor;
-                net += cohesion * coh_factor;
-            }
-            net
-        })
-        .collect();
+fn write_csv(out: &mut impl Write, report: &Report) -> io::Result<()> {
+    writeln!(
+        out,
+        "backend,boids,threads,width,height,resort,order,phase,min_ms,median_ms,p95_ms"
+    )?;
+    for scenario in &report.scenarios {
+        for stats in &scenario.phases {
+            writeln!(
+                out,
+                "{},{},{},{},{},{},{},{},{},{},{}",
+                scenario.backend,
+                scenario.boids,
+                scenario.threads,
+                scenario.width,
+                scenario.height,
+                scenario.resort,
+                scenario.order,
+                stats.phase,
+                stats.min_ms,
+                stats.median_ms,
+                stats.p95_ms
+            )?;
+        }
+    }
+    Ok(())
+}
 
-    boids.par_iter_mut().enumerate().for_each(|(i, b)| {
-        b.apply_force(forces[i]);
-        b.update(bounds);
-    });
+fn write_report(args: &Args, report: &Report) -> io::Result<()> {
+    let mut out: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout().lock()),
+    };
+    match args.format {
+        Format::Text => write_text(&mut out, report)?,
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut out, report)?;
+            writeln!(out)?;
+        }
+        Format::Csv => write_csv(&mut out, report)?,
+    }
+    out.flush()
 }
 
-fn run_quadtree(boids: &mut Vec<Boid>, bounds: Rectangle) {
-    let mut quadtree: QuadTree<Boid> = QuadTree::new(bounds);
-    for boid in boids.iter() {
-        quadtree.insert(boid);
-    }
-
-    let sep_factor = BOID_SEPERATION_FACTOR;
-    let coh_factor = BOID_COHESION_FACTOR;
-    let ali_factor = BOID_ALIGNMENT_FACTOR;
-
-    let forces: Vec<Vec2> = boids
-        .par_iter()
-        .map(|boid| {
-            let found = quadtree.query(boid.get_perception_rect());
-
-            let mut seperation = Vec2::new(0.0, 0.0);
-            let mut count_seperation = 0;
-            let mut cohesion = Vec2::new(0.0, 0.0);
-            let mut count_cohesion = 0;
-            let mut alignment = Vec2::new(0.0, 0.0);
-            let mut count_alignment = 0;
-            for other in &found {
-                if other.index != boid.index {
-                    let dist = other.position.distance(boid.position);
-                    if dist <= BOID_AVOID_RADIUS {
-                        seperation += boid.position - other.position;
-                        count_seperation += 1;
-                    }
-                    if dist <= BOID_FOLLOW_RADIUS {
-                        cohesion += other.position;
-                        alignment += other.velocity;
-                        count_cohesion += 1;
-                        count_alignment += 1;
-                    }
-                }
+/// Compares median total step times against `baseline` and returns how many
+/// scenarios got slower by more than the threshold.
+/// Prints each phase's median against the baseline's and returns how many
+/// scenarios had a phase regress by more than the threshold.
+fn compare(args: &Args, report: &Report, baseline: &Report) -> usize {
+    let mut regressions = 0;
+    eprintln!(
+        "\ncompared with baseline (regression above +{}% in any phase):",
+        args.threshold
+    );
+    for scenario in &report.scenarios {
+        let old = match baseline.scenarios.iter().find(|old| old.matches(scenario)) {
+            Some(old) => old,
+            None => {
+                eprintln!("  {}: not in baseline", scenario.label());
+                continue;
             }
-            let mut net = vec2(0.0, 0.0);
-            if count_seperation > 0 {
-                let seperation = (seperation / count_seperation as f32).normalize();
-                net += seperation * sep_factor;
+        };
+        eprintln!("  {}:", scenario.label());
+        let mut regressed = false;
+        for phase in PHASES {
+            // Without re-sorting the sort phase only times an empty call.
+            if phase == "sort" && scenario.resort == 0 {
+                continue;
             }
-            if count_cohesion > 0 {
-                let cohesion = (cohesion / count_cohesion as f32);
-                let cohesion = (cohesion - boid.position).normalize();
-                let alignment = (alignment / count_alignment as f32).normalize();
-                let alignment = alignment * BOID_MAX_VELOCITY;
-                net += (alignment - boid.velocity) * ali_factor;
-                net += cohesion * coh_factor;
+            let (old, new) = match (old.median_ms(phase), scenario.median_ms(phase)) {
+                (Some(old), Some(new)) => (old, new),
+                _ => {
+                    eprintln!("    {}: not in baseline", phase);
+                    continue;
+                }
+            };
+            // A phase that took no time has no percentage to compare.
+            if old <= 0.0 {
+                eprintln!("    {}: {:.3} ms -> {:.3} ms", phase, old, new);
+                continue;
             }
-            net
-        })
-        .collect();
+            let change = (new / old - 1.0) * 100.0;
+            let slower = change > args.threshold;
+            regressed |= slower;
+            eprintln!(
+                "    {}: {:.3} ms -> {:.3} ms ({:+.1}%){}",
+                phase,
+                old,
+                new,
+                change,
+                if slower { "  REGRESSION" } else { "" }
+            );
+        }
+        if regressed {
+            regressions += 1;
+        }
+    }
+    regressions
+}
 
-    boids.par_iter_mut().enumerate().for_each(|(i, b)| {
-        b.apply_force(forces[i]);
+fn main() {
+    let args = parse_args().unwrap_or_else(|msg| {
+        eprintln!("{}", msg);
+        process::exit(2);
+    });
+    // Read the baseline first so a bad path fails before the long runs.
+    let baseline: Option<Report> = args.baseline.as_ref().map(|path| {
+        let text = fs::read_to_string(path).unwrap_or_else(|err| {
+            eprintln!("{}: {}", path, err);
+            process::exit(1);
+        });
+        serde_json::from_str(&text).unwrap_or_else(|err| {
+            eprintln!("{}: {}", path, err);
+            process::exit(1);
+        })
     });
 
-    boids.par_iter_mut().for_each(|boid| boid.update(bounds));
+    let mut report = Report {
+        seed: args.seed,
+        warmup: args.warmup,
+        steps: args.steps,
+        scenarios: Vec::new(),
+    };
+    for &boids in &args.boids {
+        for &threads in &args.threads {
+            for &kind in &args.backends {
+                for &resort in &args.resort {
+                    let scenario = run(&args, kind, boids, threads, resort);
+                    eprintln!("finished {}", scenario.label());
+                    report.scenarios.push(scenario);
+                }
+            }
+        }
+    }
+
+    if let Err(err) = write_report(&args, &report) {
+        eprintln!("writing results failed: {}", err);
+        process::exit(1);
+    }
+    if let Some(baseline) = &baseline {
+        let regressions = compare(&args, &report, baseline);
+        if regressions > 0 {
+            eprintln!("{} scenario(s) regressed", regressions);
+            process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,239 @@
+use std::process;
+use std::time::Instant;
+
+use steering::behavior::NeighborMode;
+use steering::config::SimulationConfig;
+use steering::metrics::{Metrics, MetricsRecorder};
+use steering::predation::Predation;
+use steering::recorder::TrajectoryRecorder;
+use steering::simulation::{SimulationBuilder, SortOrder};
+use steering::spatial_hash::Rectangle;
+
+const USAGE: &str = "usage: headless [--boids N] [--species N] [--predators N] [--capture R] \
+[--topological K] [--resort N] [--cell-order] [--steps N] [--seed N] [--config PATH] \
+[--alignment F] [--width W] [--height H] [--record PATH] [--metrics PATH] [--every N] [--verify]";
+/// Predators are this much faster than their prey.
+const PREDATOR_SPEEDUP: f32 = 1.25;
+
+struct Args {
+    boids: usize,
+    species: usize,
+    predators: usize,
+    capture: f32,
+    topological: Option<usize>,
+    resort: Option<u32>,
+    cell_order: bool,
+    steps: u64,
+    seed: Option<u64>,
+    config: Option<String>,
+    alignment: Option<f32>,
+    width: f32,
+    height: f32,
+    record: Option<String>,
+    metrics: Option<String>,
+    every: u64,
+    verify: bool,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        boids: 10_000,
+        species: 1,
+        predators: 0,
+        capture: 0.0,
+        topological: None,
+        resort: None,
+        cell_order: false,
+        steps: 1_000,
+        seed: None,
+        config: None,
+        alignment: None,
+        width: 1920.0,
+        height: 1080.0,
+        record: None,
+        metrics: None,
+        every: 10,
+        verify: false,
+    };
+    let mut iter = std::env::args().skip(1);
+    while let Some(flag) = iter.next() {
+        let mut value = || iter.next().ok_or_else(|| format!("{} needs a value", flag));
+        match flag.as_str() {
+            "--boids" => args.boids = parse(&flag, value()?)?,
+            "--species" => args.species = parse(&flag, value()?)?,
+            "--predators" => args.predators = parse(&flag, value()?)?,
+            "--capture" => args.capture = parse(&flag, value()?)?,
+            "--topological" => args.topological = Some(parse(&flag, value()?)?),
+            "--resort" => args.resort = Some(parse(&flag, value()?)?),
+            "--cell-order" => args.cell_order = true,
+            "--steps" => args.steps = parse(&flag, value()?)?,
+            "--seed" => args.seed = Some(parse(&flag, value()?)?),
+            "--config" => args.config = Some(value()?),
+            "--alignment" => args.alignment = Some(parse(&flag, value()?)?),
+            "--width" => args.width = parse(&flag, value()?)?,
+            "--height" => args.height = parse(&flag, value()?)?,
+            "--record" => args.record = Some(value()?),
+            "--metrics" => args.metrics = Some(value()?),
+            "--every" => args.every = parse(&flag, value()?)?,
+            "--verify" => args.verify = true,
+            "--help" | "-h" => return Err(USAGE.to_string()),
+            _ => return Err(format!("unknown argument {}\n{}", flag, USAGE)),
+        }
+    }
+    if args.species == 0 || args.species > u16::MAX as usize {
+        return Err(format!("--species must be between 1 and {}", u16::MAX));
+    }
+    if !(positive(args.width) && positive(args.height)) {
+        return Err("--width and --height must be positive".to_string());
+    }
+    Ok(args)
+}
+
+/// Whether `value` is a finite number above zero.
+fn positive(value: f32) -> bool {
+    value.is_finite() && value > 0.0
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: String) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value {:?} for {}", value, flag))
+}
+
+fn main() {
+    let args = parse_args().unwrap_or_else(|msg| {
+        eprintln!("{}", msg);
+        process::exit(2);
+    });
+    let mut config = match &args.config {
+        Some(path) => SimulationConfig::load(path).unwrap_or_else(|err| {
+            eprintln!("{}: {}", path, err);
+            process::exit(1);
+        }),
+        None => SimulationConfig::default(),
+    };
+    if let Some(alignment) = args.alignment {
+        config.alignment_factor = alignment;
+        if let Err(err) = config.validate() {
+            eprintln!("--alignment: {}", err);
+            process::exit(2);
+        }
+    }
+    let bounds = Rectangle::new(
+        -args.width / 2.0,
+        -args.height / 2.0,
+        args.width,
+        args.height,
+    );
+    // Split the boids evenly; every species shares the config and avoids the
+    // others, so they school separately.
+    let per_species = args.boids / args.species;
+    let mut builder = SimulationBuilder::new(bounds)
+        .boid_count(args.boids - per_species * (args.species - 1))
+        .config(config);
+    for _ in 1..args.species {
+        builder = builder.species(config, per_species);
+    }
+    if args.predators > 0 {
+        let mut predator_config = config;
+        predator_config.max_velocity *= PREDATOR_SPEEDUP;
+        builder = builder
+            .predators(predator_config, args.predators)
+            .predation(Predation {
+                capture_radius: args.capture,
+                ..Predation::default()
+            });
+    }
+    if let Some(k) = args.topological {
+        builder = builder.neighbor_mode(NeighborMode::Topological { k });
+    }
+    if let Some(every) = args.resort {
+        let order = if args.cell_order {
+            SortOrder::Cell
+        } else {
+            SortOrder::Morton
+        };
+        builder = builder.resort(order, every);
+    }
+    if let Some(seed) = args.seed {
+        builder = builder.seed(seed);
+    }
+    let mut simulation = builder.build();
+    println!(
+        "{} boids in {} species, seed {}",
+        simulation.boid_count(),
+        simulation.species().len(),
+        simulation.seed()
+    );
+    if args.verify {
+        // Report behaviours that cannot see every neighbour they react to,
+        // then stop without stepping.
+        let issues = simulation.check_coverage();
+        for issue in &issues {
+            println!("{}", issue);
+        }
+        if !issues.is_empty() {
+            process::exit(1);
+        }
+        println!("every behaviour is covered by its perception radius");
+        return;
+    }
+
+    let mut recorder = args.record.as_ref().map(|path| {
+        TrajectoryRecorder::create(path, args.every, &simulation).unwrap_or_else(|err| {
+            eprintln!("{}: {}", path, err);
+            process::exit(1);
+        })
+    });
+
+    let mut metrics_recorder = args.metrics.as_ref().map(|path| {
+        let metrics = Metrics::new(simulation.bounds(), config.perception_radius());
+        MetricsRecorder::create(path, args.every, metrics).unwrap_or_else(|err| {
+            eprintln!("{}: {}", path, err);
+            process::exit(1);
+        })
+    });
+
+    let start = Instant::now();
+    let mut captured = 0;
+    let mut last_metrics = None;
+    for _ in 0..args.steps {
+        simulation.navigate();
+        captured += simulation.take_captures().len();
+        if let Some(recorder) = recorder.as_mut() {
+            if let Err(err) = recorder.record(&simulation) {
+                eprintln!("recording failed: {}", err);
+                process::exit(1);
+            }
+        }
+        if let Some(recorder) = metrics_recorder.as_mut() {
+            match recorder.record(&simulation) {
+                Ok(Some(metrics)) => last_metrics = Some(metrics),
+                Ok(None) => {}
+                Err(err) => {
+                    eprintln!("writing metrics failed: {}", err);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+    if let Some(recorder) = recorder.as_mut() {
+        if let Err(err) = recorder.flush() {
+            eprintln!("recording failed: {}", err);
+            process::exit(1);
+        }
+    }
+    if let Some(recorder) = metrics_recorder.as_mut() {
+        if let Err(err) = recorder.flush() {
+            eprintln!("writing metrics failed: {}", err);
+            process::exit(1);
+        }
+    }
+    println!("{} steps in {:?}", args.steps, start.elapsed());
+    if args.predators > 0 {
+        println!("{} prey caught", captured);
+    }
+    if let Some(metrics) = last_metrics {
+        println!("{}", metrics);
+    }
+}
@@ -1,18 +1,71 @@
-use crate::spatial_hash::*;
+use crate::config::SimulationConfig;
+use crate::id::BoidId;
 use crate::quadtree::HasLocation;
-use nannou::geom::Vec2;
+use crate::spatial_hash::*;
+use crate::spatial_index::{IndexKind, Topology};
+use crate::species::{SpeciesId, SpeciesTable};
+use glam::Vec2;
+use rayon::prelude::*;
 use std::ops::Add;
 
-pub const BOID_BOUNDS_SIZE: f32 = 10.0;
 pub const BOID_RADIUS: f32 = 3.0;
-pub const BOID_MAX_VELOCITY: f32 = 3.0;
-pub const BOID_MAX_FORCE: f32 = 1.0;
+/// Units per second.
+pub const BOID_MAX_VELOCITY: f32 = 180.0;
+/// Largest steering acceleration, in units per second squared.
+pub const BOID_MAX_FORCE: f32 = 3600.0;
 pub const BOID_AVOID_RADIUS: f32 = 3.0;
 pub const BOID_FOLLOW_RADIUS: f32 = 10.0;
 pub const BOID_SEPERATION_FACTOR: f32 = 0.5;
 pub const BOID_COHESION_FACTOR: f32 = 0.1;
 pub const BOID_ALIGNMENT_FACTOR: f32 = 0.02;
+/// Degrees; the default sees all the way round.
+pub const BOID_VIEW_ANGLE: f32 = 360.0;
+
+/// What happens to a boid at the edge of the simulation bounds.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum BoundaryMode {
+    /// Leaving one edge re-enters from the opposite one, and boids see and
+    /// steer by each other across the edges as if they were joined.
+    #[default]
+    Wrap,
+    /// Boids bounce off the edges like billiard balls.
+    Reflect,
+    /// Boids are pushed back by a force that ramps up from zero at `margin`
+    /// units inside the edge to `strength` times the boid's `max_force` at the
+    /// edge and beyond.
+    SoftWalls { margin: f32, strength: f32 },
+    /// No boundary at all; boids may fly off forever.
+    Unbounded,
+}
 
+impl BoundaryMode {
+    /// The space boids in `bounds` move in under this mode.
+    pub fn topology(&self, bounds: Rectangle) -> Topology {
+        match self {
+            BoundaryMode::Wrap => Topology::Torus(bounds),
+            _ => Topology::Plane,
+        }
+    }
+
+    /// Whether boids always end a step inside the bounds. Soft walls only
+    /// push them back, and unbounded worlds let them go.
+    pub fn keeps_inside(&self) -> bool {
+        matches!(self, BoundaryMode::Wrap | BoundaryMode::Reflect)
+    }
+
+    /// `kind`, or the hashed grid if boids can leave the bounds that the grid
+    /// and the quadtree are clipped to.
+    pub fn index_kind(&self, kind: IndexKind) -> IndexKind {
+        if self.keeps_inside() {
+            kind
+        } else {
+            IndexKind::Hashed
+        }
+    }
+}
+
+/// One boid, with the speed, force and size limits of its species. A
+/// `Simulation` keeps its boids in `Boids` and hands out `Boid`s as copies.
 #[derive(Clone)]
 pub struct Boid {
     pub position: Vec2,
@@ -21,7 +74,9 @@ pub struct Boid {
     pub max_speed: f32,
     pub max_force: f32,
     pub radius: f32,
-    pub index: i32,
+    /// Assigned by the `Simulation` that owns the boid.
+    pub id: BoidId,
+    pub species: SpeciesId,
 }
 
 impl HasLocation for Boid {
@@ -38,46 +93,225 @@ impl Boid {
             .clamp_length_max(self.max_force);
     }
 
-    pub fn update(&mut self, bounds: Rectangle) {
-        self.velocity = self.velocity.add(self.acceleration);
-        self.velocity = self.velocity.clamp_length_max(self.max_speed);
-        self.position = self.position.add(self.velocity);
-        self.acceleration = Vec2::new(0.0, 0.0);
-        self.loop_bounds(bounds);
+    /// Enforces `boundary` after the boid has moved. Soft walls act through
+    /// `wall_force` instead, and unbounded worlds need nothing.
+    pub fn apply_boundary(&mut self, bounds: Rectangle, boundary: BoundaryMode) {
+        match boundary {
+            BoundaryMode::Wrap => self.loop_bounds(bounds),
+            BoundaryMode::Reflect => self.reflect_bounds(bounds),
+            BoundaryMode::SoftWalls { .. } | BoundaryMode::Unbounded => {}
+        }
     }
 
+    /// Keeps the boid's body inside `bounds`, mirroring its position and
+    /// velocity about any edge it crossed.
     pub fn reflect_bounds(&mut self, bounds: Rectangle) {
-        if self.position.x + self.radius < bounds.x {
-            self.velocity = Vec2::new(-self.velocity.x, self.velocity.y);
-        } else if self.position.x - self.radius > bounds.x + bounds.width {
-            self.velocity = Vec2::new(-self.velocity.x, self.velocity.y);
+        let min_x = bounds.x + self.radius;
+        let max_x = bounds.x + bounds.width - self.radius;
+        let min_y = bounds.y + self.radius;
+        let max_y = bounds.y + bounds.height - self.radius;
+        if self.position.x < min_x {
+            self.position.x = (2.0 * min_x - self.position.x).min(max_x);
+            self.velocity.x = self.velocity.x.abs();
+        } else if self.position.x > max_x {
+            self.position.x = (2.0 * max_x - self.position.x).max(min_x);
+            self.velocity.x = -self.velocity.x.abs();
         }
-        if self.position.y + self.radius < bounds.y {
-            self.velocity = Vec2::new(self.velocity.x, -self.velocity.y);
-        } else if self.position.y - self.radius > bounds.y + bounds.height {
-            self.velocity = Vec2::new(self.velocity.x, -self.velocity.y);
+        if self.position.y < min_y {
+            self.position.y = (2.0 * min_y - self.position.y).min(max_y);
+            self.velocity.y = self.velocity.y.abs();
+        } else if self.position.y > max_y {
+            self.position.y = (2.0 * max_y - self.position.y).max(min_y);
+            self.velocity.y = -self.velocity.y.abs();
         }
     }
 
+    /// Inward force for `BoundaryMode::SoftWalls`, zero while the boid is
+    /// further than `margin` from every edge.
+    pub fn wall_force(&self, bounds: Rectangle, margin: f32, strength: f32) -> Vec2 {
+        let strength = strength * self.max_force;
+        let ramp = |distance: f32| {
+            if margin <= 0.0 {
+                if distance <= 0.0 {
+                    strength
+                } else {
+                    0.0
+                }
+            } else {
+                (1.0 - distance / margin).clamp(0.0, 1.0) * strength
+            }
+        };
+        let left = self.position.x - bounds.x;
+        let right = bounds.x + bounds.width - self.position.x;
+        let bottom = self.position.y - bounds.y;
+        let top = bounds.y + bounds.height - self.position.y;
+        Vec2::new(ramp(left) - ramp(right), ramp(bottom) - ramp(top))
+    }
+
+    /// Brings the boid back in through the opposite edge of `bounds` when it
+    /// leaves, so it never sits outside them.
     pub fn loop_bounds(&mut self, bounds: Rectangle) {
-        if self.position.x + self.radius < bounds.x {
-            self.position.x = bounds.x + bounds.width + self.radius
-        } else if self.position.x - self.radius > bounds.x + bounds.width {
-            self.position.x = bounds.x - self.radius
-        }
-        if self.position.y + self.radius < bounds.y {
-            self.position.y = bounds.y + bounds.height + self.radius
-        } else if self.position.y - self.radius > bounds.y + bounds.height {
-            self.position.y = bounds.y - self.radius
-        }
+        self.position = Topology::Torus(bounds).wrap(self.position);
     }
 
-    pub fn get_perception_rect(&self) -> Rectangle {
+    pub fn get_perception_rect(&self, size: f32) -> Rectangle {
         Rectangle::new(
-            self.position.x - BOID_BOUNDS_SIZE / 2.0,
-            self.position.y - BOID_BOUNDS_SIZE / 2.0,
-            BOID_BOUNDS_SIZE,
-            BOID_BOUNDS_SIZE,
+            self.position.x - size / 2.0,
+            self.position.y - size / 2.0,
+            size,
+            size,
         )
     }
 }
+
+/// Every boid's state as a structure of arrays: entry `i` of each array
+/// belongs to boid `i`, so a pass over many boids only pulls in the fields it
+/// reads. The limits in `Boid` are the same for a whole species and come from
+/// its `SimulationConfig` instead of being stored per boid.
+#[derive(Debug, Clone, Default)]
+pub struct Boids {
+    positions: Vec<Vec2>,
+    velocities: Vec<Vec2>,
+    accelerations: Vec<Vec2>,
+    ids: Vec<BoidId>,
+    species: Vec<SpeciesId>,
+}
+
+impl Boids {
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    pub fn positions(&self) -> &[Vec2] {
+        &self.positions
+    }
+
+    pub fn positions_mut(&mut self) -> &mut [Vec2] {
+        &mut self.positions
+    }
+
+    pub fn velocities(&self) -> &[Vec2] {
+        &self.velocities
+    }
+
+    pub fn velocities_mut(&mut self) -> &mut [Vec2] {
+        &mut self.velocities
+    }
+
+    pub fn accelerations(&self) -> &[Vec2] {
+        &self.accelerations
+    }
+
+    pub fn accelerations_mut(&mut self) -> &mut [Vec2] {
+        &mut self.accelerations
+    }
+
+    pub fn ids(&self) -> &[BoidId] {
+        &self.ids
+    }
+
+    pub fn species(&self) -> &[SpeciesId] {
+        &self.species
+    }
+
+    /// A copy of boid `i`, with the limits from `config`, which should be its
+    /// species'.
+    pub fn get(&self, i: usize, config: &SimulationConfig) -> Boid {
+        Boid {
+            position: self.positions[i],
+            velocity: self.velocities[i],
+            acceleration: self.accelerations[i],
+            max_speed: config.max_velocity,
+            max_force: config.max_force,
+            radius: config.radius,
+            id: self.ids[i],
+            species: self.species[i],
+        }
+    }
+
+    /// Stores the position, velocity and acceleration of `boid` as boid
+    /// `i`'s. Its id, species and limits are ignored.
+    pub fn set(&mut self, i: usize, boid: &Boid) {
+        self.positions[i] = boid.position;
+        self.velocities[i] = boid.velocity;
+        self.accelerations[i] = boid.acceleration;
+    }
+
+    pub(crate) fn reserve(&mut self, additional: usize) {
+        self.positions.reserve(additional);
+        self.velocities.reserve(additional);
+        self.accelerations.reserve(additional);
+        self.ids.reserve(additional);
+        self.species.reserve(additional);
+    }
+
+    pub(crate) fn push(&mut self, boid: &Boid) {
+        self.positions.push(boid.position);
+        self.velocities.push(boid.velocity);
+        self.accelerations.push(boid.acceleration);
+        self.ids.push(boid.id);
+        self.species.push(boid.species);
+    }
+
+    /// Removes boid `i`, moving the last boid into its place.
+    pub(crate) fn swap_remove(&mut self, i: usize) {
+        self.positions.swap_remove(i);
+        self.velocities.swap_remove(i);
+        self.accelerations.swap_remove(i);
+        self.ids.swap_remove(i);
+        self.species.swap_remove(i);
+    }
+
+    /// Reorders the boids so that boid `order[i]` becomes boid `i`. `order`
+    /// must be a permutation of `0..len`.
+    pub(crate) fn permute(&mut self, order: &[usize]) {
+        fn gather<T: Copy + Send + Sync>(values: &mut Vec<T>, order: &[usize]) {
+            *values = order.par_iter().map(|&i| values[i]).collect();
+        }
+        gather(&mut self.positions, order);
+        gather(&mut self.velocities, order);
+        gather(&mut self.accelerations, order);
+        gather(&mut self.ids, order);
+        gather(&mut self.species, order);
+    }
+
+    /// Runs `update(scratch, i, boid)` on a copy of every boid in parallel
+    /// and stores back its position, velocity and acceleration. `init` makes
+    /// the scratch value each rayon worker passes along.
+    pub(crate) fn par_update<T, I, F>(&mut self, species: &SpeciesTable, init: I, update: F)
+    where
+        I: Fn() -> T + Sync + Send,
+        F: Fn(&mut T, usize, &mut Boid) + Sync + Send,
+    {
+        let (ids, kinds) = (&self.ids, &self.species);
+        self.positions
+            .par_iter_mut()
+            .zip(&mut self.velocities)
+            .zip(&mut self.accelerations)
+            .enumerate()
+            .for_each_init(
+                init,
+                |scratch, (i, ((position, velocity), acceleration))| {
+                    let config = species.config(kinds[i]);
+                    let mut boid = Boid {
+                        position: *position,
+                        velocity: *velocity,
+                        acceleration: *acceleration,
+                        max_speed: config.max_velocity,
+                        max_force: config.max_force,
+                        radius: config.radius,
+                        id: ids[i],
+                        species: kinds[i],
+                    };
+                    update(scratch, i, &mut boid);
+                    *position = boid.position;
+                    *velocity = boid.velocity;
+                    *acceleration = boid.acceleration;
+                },
+            );
+    }
+}
@@ -0,0 +1,342 @@
+use crate::boid::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Every tunable of the flocking model. Missing keys in a config file fall
+/// back to the `BOID_*` defaults, so a file only needs the values it changes.
+///
+/// Boids gather neighbours out to `perception_radius`, the larger of the two
+/// interaction radii, so every neighbour a rule can react to is seen.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulationConfig {
+    pub radius: f32,
+    pub max_velocity: f32,
+    pub max_force: f32,
+    pub avoid_radius: f32,
+    pub follow_radius: f32,
+    pub seperation_factor: f32,
+    pub cohesion_factor: f32,
+    pub alignment_factor: f32,
+    /// Full width in degrees of the cone around a boid's heading in which it
+    /// sees neighbours; 360 means no blind spot.
+    pub view_angle: f32,
+    /// How much less a neighbour counts at `follow_radius` than right next to
+    /// the boid, from 0 (no weighting) to 1.
+    pub distance_weighting: f32,
+    /// How much less a neighbour counts at the edge of the view cone than
+    /// straight ahead, from 0 (no weighting) to 1.
+    pub angle_weighting: f32,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        SimulationConfig {
+            radius: BOID_RADIUS,
+            max_velocity: BOID_MAX_VELOCITY,
+            max_force: BOID_MAX_FORCE,
+            avoid_radius: BOID_AVOID_RADIUS,
+            follow_radius: BOID_FOLLOW_RADIUS,
+            seperation_factor: BOID_SEPERATION_FACTOR,
+            cohesion_factor: BOID_COHESION_FACTOR,
+            alignment_factor: BOID_ALIGNMENT_FACTOR,
+            view_angle: BOID_VIEW_ANGLE,
+            distance_weighting: 0.0,
+            angle_weighting: 0.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse(String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "could not read config: {}", err),
+            ConfigError::Parse(msg) => write!(f, "could not parse config: {}", msg),
+            ConfigError::Invalid(msg) => write!(f, "invalid config: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(err: std::io::Error) -> Self {
+        ConfigError::Io(err)
+    }
+}
+
+impl SimulationConfig {
+    /// Loads a config file, picking JSON for a `.json` extension and TOML
+    /// otherwise.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Self::from_json_str(&text),
+            _ => Self::from_toml_str(&text),
+        }
+    }
+
+    pub fn from_toml_str(text: &str) -> Result<Self, ConfigError> {
+        let config: SimulationConfig =
+            toml::from_str(text).map_err(|err| ConfigError::Parse(err.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_json_str(text: &str) -> Result<Self, ConfigError> {
+        let config: SimulationConfig =
+            serde_json::from_str(text).map_err(|err| ConfigError::Parse(err.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn to_toml_string(&self) -> String {
+        toml::to_string_pretty(self).expect("config is always representable as toml")
+    }
+
+    /// How far boids look for neighbours: the larger of `avoid_radius` and
+    /// `follow_radius`.
+    pub fn perception_radius(&self) -> f32 {
+        self.avoid_radius.max(self.follow_radius)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let fields = [
+            ("radius", self.radius),
+            ("max_velocity", self.max_velocity),
+            ("max_force", self.max_force),
+            ("avoid_radius", self.avoid_radius),
+            ("follow_radius", self.follow_radius),
+            ("seperation_factor", self.seperation_factor),
+            ("cohesion_factor", self.cohesion_factor),
+            ("alignment_factor", self.alignment_factor),
+            ("view_angle", self.view_angle),
+            ("distance_weighting", self.distance_weighting),
+            ("angle_weighting", self.angle_weighting),
+        ];
+        for (name, value) in fields {
+            if !value.is_finite() {
+                return Err(ConfigError::Invalid(format!("{} must be finite", name)));
+            }
+        }
+        let positive = [
+            ("max_velocity", self.max_velocity),
+            ("max_force", self.max_force),
+        ];
+        for (name, value) in positive {
+            if value <= 0.0 {
+                return Err(ConfigError::Invalid(format!("{} must be positive", name)));
+            }
+        }
+        let non_negative = [
+            ("radius", self.radius),
+            ("avoid_radius", self.avoid_radius),
+            ("follow_radius", self.follow_radius),
+        ];
+        for (name, value) in non_negative {
+            if value < 0.0 {
+                return Err(ConfigError::Invalid(format!(
+                    "{} must not be negative",
+                    name
+                )));
+            }
+        }
+        if self.perception_radius() <= 0.0 {
+            return Err(ConfigError::Invalid(
+                "avoid_radius or follow_radius must be positive".to_string(),
+            ));
+        }
+        if self.view_angle <= 0.0 || self.view_angle > 360.0 {
+            return Err(ConfigError::Invalid(
+                "view_angle must be in (0, 360]".to_string(),
+            ));
+        }
+        let fractions = [
+            ("distance_weighting", self.distance_weighting),
+            ("angle_weighting", self.angle_weighting),
+        ];
+        for (name, value) in fractions {
+            if !(0.0..=1.0).contains(&value) {
+                return Err(ConfigError::Invalid(format!(
+                    "{} must be between 0 and 1",
+                    name
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Polls a config file's modification time and reloads it when it changes.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let modified = modified_time(&path);
+        ConfigWatcher { path, modified }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the freshly loaded config if the file changed since the last
+    /// poll, or `None` if it did not.
+    pub fn poll(&mut self) -> Option<Result<SimulationConfig, ConfigError>> {
+        let modified = modified_time(&self.path);
+        if modified.is_none() || modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+        Some(SimulationConfig::load(&self.path))
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// A fresh directory for one test's files.
+    fn scratch(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("steering-config-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes `text` to `path` and stamps it `seconds` after the epoch, so
+    /// a rewrite is seen however coarse the file system's clock is.
+    fn write(path: &Path, text: &str, seconds: u64) {
+        fs::write(path, text).unwrap();
+        let file = fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds))
+            .unwrap();
+    }
+
+    #[test]
+    fn load_reads_toml_and_json() {
+        let dir = scratch("load");
+        let toml = dir.join("boids.toml");
+        write(&toml, "alignment_factor = 0.5\nview_angle = 270.0\n", 1);
+        let config = SimulationConfig::load(&toml).unwrap();
+        assert_eq!(config.alignment_factor, 0.5);
+        assert_eq!(config.view_angle, 270.0);
+        assert_eq!(config.max_velocity, BOID_MAX_VELOCITY);
+
+        let json = dir.join("boids.json");
+        write(
+            &json,
+            r#"{"cohesion_factor": 0.25, "follow_radius": 20}"#,
+            1,
+        );
+        let config = SimulationConfig::load(&json).unwrap();
+        assert_eq!(config.cohesion_factor, 0.25);
+        assert_eq!(config.perception_radius(), 20.0);
+        assert_eq!(config.alignment_factor, BOID_ALIGNMENT_FACTOR);
+
+        let original = SimulationConfig {
+            seperation_factor: 0.75,
+            ..SimulationConfig::default()
+        };
+        let round_trip = SimulationConfig::from_toml_str(&original.to_toml_string()).unwrap();
+        assert_eq!(round_trip, original);
+
+        assert!(matches!(
+            SimulationConfig::load(dir.join("missing.toml")),
+            Err(ConfigError::Io(_))
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unknown_and_invalid_fields_are_rejected() {
+        // A typo must not silently fall back to the default.
+        assert!(matches!(
+            SimulationConfig::from_toml_str("alignment_facter = 0.5"),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            SimulationConfig::from_json_str(r#"{"alignment_facter": 0.5}"#),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            SimulationConfig::from_toml_str("max_velocity = \"fast\""),
+            Err(ConfigError::Parse(_))
+        ));
+        for text in [
+            "max_velocity = 0.0",
+            "radius = -1.0",
+            "view_angle = 400.0",
+            "angle_weighting = 1.5",
+            "avoid_radius = 0.0\nfollow_radius = 0.0",
+            "cohesion_factor = nan",
+        ] {
+            assert!(
+                matches!(
+                    SimulationConfig::from_toml_str(text),
+                    Err(ConfigError::Invalid(_))
+                ),
+                "{text}"
+            );
+        }
+    }
+
+    #[test]
+    fn watcher_reloads_only_when_the_file_changes() {
+        let dir = scratch("watcher");
+        let path = dir.join("boids.toml");
+        write(&path, "alignment_factor = 0.5\n", 1);
+        let mut watcher = ConfigWatcher::new(&path);
+        assert_eq!(watcher.path(), path);
+        assert!(watcher.poll().is_none());
+
+        write(&path, "alignment_factor = 0.75\n", 2);
+        let config = watcher.poll().unwrap().unwrap();
+        assert_eq!(config.alignment_factor, 0.75);
+        assert!(watcher.poll().is_none());
+
+        write(&path, "alignment_factor = \n", 3);
+        assert!(matches!(watcher.poll(), Some(Err(ConfigError::Parse(_)))));
+        assert!(watcher.poll().is_none());
+
+        // A file that is briefly missing, as while an editor saves, is
+        // picked up again once it is back.
+        fs::remove_file(&path).unwrap();
+        assert!(watcher.poll().is_none());
+        write(&path, "alignment_factor = 0.25\n", 4);
+        assert_eq!(watcher.poll().unwrap().unwrap().alignment_factor, 0.25);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn watcher_picks_up_a_file_created_later() {
+        let dir = scratch("created");
+        let path = dir.join("boids.json");
+        let mut watcher = ConfigWatcher::new(&path);
+        assert!(watcher.poll().is_none());
+        write(&path, r#"{"view_angle": 180}"#, 1);
+        assert_eq!(watcher.poll().unwrap().unwrap().view_angle, 180.0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
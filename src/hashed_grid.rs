@@ -0,0 +1,441 @@
+use crate::spatial_hash::{CellLists, Rectangle};
+use crate::spatial_index::{push_nearest, SpatialIndex};
+use glam::Vec2;
+use rayon::prelude::*;
+
+/// Cell coordinates; cell `(x, y)` covers `[x, x + 1) * cell_size` by
+/// `[y, y + 1) * cell_size`.
+type Cell = (i32, i32);
+
+/// A uniform grid over the whole plane. Cell coordinates are hashed into a
+/// fixed table of buckets by wrapping them around its columns and rows, so
+/// the table tiles the plane: neighbouring cells stay next to each other in
+/// memory, and only cells a whole table width or height apart share a bucket.
+/// Memory depends on the table size rather than on how far apart items are.
+/// Cells that share a bucket are told apart by recomputing each item's cell
+/// from its stored position.
+pub struct HashedGrid {
+    cell_size: f32,
+    inv_cell_size: f32,
+    /// Columns minus one; both columns and rows are powers of two.
+    column_mask: i32,
+    column_bits: u32,
+    row_mask: i32,
+    buckets: CellLists,
+    /// Lowest and highest cell coordinates holding an item, if any.
+    extent: Option<(Cell, Cell)>,
+    /// Whether the extent is wider or taller than the table, so that two
+    /// occupied cells may share a bucket. Until it is, buckets are cells and
+    /// items need no checking.
+    aliased: bool,
+}
+
+impl HashedGrid {
+    /// An empty grid whose table has `columns` by `rows` buckets, each
+    /// rounded up to a power of two.
+    pub fn new(cell_size: f32, columns: usize, rows: usize) -> Self {
+        let columns = columns.clamp(1, 1 << 15).next_power_of_two();
+        let rows = rows.clamp(1, 1 << 15).next_power_of_two();
+        HashedGrid {
+            cell_size,
+            inv_cell_size: 1.0 / cell_size,
+            column_mask: columns as i32 - 1,
+            column_bits: columns.trailing_zeros(),
+            row_mask: rows as i32 - 1,
+            buckets: CellLists::new(columns * rows),
+            extent: None,
+            aliased: false,
+        }
+    }
+
+    /// A grid whose table covers `bounds`, so a flock inside them never has
+    /// two cells share a bucket.
+    pub fn covering(bounds: Rectangle, cell_size: f32) -> Self {
+        let columns = (bounds.width / cell_size).ceil().max(1.0) as usize;
+        let rows = (bounds.height / cell_size).ceil().max(1.0) as usize;
+        HashedGrid::new(cell_size, columns, rows)
+    }
+
+    pub fn cell_size(&self) -> f32 {
+        self.cell_size
+    }
+
+    pub fn bucket_count(&self) -> usize {
+        ((self.column_mask + 1) * (self.row_mask + 1)) as usize
+    }
+
+    pub fn clear(&mut self) {
+        self.buckets.clear();
+        self.set_extent(None);
+    }
+
+    fn cell_of(&self, position: Vec2) -> Cell {
+        (
+            (position.x * self.inv_cell_size).floor() as i32,
+            (position.y * self.inv_cell_size).floor() as i32,
+        )
+    }
+
+    fn bucket(&self, (x, y): Cell) -> usize {
+        (((y & self.row_mask) << self.column_bits) | (x & self.column_mask)) as usize
+    }
+
+    fn grow_extent(extent: Option<(Cell, Cell)>, (min, max): (Cell, Cell)) -> Option<(Cell, Cell)> {
+        Some(match extent {
+            None => (min, max),
+            Some((lo, hi)) => (
+                (lo.0.min(min.0), lo.1.min(min.1)),
+                (hi.0.max(max.0), hi.1.max(max.1)),
+            ),
+        })
+    }
+
+    /// Adds one item, shifting everything after its bucket. Fine for a
+    /// handful of items; use `rebuild` for many.
+    pub fn insert(&mut self, position: Vec2, index: usize) {
+        let cell = self.cell_of(position);
+        self.buckets.insert(self.bucket(cell), position, index);
+        self.set_extent(Self::grow_extent(self.extent, (cell, cell)));
+    }
+
+    /// Replaces the contents with items `0..count`, item `i` at
+    /// `position(i)`, sorting them into buckets in parallel.
+    pub fn rebuild<P>(&mut self, count: usize, position: P)
+    where
+        P: Fn(usize) -> Vec2 + Sync,
+    {
+        let extent = (0..count)
+            .into_par_iter()
+            .map(|i| {
+                let cell = self.cell_of(position(i));
+                Some((cell, cell))
+            })
+            .reduce(
+                || None,
+                |a, b| match b {
+                    Some(b) => Self::grow_extent(a, b),
+                    None => a,
+                },
+            );
+        self.set_extent(extent);
+        // `bucket` borrows the whole grid, so sort into the lists detached.
+        let mut buckets = std::mem::take(&mut self.buckets);
+        buckets.rebuild(count, position, |position| {
+            Some(self.bucket(self.cell_of(position)))
+        });
+        self.buckets = buckets;
+    }
+
+    fn set_extent(&mut self, extent: Option<(Cell, Cell)>) {
+        self.extent = extent;
+        self.aliased = extent.is_some_and(|(lo, hi)| {
+            hi.0 as i64 - lo.0 as i64 > self.column_mask as i64
+                || hi.1 as i64 - lo.1 as i64 > self.row_mask as i64
+        });
+    }
+
+    /// The cells from `min` to `max` that can hold items, or `None` if none
+    /// can.
+    fn clip(&self, min: Cell, max: Cell) -> Option<(Cell, Cell)> {
+        let (lo, hi) = self.extent?;
+        let min = (min.0.max(lo.0), min.1.max(lo.1));
+        let max = (max.0.min(hi.0), max.1.min(hi.1));
+        (min.0 <= max.0 && min.1 <= max.1).then_some((min, max))
+    }
+
+    /// Whether visiting every cell from `min` to `max` would look at more
+    /// buckets than scanning the whole table.
+    fn wider_than_table(&self, min: Cell, max: Cell) -> bool {
+        let cells = (max.0 as i64 - min.0 as i64 + 1) * (max.1 as i64 - min.1 as i64 + 1);
+        cells > self.bucket_count() as i64
+    }
+
+    /// Calls `callback(index, position)` for every item in `cell`.
+    fn visit_cell<F>(&self, cell: Cell, mut callback: F)
+    where
+        F: FnMut(usize, Vec2),
+    {
+        match self.extent {
+            Some((lo, hi))
+                if (lo.0..=hi.0).contains(&cell.0) && (lo.1..=hi.1).contains(&cell.1) => {}
+            // Empty, and may share a bucket with a cell that isn't.
+            _ => return,
+        }
+        let (indices, positions) = self.buckets.cell(self.bucket(cell));
+        for (&index, &position) in indices.iter().zip(positions) {
+            if !self.aliased || self.cell_of(position) == cell {
+                callback(index, position);
+            }
+        }
+    }
+
+    /// Calls `callback(index)` for every item in a cell `rect` overlaps.
+    pub fn query<F>(&self, rect: Rectangle, mut callback: F)
+    where
+        F: FnMut(usize),
+    {
+        let min = self.cell_of(Vec2::new(rect.x, rect.y));
+        let max = self.cell_of(Vec2::new(rect.x + rect.width, rect.y + rect.height));
+        let (min, max) = match self.clip(min, max) {
+            Some(range) => range,
+            None => return,
+        };
+        if self.wider_than_table(min, max) {
+            let (indices, positions) = self.buckets.all();
+            for (&index, &position) in indices.iter().zip(positions) {
+                let (x, y) = self.cell_of(position);
+                if (min.0..=max.0).contains(&x) && (min.1..=max.1).contains(&y) {
+                    callback(index);
+                }
+            }
+            return;
+        }
+        for y in min.1..=max.1 {
+            for x in min.0..=max.0 {
+                self.visit_cell((x, y), |index, _| callback(index));
+            }
+        }
+    }
+
+    /// Calls `callback(index, distance_squared)` for every item within
+    /// `radius` of `center`, skipping cells the circle doesn't reach.
+    pub fn query_radius<F>(&self, center: Vec2, radius: f32, mut callback: F)
+    where
+        F: FnMut(usize, f32),
+    {
+        let radius_sq = radius * radius;
+        let min = self.cell_of(center - Vec2::new(radius, radius));
+        let max = self.cell_of(center + Vec2::new(radius, radius));
+        let (min, max) = match self.clip(min, max) {
+            Some(range) => range,
+            None => return,
+        };
+        let mut report = |index: usize, position: Vec2| {
+            let distance_squared = (position - center).length_squared();
+            if distance_squared <= radius_sq {
+                callback(index, distance_squared);
+            }
+        };
+        if self.wider_than_table(min, max) {
+            let (indices, positions) = self.buckets.all();
+            for (&index, &position) in indices.iter().zip(positions) {
+                report(index, position);
+            }
+            return;
+        }
+        for y in min.1..=max.1 {
+            for x in min.0..=max.0 {
+                let cell = Rectangle::new(
+                    x as f32 * self.cell_size,
+                    y as f32 * self.cell_size,
+                    self.cell_size,
+                    self.cell_size,
+                );
+                if cell.distance_squared_to(center) <= radius_sq {
+                    self.visit_cell((x, y), &mut report);
+                }
+            }
+        }
+    }
+
+    /// Finds the `k` nearest items to `center` that `accept` lets through,
+    /// searching rings of cells outwards from the one holding `center` until
+    /// no unvisited cell can hold anything nearer, or scanning every item once
+    /// the rings would cover more cells than there are buckets. `found` is
+    /// refilled with `(distance_squared, index)` pairs, nearest first.
+    pub fn query_nearest<A>(
+        &self,
+        center: Vec2,
+        k: usize,
+        mut accept: A,
+        found: &mut Vec<(f32, usize)>,
+    ) where
+        A: FnMut(usize) -> bool,
+    {
+        found.clear();
+        let (lo, hi) = match self.extent {
+            Some(extent) if k > 0 => extent,
+            _ => return,
+        };
+        let (cx, cy) = self.cell_of(center);
+        // Rings beyond the farthest occupied cell are empty.
+        let max_ring = [cx - lo.0, hi.0 - cx, cy - lo.1, hi.1 - cy]
+            .into_iter()
+            .max()
+            .unwrap_or(0)
+            .max(0);
+
+        let mut visit = |cell: Cell, found: &mut Vec<(f32, usize)>| {
+            self.visit_cell(cell, |index, position| {
+                if accept(index) {
+                    let distance_squared = (position - center).length_squared();
+                    push_nearest(found, k, distance_squared, index);
+                }
+            });
+        };
+
+        for ring in 0..=max_ring {
+            if ring > 0 && found.len() == k {
+                // Everything in this ring lies outside the block of cells
+                // already searched, so at least this far from `center`. Sides
+                // of the block past the occupied cells have nothing beyond.
+                let (first_x, last_x) = (cx - ring + 1, cx + ring - 1);
+                let (first_y, last_y) = (cy - ring + 1, cy + ring - 1);
+                let edge = |outside: bool, cells: i32, beyond: f32| {
+                    if outside {
+                        beyond
+                    } else {
+                        cells as f32 * self.cell_size
+                    }
+                };
+                let left = edge(first_x <= lo.0, first_x, f32::NEG_INFINITY);
+                let right = edge(last_x >= hi.0, last_x + 1, f32::INFINITY);
+                let bottom = edge(first_y <= lo.1, first_y, f32::NEG_INFINITY);
+                let top = edge(last_y >= hi.1, last_y + 1, f32::INFINITY);
+                let reach = (center.x - left)
+                    .min(right - center.x)
+                    .min(center.y - bottom)
+                    .min(top - center.y)
+                    .max(0.0);
+                if found[k - 1].0 <= reach * reach {
+                    return;
+                }
+            }
+            if self.wider_than_table((cx - ring, cy - ring), (cx + ring, cy + ring)) {
+                break;
+            }
+            if ring == 0 {
+                visit((cx, cy), found);
+                continue;
+            }
+            for dx in -ring..=ring {
+                visit((cx + dx, cy - ring), found);
+                visit((cx + dx, cy + ring), found);
+            }
+            for dy in (-ring + 1)..ring {
+                visit((cx - ring, cy + dy), found);
+                visit((cx + ring, cy + dy), found);
+            }
+        }
+        if self.wider_than_table(
+            (cx - max_ring, cy - max_ring),
+            (cx + max_ring, cy + max_ring),
+        ) {
+            // The rings stopped early; settle it by looking at everything.
+            found.clear();
+            let (indices, positions) = self.buckets.all();
+            for (&index, &position) in indices.iter().zip(positions) {
+                if accept(index) {
+                    let distance_squared = (position - center).length_squared();
+                    push_nearest(found, k, distance_squared, index);
+                }
+            }
+        }
+    }
+}
+
+impl SpatialIndex for HashedGrid {
+    fn clear(&mut self) {
+        HashedGrid::clear(self);
+    }
+
+    fn insert(&mut self, position: Vec2, index: usize) {
+        HashedGrid::insert(self, position, index);
+    }
+
+    fn rebuild<P>(&mut self, count: usize, position: P)
+    where
+        P: Fn(usize) -> Vec2 + Sync,
+    {
+        HashedGrid::rebuild(self, count, position);
+    }
+
+    fn query<F>(&self, rect: Rectangle, callback: F)
+    where
+        F: FnMut(usize),
+    {
+        HashedGrid::query(self, rect, callback);
+    }
+
+    fn query_radius<P, F>(&self, center: Vec2, radius: f32, _position: P, callback: F)
+    where
+        P: Fn(usize) -> Vec2,
+        F: FnMut(usize, f32),
+    {
+        HashedGrid::query_radius(self, center, radius, callback);
+    }
+
+    fn query_nearest<P, A>(
+        &self,
+        center: Vec2,
+        k: usize,
+        _position: P,
+        accept: A,
+        found: &mut Vec<(f32, usize)>,
+    ) where
+        P: Fn(usize) -> Vec2,
+        A: FnMut(usize) -> bool,
+    {
+        HashedGrid::query_nearest(self, center, k, accept, found);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    #[test]
+    fn cells_sharing_a_bucket_are_told_apart() {
+        // A 4x4 table of 10-unit cells repeats every 40 units, so all four
+        // points land in the same bucket.
+        let points = [
+            Vec2::new(5.0, 5.0),
+            Vec2::new(45.0, 5.0),
+            Vec2::new(5.0, 45.0),
+            Vec2::new(-35.0, -35.0),
+        ];
+        let mut grid = HashedGrid::new(10.0, 4, 4);
+        grid.rebuild(points.len(), |i| points[i]);
+        assert_eq!(grid.bucket_count(), 16);
+        let buckets: Vec<usize> = points
+            .iter()
+            .map(|&p| grid.bucket(grid.cell_of(p)))
+            .collect();
+        assert!(buckets.iter().all(|&bucket| bucket == buckets[0]));
+        for (i, &point) in points.iter().enumerate() {
+            let mut found = Vec::new();
+            grid.query_radius(point, 3.0, |j, d| found.push((j, d)));
+            assert_eq!(found, [(i, 0.0)]);
+
+            let mut found = Vec::new();
+            grid.query(
+                Rectangle::new(point.x - 3.0, point.y - 3.0, 6.0, 6.0),
+                |j| found.push(j),
+            );
+            assert_eq!(found, [i]);
+        }
+    }
+
+    #[test]
+    fn aliased_queries_match_brute_force() {
+        let mut rng = ChaCha8Rng::seed_from_u64(9);
+        let points: Vec<Vec2> = (0..2000)
+            .map(|_| Vec2::new(rng.gen_range(-500.0..500.0), rng.gen_range(-500.0..500.0)))
+            .collect();
+        let mut grid = HashedGrid::new(10.0, 8, 8);
+        grid.rebuild(points.len(), |i| points[i]);
+        for _ in 0..100 {
+            let center = Vec2::new(rng.gen_range(-520.0..520.0), rng.gen_range(-520.0..520.0));
+            let radius: f32 = rng.gen_range(0.0..60.0);
+            let mut found = Vec::new();
+            grid.query_radius(center, radius, |j, _| found.push(j));
+            found.sort_unstable();
+            let expected: Vec<usize> = (0..points.len())
+                .filter(|&j| (points[j] - center).length_squared() <= radius * radius)
+                .collect();
+            assert_eq!(found, expected);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+/// Stable handle to a boid. Stays valid while the boid is alive no matter how
+/// `Simulation::boids` gets reordered, and never matches a later boid that
+/// reuses the same slot because the generation is bumped on every despawn.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BoidId {
+    slot: u32,
+    generation: u32,
+}
+
+impl BoidId {
+    pub(crate) fn from_raw(slot: u32, generation: u32) -> Self {
+        BoidId { slot, generation }
+    }
+
+    pub fn slot(&self) -> u32 {
+        self.slot
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Slot {
+    generation: u32,
+    dense: Option<usize>,
+}
+
+/// Maps `BoidId`s to positions in the dense boid vector.
+#[derive(Debug, Clone, Default)]
+pub struct BoidIds {
+    slots: Vec<Slot>,
+    free: Vec<u32>,
+}
+
+impl BoidIds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hands out an id for a boid stored at `dense`, reusing freed slots first.
+    pub fn allocate(&mut self, dense: usize) -> BoidId {
+        match self.free.pop() {
+            Some(slot) => {
+                let entry = &mut self.slots[slot as usize];
+                entry.dense = Some(dense);
+                BoidId {
+                    slot,
+                    generation: entry.generation,
+                }
+            }
+            None => {
+                let slot = self.slots.len() as u32;
+                self.slots.push(Slot {
+                    generation: 0,
+                    dense: Some(dense),
+                });
+                BoidId {
+                    slot,
+                    generation: 0,
+                }
+            }
+        }
+    }
+
+    /// Releases `id`, returning where its boid was stored, or `None` if the id
+    /// is stale.
+    pub fn free(&mut self, id: BoidId) -> Option<usize> {
+        let dense = self.dense_index(id)?;
+        let entry = &mut self.slots[id.slot as usize];
+        entry.dense = None;
+        entry.generation = entry.generation.wrapping_add(1);
+        self.free.push(id.slot);
+        Some(dense)
+    }
+
+    pub fn dense_index(&self, id: BoidId) -> Option<usize> {
+        let entry = self.slots.get(id.slot as usize)?;
+        if entry.generation != id.generation {
+            return None;
+        }
+        entry.dense
+    }
+
+    pub fn contains(&self, id: BoidId) -> bool {
+        self.dense_index(id).is_some()
+    }
+
+    pub(crate) fn generations(&self) -> impl Iterator<Item = u32> + '_ {
+        self.slots.iter().map(|slot| slot.generation)
+    }
+
+    pub(crate) fn free_slots(&self) -> &[u32] {
+        &self.free
+    }
+
+    /// Rebuilds the map from saved slot generations and free list, with the
+    /// live boids' ids in dense order. Returns `None` if the parts disagree:
+    /// every slot must be either live or listed as free exactly once.
+    pub(crate) fn from_parts(
+        generations: Vec<u32>,
+        free: Vec<u32>,
+        live: impl IntoIterator<Item = BoidId>,
+    ) -> Option<Self> {
+        let mut slots: Vec<Slot> = generations
+            .into_iter()
+            .map(|generation| Slot {
+                generation,
+                dense: None,
+            })
+            .collect();
+        for (dense, id) in live.into_iter().enumerate() {
+            let slot = slots.get_mut(id.slot as usize)?;
+            if slot.generation != id.generation || slot.dense.is_some() {
+                return None;
+            }
+            slot.dense = Some(dense);
+        }
+        let mut listed = vec![false; slots.len()];
+        for &slot in &free {
+            if slots.get(slot as usize)?.dense.is_some() || listed[slot as usize] {
+                return None;
+            }
+            listed[slot as usize] = true;
+        }
+        let dead = slots.iter().filter(|slot| slot.dense.is_none()).count();
+        if free.len() != dead {
+            return None;
+        }
+        Some(BoidIds { slots, free })
+    }
+
+    /// Records that the boid with `id` now lives at `dense`.
+    pub fn relocate(&mut self, id: BoidId, dense: usize) {
+        if let Some(entry) = self.slots.get_mut(id.slot as usize) {
+            if entry.generation == id.generation {
+                entry.dense = Some(dense);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freed_slots_are_reused_with_a_new_generation() {
+        let mut ids = BoidIds::new();
+        let a = ids.allocate(0);
+        let b = ids.allocate(1);
+        assert_eq!((a.slot(), b.slot()), (0, 1));
+        assert_eq!(ids.free(a), Some(0));
+        assert_eq!(ids.free(a), None);
+        assert!(!ids.contains(a));
+
+        let c = ids.allocate(0);
+        assert_eq!((c.slot(), c.generation()), (0, 1));
+        assert_eq!(ids.dense_index(a), None);
+        assert_eq!(ids.dense_index(c), Some(0));
+        assert_eq!(ids.dense_index(b), Some(1));
+    }
+
+    #[test]
+    fn relocate_ignores_stale_ids() {
+        let mut ids = BoidIds::new();
+        let a = ids.allocate(0);
+        ids.free(a);
+        let b = ids.allocate(3);
+        ids.relocate(a, 7);
+        assert_eq!(ids.dense_index(b), Some(3));
+        ids.relocate(b, 7);
+        assert_eq!(ids.dense_index(b), Some(7));
+    }
+
+    #[test]
+    fn unknown_slots_resolve_to_nothing() {
+        let ids = BoidIds::new();
+        assert_eq!(ids.dense_index(BoidId::from_raw(4, 0)), None);
+    }
+}
@@ -0,0 +1,216 @@
+use crate::boid::Boid;
+use glam::Vec2;
+
+/// Default simulation step, in seconds.
+pub const DEFAULT_TIME_STEP: f32 = 1.0 / 60.0;
+/// Most fixed steps `FixedTimestep::advance` runs for one call, so a long
+/// stall (window drag, breakpoint) doesn't trigger a burst of catch-up work.
+pub const MAX_STEPS_PER_ADVANCE: u32 = 8;
+
+/// How a boid's velocity and position are advanced over one time step.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Integrator {
+    /// Position from the old velocity, then velocity from the acceleration.
+    ExplicitEuler,
+    /// Velocity from the acceleration, then position from the new velocity.
+    #[default]
+    SemiImplicitEuler,
+    /// Position from the old velocity and acceleration, then velocity from the
+    /// mean of the old and new accelerations. Forces are evaluated between the
+    /// two halves, at the updated positions.
+    VelocityVerlet,
+}
+
+impl Integrator {
+    /// Whether positions move before forces are computed for this step.
+    pub fn drifts_first(&self) -> bool {
+        *self == Integrator::VelocityVerlet
+    }
+
+    /// First half of a Verlet step, using last step's acceleration.
+    pub fn drift(&self, boid: &mut Boid, dt: f32) {
+        boid.position += boid.velocity * dt + boid.acceleration * (0.5 * dt * dt);
+    }
+
+    /// Applies `force` (an acceleration in units per second squared, clamped
+    /// to the boid's `max_force`) and advances the boid by `dt` seconds. The
+    /// clamped acceleration is left on the boid for inspection and for the
+    /// next Verlet drift.
+    pub fn integrate(&self, boid: &mut Boid, force: Vec2, dt: f32) {
+        let previous = boid.acceleration;
+        boid.acceleration = Vec2::new(0.0, 0.0);
+        boid.apply_force(force);
+        match self {
+            Integrator::ExplicitEuler => {
+                boid.position += boid.velocity * dt;
+                boid.velocity =
+                    (boid.velocity + boid.acceleration * dt).clamp_length_max(boid.max_speed);
+            }
+            Integrator::SemiImplicitEuler => {
+                boid.velocity =
+                    (boid.velocity + boid.acceleration * dt).clamp_length_max(boid.max_speed);
+                boid.position += boid.velocity * dt;
+            }
+            Integrator::VelocityVerlet => {
+                boid.velocity = (boid.velocity + (previous + boid.acceleration) * (0.5 * dt))
+                    .clamp_length_max(boid.max_speed);
+            }
+        }
+    }
+}
+
+/// Whether `seconds` can be a time step: positive and finite.
+pub(crate) fn is_valid_step(seconds: f32) -> bool {
+    seconds.is_finite() && seconds > 0.0
+}
+
+/// Accumulates wall-clock time and hands it out in fixed steps, so motion is
+/// the same whatever the display's frame rate.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FixedTimestep {
+    pub step: f32,
+    pub max_steps: u32,
+    accumulator: f32,
+}
+
+impl Default for FixedTimestep {
+    fn default() -> Self {
+        FixedTimestep::new(DEFAULT_TIME_STEP)
+    }
+}
+
+impl FixedTimestep {
+    pub fn new(step: f32) -> Self {
+        FixedTimestep {
+            step,
+            max_steps: MAX_STEPS_PER_ADVANCE,
+            accumulator: 0.0,
+        }
+    }
+
+    /// Adds `elapsed` seconds and returns how many whole steps are due. Time
+    /// beyond `max_steps` steps is dropped.
+    pub fn advance(&mut self, elapsed: f32) -> u32 {
+        self.accumulator += elapsed.max(0.0);
+        let mut steps = 0;
+        while self.accumulator >= self.step && steps < self.max_steps {
+            self.accumulator -= self.step;
+            steps += 1;
+        }
+        if steps == self.max_steps {
+            self.accumulator = self.accumulator.min(self.step);
+        }
+        steps
+    }
+
+    /// Time carried over to the next `advance`, in seconds.
+    pub fn accumulator(&self) -> f32 {
+        self.accumulator
+    }
+
+    pub(crate) fn set_accumulator(&mut self, accumulator: f32) {
+        self.accumulator = accumulator;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::id::BoidId;
+    use glam::vec2;
+
+    fn boid() -> Boid {
+        Boid {
+            position: vec2(0.0, 0.0),
+            velocity: vec2(1.0, 0.0),
+            acceleration: vec2(0.0, 0.0),
+            max_speed: 100.0,
+            max_force: 100.0,
+            radius: 1.0,
+            id: BoidId::from_raw(0, 0),
+            species: 0,
+        }
+    }
+
+    fn step(integrator: Integrator, boid: &mut Boid, force: Vec2, dt: f32) {
+        if integrator.drifts_first() {
+            integrator.drift(boid, dt);
+        }
+        integrator.integrate(boid, force, dt);
+    }
+
+    #[test]
+    fn euler_integrators_differ_in_which_velocity_moves_the_boid() {
+        let force = vec2(0.0, 2.0);
+        let mut explicit = boid();
+        Integrator::ExplicitEuler.integrate(&mut explicit, force, 0.5);
+        assert_eq!(explicit.position, vec2(0.5, 0.0));
+        assert_eq!(explicit.velocity, vec2(1.0, 1.0));
+
+        let mut semi_implicit = boid();
+        Integrator::SemiImplicitEuler.integrate(&mut semi_implicit, force, 0.5);
+        assert_eq!(semi_implicit.position, vec2(0.5, 0.5));
+        assert_eq!(semi_implicit.velocity, vec2(1.0, 1.0));
+        assert_eq!(semi_implicit.acceleration, force);
+    }
+
+    #[test]
+    fn verlet_is_exact_under_constant_acceleration() {
+        let acceleration = vec2(0.0, 2.0);
+        let mut verlet = boid();
+        verlet.acceleration = acceleration;
+        for _ in 0..4 {
+            step(Integrator::VelocityVerlet, &mut verlet, acceleration, 0.25);
+        }
+        // One second: x = v t, y = a t² / 2.
+        assert!(verlet.position.distance(vec2(1.0, 1.0)) < 1e-6);
+        assert!(verlet.velocity.distance(vec2(1.0, 2.0)) < 1e-6);
+    }
+
+    #[test]
+    fn integrators_clamp_force_and_speed() {
+        for integrator in [
+            Integrator::ExplicitEuler,
+            Integrator::SemiImplicitEuler,
+            Integrator::VelocityVerlet,
+        ] {
+            let mut b = boid();
+            step(integrator, &mut b, vec2(0.0, 1000.0), 0.5);
+            assert!((b.acceleration.length() - b.max_force).abs() < 1e-3);
+            for _ in 0..10 {
+                step(integrator, &mut b, vec2(0.0, 1000.0), 0.5);
+            }
+            assert!(b.velocity.length() <= b.max_speed + 1e-3, "{integrator:?}");
+        }
+    }
+
+    #[test]
+    fn fixed_timestep_hands_out_whole_steps() {
+        let mut time = FixedTimestep::new(0.25);
+        assert_eq!(time.advance(0.1), 0);
+        assert_eq!(time.advance(0.2), 1);
+        assert!((time.accumulator() - 0.05).abs() < 1e-6);
+        assert_eq!(time.advance(0.7), 3);
+        assert!((time.accumulator() - 0.0).abs() < 1e-6);
+        assert_eq!(time.advance(-1.0), 0);
+        assert_eq!(time.accumulator(), 0.0);
+    }
+
+    #[test]
+    fn fixed_timestep_drops_time_beyond_max_steps() {
+        let mut time = FixedTimestep::new(0.25);
+        time.max_steps = 4;
+        assert_eq!(time.advance(10.0), 4);
+        assert!(time.accumulator() <= time.step);
+        assert_eq!(time.advance(0.0), 1);
+        assert_eq!(time.advance(0.0), 0);
+    }
+
+    #[test]
+    fn only_positive_finite_steps_are_valid() {
+        assert!(is_valid_step(DEFAULT_TIME_STEP));
+        for seconds in [0.0, -0.5, f32::NAN, f32::INFINITY] {
+            assert!(!is_valid_step(seconds), "{seconds}");
+        }
+    }
+}
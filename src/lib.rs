@@ -1,4 +1,16 @@
+pub mod behavior;
 pub mod boid;
+pub mod config;
+pub mod hashed_grid;
+pub mod id;
+pub mod integrator;
+pub mod metrics;
+pub mod obstacle;
 pub mod spatial_hash;
+pub mod spatial_index;
+pub mod species;
+pub mod pointer;
+pub mod predation;
 pub mod quadtree;
+pub mod recorder;
 pub mod simulation;
@@ -1,6 +1,40 @@
 use nannou::prelude::*;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use steering::behavior::{NeighborMode, TOPOLOGICAL_NEIGHBORS};
+use steering::boid::BoundaryMode;
+use steering::config::{ConfigError, ConfigWatcher, SimulationConfig};
+use steering::integrator::Integrator;
+use steering::metrics::{FlockMetrics, Metrics};
+use steering::obstacle::{Obstacle, ObstacleId};
+use steering::pointer::PointerMode;
+use steering::predation::{PursuitTarget, PREDATOR_CAPTURE_RADIUS};
+use steering::recorder::TrajectoryRecorder;
+use steering::simulation::{Simulation, SimulationBuilder, SortOrder};
 use steering::spatial_hash::Rectangle;
-use steering::simulation::Simulation;
+use steering::spatial_index::IndexKind;
+use steering::species::{Role, SpeciesId};
+
+const DEFAULT_CONFIG_PATH: &str = "boids.toml";
+const SNAPSHOT_PATH: &str = "snapshot.boids";
+const TRAJECTORY_PATH: &str = "trajectory.csv";
+/// Steps between re-sorting boids into Morton order.
+const RESORT_EVERY: u32 = 30;
+const TRAJECTORY_EVERY: u64 = 10;
+const SOFT_WALL_MARGIN: f32 = 50.0;
+const SOFT_WALL_STRENGTH: f32 = 0.5;
+/// Predators spawned with P are this much faster than the flock.
+const PREDATOR_SPEEDUP: f32 = 1.25;
+const OBSTACLE_SIZE: f32 = 60.0;
+/// Size of the metrics panel H shows in the top left corner.
+const HUD_WIDTH: f32 = 320.0;
+const HUD_HEIGHT: f32 = 220.0;
+const HUD_MARGIN: f32 = 10.0;
+/// Steps between HUD refreshes, once a second at the default time step.
+const HUD_EVERY: u64 = 60;
+/// View cone widths, in degrees, that V steps through.
+const VIEW_ANGLES: [f32; 4] = [360.0, 270.0, 180.0, 120.0];
 
 fn main() {
     nannou::app(model).update(update).run();
@@ -9,13 +43,128 @@ fn main() {
 struct Model {
     mouse_position: Vec2,
     simulation: Simulation,
-    counter: u32,
+    config_watcher: Option<ConfigWatcher>,
+    /// Pointer mode chosen with the keyboard, restored when a mouse button
+    /// that temporarily overrides it is released.
+    pointer_mode: PointerMode,
+    recorder: Option<TrajectoryRecorder<BufWriter<File>>>,
+    captured: usize,
+    /// Obstacles placed with O, which picks the next shape from this.
+    obstacles_placed: usize,
+    /// Obstacle following the mouse while the middle button is held.
+    dragging: Option<ObstacleId>,
+    metrics: Metrics,
+    /// Latest flock metrics while the HUD is shown, refreshed every
+    /// `HUD_EVERY` steps.
+    hud: Option<FlockMetrics>,
+    /// Fixed steps taken since startup.
+    steps: u64,
 }
 
 impl Model {
-    pub fn update(&mut self, app: &App) {
+    pub fn update(&mut self, app: &App, elapsed: f32) {
+        self.reload_config();
         self.mouse_position = app.mouse.position();
-        self.simulation.update(self.mouse_position);
+        if let Some(id) = self.dragging {
+            if let Some(obstacle) = self.simulation.obstacles_mut().get_mut(id) {
+                obstacle.position = self.mouse_position;
+            }
+        }
+        self.simulation.pointer.position = self.mouse_position;
+        let Model {
+            simulation,
+            recorder,
+            captured,
+            metrics,
+            hud,
+            steps,
+            ..
+        } = self;
+        simulation.advance_with(elapsed, |simulation| {
+            *captured += simulation.take_captures().len();
+            record_trajectory(recorder, simulation);
+            if hud.is_some() && steps.is_multiple_of(HUD_EVERY) {
+                *hud = Some(metrics.measure(simulation));
+            }
+            *steps += 1;
+        });
+    }
+
+    fn toggle_hud(&mut self) {
+        self.hud = match self.hud {
+            Some(_) => None,
+            None => Some(self.metrics.measure(&self.simulation)),
+        };
+    }
+
+    fn toggle_recording(&mut self) {
+        match self.recorder.take() {
+            Some(mut recorder) => match recorder.flush() {
+                Ok(()) => println!("saved {}", TRAJECTORY_PATH),
+                Err(err) => eprintln!("{}: {}", TRAJECTORY_PATH, err),
+            },
+            None => {
+                match TrajectoryRecorder::create(TRAJECTORY_PATH, TRAJECTORY_EVERY, &self.simulation) {
+                    Ok(recorder) => {
+                        println!("recording to {}", TRAJECTORY_PATH);
+                        self.recorder = Some(recorder);
+                    }
+                    Err(err) => eprintln!("{}: {}", TRAJECTORY_PATH, err),
+                }
+            }
+        }
+    }
+
+    fn reload_config(&mut self) {
+        let watcher = match self.config_watcher.as_mut() {
+            Some(watcher) => watcher,
+            None => return,
+        };
+        match watcher.poll() {
+            Some(Ok(config)) => match set_flock_config(&mut self.simulation, config) {
+                Ok(()) => {
+                    println!("reloaded {}", watcher.path().display());
+                    self.metrics.neighbor_radius = config.perception_radius();
+                    report_coverage(&self.simulation);
+                }
+                Err(err) => eprintln!("{}: {}", watcher.path().display(), err),
+            },
+            Some(Err(err)) => eprintln!("{}: {}", watcher.path().display(), err),
+            None => {}
+        }
+    }
+}
+
+/// Writes the current step to `recorder`, dropping it if the write fails.
+fn record_trajectory(
+    recorder: &mut Option<TrajectoryRecorder<BufWriter<File>>>,
+    simulation: &Simulation,
+) {
+    if let Some(writer) = recorder.as_mut() {
+        if let Err(err) = writer.record(simulation) {
+            eprintln!("{}: {}, recording stopped", TRAJECTORY_PATH, err);
+            *recorder = None;
+        }
+    }
+}
+
+/// Warns about behaviours that react to neighbours outside the perception
+/// radius, so tuning doesn't silently run on a partial neighbourhood.
+fn report_coverage(simulation: &Simulation) {
+    for issue in simulation.check_coverage() {
+        eprintln!("warning: {}", issue);
+    }
+}
+
+/// The config file given as the first argument, or `boids.toml` in the
+/// working directory if it exists.
+fn config_path() -> Option<PathBuf> {
+    match std::env::args().nth(1) {
+        Some(path) => Some(PathBuf::from(path)),
+        None => {
+            let path = PathBuf::from(DEFAULT_CONFIG_PATH);
+            path.exists().then_some(path)
+        }
     }
 }
 
@@ -36,20 +185,39 @@ fn model(app: &App) -> Model {
         height: w_rect.h(),
     };
     dbg!(bounds);
-    let model = Model {
-        simulation: Simulation::new(1000000, bounds),
-        mouse_position: Vec2::new(0.0, 0.0),
-        counter: 0,
+    let config_path = config_path();
+    let config = match &config_path {
+        Some(path) => SimulationConfig::load(path).unwrap_or_else(|err| {
+            eprintln!("{}: {}, using defaults", path.display(), err);
+            SimulationConfig::default()
+        }),
+        None => SimulationConfig::default(),
     };
-    model
+    let simulation = SimulationBuilder::new(bounds)
+        .boid_count(1000000)
+        .config(config)
+        .resort(SortOrder::Morton, RESORT_EVERY)
+        .build();
+    println!("seed {}", simulation.seed());
+    report_coverage(&simulation);
+    let metrics = Metrics::new(bounds, config.perception_radius());
+    Model {
+        simulation,
+        config_watcher: config_path.map(ConfigWatcher::new),
+        mouse_position: Vec2::new(0.0, 0.0),
+        pointer_mode: PointerMode::Off,
+        recorder: None,
+        captured: 0,
+        obstacles_placed: 0,
+        dragging: None,
+        metrics,
+        hud: None,
+        steps: 0,
+    }
 }
 
 fn update(_app: &App, _model: &mut Model, _update: Update) {
-    _model.counter += 1;
-    if _model.counter > 60 {
-        _model.counter = 0;
-    }
-    _model.update(_app);
+    _model.update(_app, _update.since_last.as_secs_f32());
 }
 
 fn view(app: &App, _model: &Model, frame: Frame) {
@@ -57,33 +225,282 @@ fn view(app: &App, _model: &Model, frame: Frame) {
     let _w_rect = app.window_rect();
     draw.background().color(BLACK);
     _model.simulation.draw(&draw);
-    if _model.counter == 0 {
-        dbg!(app.fps());
+    if let Some(metrics) = &_model.hud {
+        let text = format!(
+            "{:.0} fps, {} captured\nalignment factor {:.2}\n{}",
+            app.fps(),
+            _model.captured,
+            _model.simulation.config().alignment_factor,
+            metrics
+        );
+        draw.text(&text)
+            .x_y(
+                _w_rect.left() + HUD_MARGIN + HUD_WIDTH / 2.0,
+                _w_rect.top() - HUD_MARGIN - HUD_HEIGHT / 2.0,
+            )
+            .w_h(HUD_WIDTH, HUD_HEIGHT)
+            .font_size(14)
+            .left_justify()
+            .align_text_top()
+            .color(WHITE);
     }
     draw.to_frame(app, &frame).unwrap();
 }
 
-fn handle_key_press(key: nannou::event::Key, simulation: &mut Simulation) {
+fn handle_key_press(key: nannou::event::Key, model: &mut Model) {
     use nannou::event::Key::*;
+    let simulation = &mut model.simulation;
+    let mut config = *simulation.config();
     match key {
-        W => simulation.boid_seperation_factor += 0.1,
-        Q => simulation.boid_seperation_factor -= 0.1,
-        S => simulation.boid_cohesion_factor += 0.1,
-        A => simulation.boid_cohesion_factor -= 0.1,
-        X => simulation.boid_alignment_factor += 0.05,
-        Z => simulation.boid_alignment_factor -= 0.05,
-        _ => {}
+        W => config.seperation_factor += 0.1,
+        Q => config.seperation_factor -= 0.1,
+        S => config.cohesion_factor += 0.1,
+        A => config.cohesion_factor -= 0.1,
+        X => config.alignment_factor += 0.05,
+        Z => config.alignment_factor -= 0.05,
+        V => config.view_angle = next_view_angle(config.view_angle),
+        B => simulation.boundary_mode = next_boundary_mode(simulation.boundary_mode),
+        M => {
+            model.pointer_mode = next_pointer_mode(model.pointer_mode);
+            simulation.pointer.mode = model.pointer_mode;
+        }
+        N => {
+            simulation.neighbor_mode = match simulation.neighbor_mode {
+                NeighborMode::Metric => NeighborMode::Topological {
+                    k: TOPOLOGICAL_NEIGHBORS,
+                },
+                NeighborMode::Topological { .. } => NeighborMode::Metric,
+            };
+        }
+        I => simulation.integrator = next_integrator(simulation.integrator),
+        G => simulation.set_index_kind(next_index_kind(simulation.requested_index_kind())),
+        R => {
+            model.toggle_recording();
+            return;
+        }
+        H => {
+            model.toggle_hud();
+            return;
+        }
+        P => {
+            spawn_predator(simulation, model.mouse_position);
+            return;
+        }
+        O => {
+            let obstacle = obstacle_shape(model.obstacles_placed, model.mouse_position);
+            simulation.obstacles_mut().add(obstacle);
+            model.obstacles_placed += 1;
+            return;
+        }
+        C => {
+            let predation = &mut simulation.predation;
+            predation.capture_radius = if predation.capture_radius > 0.0 {
+                0.0
+            } else {
+                PREDATOR_CAPTURE_RADIUS
+            };
+        }
+        T => {
+            let predation = &mut simulation.predation;
+            predation.target = match predation.target {
+                PursuitTarget::Nearest => PursuitTarget::Densest,
+                PursuitTarget::Densest => PursuitTarget::Nearest,
+            };
+        }
+        K => {
+            save_snapshot(simulation);
+            return;
+        }
+        L => {
+            if let Some(loaded) = load_snapshot() {
+                *simulation = loaded;
+                return;
+            }
+        }
+        _ => return,
+    }
+    if let Err(err) = set_flock_config(simulation, config) {
+        eprintln!("{}", err);
+        return;
+    }
+    print_status(simulation, model.pointer_mode);
+}
+
+/// One line with every setting the keyboard changes.
+fn print_status(simulation: &Simulation, pointer_mode: PointerMode) {
+    let config = simulation.config();
+    let predation = &simulation.predation;
+    println!(
+        "separation {:.2}, cohesion {:.2}, alignment {:.2}, view {}°, {:?}, {:?} index, \
+{:?}, {:?}, pointer {:?}, capture radius {}, {:?} target",
+        config.seperation_factor,
+        config.cohesion_factor,
+        config.alignment_factor,
+        config.view_angle,
+        simulation.boundary_mode,
+        simulation.index_kind(),
+        simulation.neighbor_mode,
+        simulation.integrator,
+        pointer_mode,
+        predation.capture_radius,
+        predation.target
+    );
+}
+
+/// Cycles through a circle, a tilted square and a triangle.
+fn obstacle_shape(placed: usize, position: Vec2) -> Obstacle {
+    let size = OBSTACLE_SIZE;
+    match placed % 3 {
+        0 => Obstacle::circle(position, size / 2.0),
+        1 => Obstacle::rect(position, Vec2::new(size, size), PI / 6.0),
+        _ => Obstacle::polyline(
+            position,
+            vec![
+                Vec2::new(0.0, size / 2.0),
+                Vec2::new(-size / 2.0, -size / 2.0),
+                Vec2::new(size / 2.0, -size / 2.0),
+            ],
+            true,
+        ),
+    }
+}
+
+/// The obstacle under `position`, if any.
+fn obstacle_at(simulation: &Simulation, position: Vec2) -> Option<ObstacleId> {
+    simulation
+        .obstacles()
+        .iter()
+        .find(|(_, obstacle)| obstacle.signed_distance(position).0 <= 0.0)
+        .map(|(id, _)| id)
+}
+
+/// Drops a predator at `position`, adding a predator species the first time.
+fn spawn_predator(simulation: &mut Simulation, position: Vec2) {
+    let species = simulation.species();
+    let existing = (0..species.len() as SpeciesId).find(|&id| species.role(id) == Role::Predator);
+    let predators = match existing {
+        Some(predators) => predators,
+        None => {
+            let config = predator_config(*simulation.config());
+            match simulation.add_predator_species(config) {
+                Ok(predators) => predators,
+                Err(err) => {
+                    eprintln!("predators: {}", err);
+                    return;
+                }
+            }
+        }
+    };
+    simulation.spawn_species(predators, position, Vec2::new(0.0, 0.0));
+}
+
+/// Predators fly like the flock, only faster.
+fn predator_config(mut config: SimulationConfig) -> SimulationConfig {
+    config.max_velocity *= PREDATOR_SPEEDUP;
+    config
+}
+
+/// Gives the flock `config`, and predator species the config derived from it,
+/// so they stay faster than the flock they hunt.
+fn set_flock_config(
+    simulation: &mut Simulation,
+    config: SimulationConfig,
+) -> Result<(), ConfigError> {
+    simulation.set_config(config)?;
+    let species = simulation.species();
+    let predators: Vec<SpeciesId> = (0..species.len() as SpeciesId)
+        .filter(|&id| species.role(id) == Role::Predator)
+        .collect();
+    for id in predators {
+        simulation.set_species_config(id, predator_config(config))?;
+    }
+    Ok(())
+}
+
+fn save_snapshot(simulation: &Simulation) {
+    let result = std::fs::File::create(SNAPSHOT_PATH)
+        .map_err(Into::into)
+        .and_then(|file| simulation.save_snapshot(std::io::BufWriter::new(file)));
+    match result {
+        Ok(()) => println!("saved {}", SNAPSHOT_PATH),
+        Err(err) => eprintln!("{}: {}", SNAPSHOT_PATH, err),
+    }
+}
+
+fn load_snapshot() -> Option<Simulation> {
+    let result = std::fs::File::open(SNAPSHOT_PATH)
+        .map_err(Into::into)
+        .and_then(|file| Simulation::load_snapshot(std::io::BufReader::new(file)));
+    match result {
+        Ok(simulation) => {
+            println!("loaded {}", SNAPSHOT_PATH);
+            Some(simulation)
+        }
+        Err(err) => {
+            eprintln!("{}: {}", SNAPSHOT_PATH, err);
+            None
+        }
+    }
+}
+
+fn next_boundary_mode(mode: BoundaryMode) -> BoundaryMode {
+    match mode {
+        BoundaryMode::Wrap => BoundaryMode::Reflect,
+        BoundaryMode::Reflect => BoundaryMode::SoftWalls {
+            margin: SOFT_WALL_MARGIN,
+            strength: SOFT_WALL_STRENGTH,
+        },
+        BoundaryMode::SoftWalls { .. } => BoundaryMode::Unbounded,
+        BoundaryMode::Unbounded => BoundaryMode::Wrap,
+    }
+}
+
+fn next_view_angle(angle: f32) -> f32 {
+    VIEW_ANGLES
+        .iter()
+        .copied()
+        .find(|&next| next < angle)
+        .unwrap_or(VIEW_ANGLES[0])
+}
+
+fn next_integrator(integrator: Integrator) -> Integrator {
+    match integrator {
+        Integrator::ExplicitEuler => Integrator::SemiImplicitEuler,
+        Integrator::SemiImplicitEuler => Integrator::VelocityVerlet,
+        Integrator::VelocityVerlet => Integrator::ExplicitEuler,
+    }
+}
+
+fn next_index_kind(kind: IndexKind) -> IndexKind {
+    match kind {
+        IndexKind::Grid => IndexKind::QuadTree,
+        IndexKind::QuadTree => IndexKind::Hashed,
+        IndexKind::Hashed => IndexKind::Grid,
+    }
+}
+
+fn next_pointer_mode(mode: PointerMode) -> PointerMode {
+    match mode {
+        PointerMode::Off => PointerMode::Attract,
+        PointerMode::Attract => PointerMode::Repel,
+        PointerMode::Repel => PointerMode::Predator,
+        PointerMode::Predator => PointerMode::Off,
     }
-    dbg!(simulation.boid_seperation_factor);
-    dbg!(simulation.boid_cohesion_factor);
-    dbg!(simulation.boid_alignment_factor);
 }
 
 fn event(_app: &App, _model: &mut Model, event: WindowEvent) {
     match event {
         // Keyboard events
-        KeyPressed(_key) => handle_key_press(_key, &mut _model.simulation),
+        KeyPressed(_key) => handle_key_press(_key, _model),
         KeyReleased(_key) => {}
+        // Holding a mouse button overrides the keyboard pointer mode
+        MousePressed(MouseButton::Left) => _model.simulation.pointer.mode = PointerMode::Attract,
+        MousePressed(MouseButton::Right) => _model.simulation.pointer.mode = PointerMode::Repel,
+        MousePressed(MouseButton::Middle) => {
+            _model.dragging = obstacle_at(&_model.simulation, _model.mouse_position)
+        }
+        MouseReleased(MouseButton::Middle) => _model.dragging = None,
+        MouseReleased(_button) => _model.simulation.pointer.mode = _model.pointer_mode,
         _ => {}
     }
 }
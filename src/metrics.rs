@@ -0,0 +1,501 @@
+//! Order parameters for the flock as a whole, to tell ordered flight,
+//! milling and disorder apart and to spot the transitions between them.
+
+use crate::recorder::TrajectoryFormat;
+use crate::simulation::Simulation;
+use crate::spatial_hash::Rectangle;
+use crate::spatial_index::{AnyIndex, SpatialIndex, Topology};
+use crate::species::Role;
+use glam::Vec2;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::f64::consts::TAU;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Neighbour counts from 0 up to one less than this get a bin each; the last
+/// bin holds everything from there up.
+pub const NEIGHBOR_BINS: usize = 32;
+pub const SPEED_BINS: usize = 16;
+
+/// Counts in bins of equal width starting at zero. The last bin also holds
+/// everything beyond it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    pub bin_width: f32,
+    pub counts: Vec<usize>,
+}
+
+impl Histogram {
+    fn new(bin_width: f32, bins: usize) -> Self {
+        Histogram {
+            bin_width,
+            counts: vec![0; bins],
+        }
+    }
+
+    fn add(&mut self, value: f32) {
+        let bin = if self.bin_width > 0.0 {
+            (value / self.bin_width) as usize
+        } else {
+            0
+        };
+        let last = self.counts.len() - 1;
+        self.counts[bin.min(last)] += 1;
+    }
+}
+
+/// One step's order parameters, over every prey boid; predators are left
+/// out. Offsets are measured the way the simulation measures them, so on a
+/// torus the centroid of a flock straddling an edge is where the flock is.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FlockMetrics {
+    pub boids: usize,
+    /// Length of the mean unit heading: 1 when every boid flies the same
+    /// way, near 0 when headings are random.
+    pub polarization: f32,
+    /// Length of the mean of `r̂ × v̂`, with `r` the offset from the
+    /// centroid: 1 when every boid circles the centroid the same way.
+    pub milling: f32,
+    /// Mean `r × v` about the centroid, positive counter-clockwise.
+    pub angular_momentum: f32,
+    pub centroid: Vec2,
+    /// Root mean square distance from the centroid.
+    pub spread: f32,
+    /// Mean distance to the nearest other boid, over boids that have one.
+    pub mean_nearest_distance: f32,
+    /// Mean number of other boids within the neighbour radius.
+    pub mean_neighbors: f32,
+    /// How many boids have 0, 1, 2, ... others within the neighbour radius.
+    pub neighbor_counts: Histogram,
+    pub speed_mean: f32,
+    pub speed_std_dev: f32,
+    pub speed_min: f32,
+    pub speed_max: f32,
+    /// Speeds in `SPEED_BINS` bins from 0 to `speed_max`.
+    pub speeds: Histogram,
+}
+
+impl fmt::Display for FlockMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "boids            {}", self.boids)?;
+        writeln!(f, "polarization     {:.3}", self.polarization)?;
+        writeln!(f, "milling          {:.3}", self.milling)?;
+        writeln!(f, "angular momentum {:.1}", self.angular_momentum)?;
+        writeln!(
+            f,
+            "centroid         ({:.1}, {:.1})",
+            self.centroid.x, self.centroid.y
+        )?;
+        writeln!(f, "spread           {:.1}", self.spread)?;
+        writeln!(f, "nearest distance {:.2}", self.mean_nearest_distance)?;
+        writeln!(f, "neighbours       {:.2}", self.mean_neighbors)?;
+        write!(
+            f,
+            "speed            {:.1} ± {:.1} ({:.1} to {:.1})",
+            self.speed_mean, self.speed_std_dev, self.speed_min, self.speed_max
+        )
+    }
+}
+
+/// Measures `FlockMetrics`, keeping the index it files boids in from one
+/// call to the next.
+pub struct Metrics {
+    /// Radius within which other boids count as neighbours.
+    pub neighbor_radius: f32,
+    bounds: Rectangle,
+    index: AnyIndex,
+    prey: Vec<usize>,
+}
+
+impl Metrics {
+    /// `bounds` should be the simulation's; boids are filed in the same kind
+    /// of index it uses, a `SpatialHashGrid` by default.
+    pub fn new(bounds: Rectangle, neighbor_radius: f32) -> Self {
+        Metrics {
+            neighbor_radius,
+            bounds,
+            index: AnyIndex::new(Default::default(), bounds, neighbor_radius),
+            prey: Vec::new(),
+        }
+    }
+
+    pub fn measure(&mut self, simulation: &Simulation) -> FlockMetrics {
+        let boids = simulation.boids();
+        let positions = boids.positions();
+        let velocities = boids.velocities();
+        let species = simulation.species();
+        self.prey.clear();
+        self.prey
+            .extend((0..boids.len()).filter(|&i| species.role(boids.species()[i]) == Role::Prey));
+        let prey = &self.prey;
+        let count = prey.len();
+        if count == 0 {
+            return FlockMetrics::default();
+        }
+        let topology = simulation.boundary_mode.topology(simulation.bounds());
+
+        let centroid = centroid(topology, prey.iter().map(|&i| positions[i]));
+        let mut heading = Vec2::new(0.0, 0.0);
+        let mut milling = 0.0f64;
+        let mut angular_momentum = 0.0f64;
+        let mut spread = 0.0f64;
+        let mut speed_sum = 0.0f64;
+        let mut speed_squares = 0.0f64;
+        let mut speed_min = f32::INFINITY;
+        let mut speed_max = 0.0f32;
+        for &i in prey {
+            let offset = topology.offset(centroid, positions[i]);
+            let velocity = velocities[i];
+            let speed = velocity.length();
+            let cross = offset.x * velocity.y - offset.y * velocity.x;
+            if speed > 0.0 {
+                heading += velocity / speed;
+                let distance = offset.length();
+                if distance > 0.0 {
+                    milling += (cross / (distance * speed)) as f64;
+                }
+            }
+            angular_momentum += cross as f64;
+            spread += offset.length_squared() as f64;
+            speed_sum += speed as f64;
+            speed_squares += (speed * speed) as f64;
+            speed_min = speed_min.min(speed);
+            speed_max = speed_max.max(speed);
+        }
+        let n = count as f64;
+        let speed_mean = speed_sum / n;
+        let mut speeds = Histogram::new(speed_max / SPEED_BINS as f32, SPEED_BINS);
+        for &i in prey {
+            speeds.add(velocities[i].length());
+        }
+
+        let kind = simulation.index_kind();
+        let resized = self
+            .index
+            .cell_size()
+            .is_some_and(|size| size != self.neighbor_radius);
+        if kind != self.index.kind() || resized {
+            self.index = AnyIndex::new(kind, self.bounds, self.neighbor_radius);
+        }
+        self.index.rebuild(count, |k| positions[prey[k]]);
+        let index = &self.index;
+        let radius = self.neighbor_radius;
+        // Per boid, in order: how many neighbours it has and how far the
+        // nearest is.
+        let neighborhoods: Vec<(usize, Option<f32>)> = (0..count)
+            .into_par_iter()
+            .map_init(Vec::new, |nearest, k| {
+                let position = positions[prey[k]];
+                let mut neighbors = 0;
+                topology.query_radius(
+                    index,
+                    position,
+                    radius,
+                    |other| positions[prey[other]],
+                    |other, _| neighbors += usize::from(other != k),
+                );
+                topology.query_nearest(
+                    index,
+                    position,
+                    1,
+                    |other| positions[prey[other]],
+                    |other| other != k,
+                    nearest,
+                );
+                let nearest = nearest.first().map(|&(d, _)| d.sqrt());
+                (neighbors, nearest)
+            })
+            .collect();
+        let mut neighbor_counts = Histogram::new(1.0, NEIGHBOR_BINS);
+        let mut neighbor_sum = 0.0f64;
+        let mut nearest_sum = 0.0f64;
+        let mut with_nearest = 0usize;
+        for &(neighbors, nearest) in &neighborhoods {
+            neighbor_counts.add(neighbors as f32);
+            neighbor_sum += neighbors as f64;
+            if let Some(distance) = nearest {
+                nearest_sum += distance as f64;
+                with_nearest += 1;
+            }
+        }
+
+        FlockMetrics {
+            boids: count,
+            polarization: (heading / count as f32).length(),
+            milling: (milling / n).abs() as f32,
+            angular_momentum: (angular_momentum / n) as f32,
+            centroid,
+            spread: (spread / n).sqrt() as f32,
+            mean_nearest_distance: if with_nearest > 0 {
+                (nearest_sum / with_nearest as f64) as f32
+            } else {
+                0.0
+            },
+            mean_neighbors: (neighbor_sum / n) as f32,
+            neighbor_counts,
+            speed_mean: speed_mean as f32,
+            speed_std_dev: (speed_squares / n - speed_mean * speed_mean)
+                .max(0.0)
+                .sqrt() as f32,
+            speed_min,
+            speed_max,
+            speeds,
+        }
+    }
+}
+
+/// The mean of `points`; on a torus, the circular mean along each axis.
+fn centroid(topology: Topology, points: impl Iterator<Item = Vec2>) -> Vec2 {
+    match topology {
+        Topology::Plane => {
+            let (sum, count) = points.fold((Vec2::new(0.0, 0.0), 0), |(sum, count), point| {
+                (sum + point, count + 1)
+            });
+            sum / count as f32
+        }
+        Topology::Torus(bounds) => {
+            // Each axis as an angle around a circle one period long.
+            let mut x = (0.0f64, 0.0f64);
+            let mut y = (0.0f64, 0.0f64);
+            for point in points {
+                let angle = TAU * ((point.x - bounds.x) / bounds.width) as f64;
+                x = (x.0 + angle.cos(), x.1 + angle.sin());
+                let angle = TAU * ((point.y - bounds.y) / bounds.height) as f64;
+                y = (y.0 + angle.cos(), y.1 + angle.sin());
+            }
+            let along = |(cos, sin): (f64, f64), start: f32, length: f32| {
+                start + length * (sin.atan2(cos) / TAU) as f32
+            };
+            topology.wrap(Vec2::new(
+                along(x, bounds.x, bounds.width),
+                along(y, bounds.y, bounds.height),
+            ))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Row<'a> {
+    step: u64,
+    boids: usize,
+    polarization: f32,
+    milling: f32,
+    angular_momentum: f32,
+    centroid: [f32; 2],
+    spread: f32,
+    mean_nearest_distance: f32,
+    mean_neighbors: f32,
+    neighbor_counts: &'a [usize],
+    speed_mean: f32,
+    speed_std_dev: f32,
+    speed_min: f32,
+    speed_max: f32,
+    speed_bin_width: f32,
+    speed_counts: &'a [usize],
+}
+
+/// Writes `FlockMetrics` every `every` steps, as CSV or NDJSON like
+/// `TrajectoryRecorder`. CSV rows carry the scalar metrics only; NDJSON rows
+/// also carry the neighbour-count and speed histograms.
+pub struct MetricsRecorder<W: Write> {
+    writer: W,
+    format: TrajectoryFormat,
+    metrics: Metrics,
+    every: u64,
+    step: u64,
+}
+
+impl MetricsRecorder<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>, every: u64, metrics: Metrics) -> io::Result<Self> {
+        let path = path.as_ref();
+        let writer = BufWriter::new(File::create(path)?);
+        MetricsRecorder::new(writer, TrajectoryFormat::from_path(path), every, metrics)
+    }
+}
+
+impl<W: Write> MetricsRecorder<W> {
+    pub fn new(
+        mut writer: W,
+        format: TrajectoryFormat,
+        every: u64,
+        metrics: Metrics,
+    ) -> io::Result<Self> {
+        if format == TrajectoryFormat::Csv {
+            writeln!(
+                writer,
+                "step,boids,polarization,milling,angular_momentum,centroid_x,centroid_y,spread,\
+mean_nearest_distance,mean_neighbors,speed_mean,speed_std_dev,speed_min,speed_max"
+            )?;
+        }
+        Ok(MetricsRecorder {
+            writer,
+            format,
+            metrics,
+            every: every.max(1),
+            step: 0,
+        })
+    }
+
+    /// Call once per simulation step; measures and writes a row on every
+    /// `every`th call, and returns what it measured.
+    pub fn record(&mut self, simulation: &Simulation) -> io::Result<Option<FlockMetrics>> {
+        let step = self.step;
+        self.step += 1;
+        if !step.is_multiple_of(self.every) {
+            return Ok(None);
+        }
+        let m = self.metrics.measure(simulation);
+        match self.format {
+            TrajectoryFormat::Csv => writeln!(
+                self.writer,
+                "{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                step,
+                m.boids,
+                m.polarization,
+                m.milling,
+                m.angular_momentum,
+                m.centroid.x,
+                m.centroid.y,
+                m.spread,
+                m.mean_nearest_distance,
+                m.mean_neighbors,
+                m.speed_mean,
+                m.speed_std_dev,
+                m.speed_min,
+                m.speed_max
+            )?,
+            TrajectoryFormat::Ndjson => {
+                let row = Row {
+                    step,
+                    boids: m.boids,
+                    polarization: m.polarization,
+                    milling: m.milling,
+                    angular_momentum: m.angular_momentum,
+                    centroid: [m.centroid.x, m.centroid.y],
+                    spread: m.spread,
+                    mean_nearest_distance: m.mean_nearest_distance,
+                    mean_neighbors: m.mean_neighbors,
+                    neighbor_counts: &m.neighbor_counts.counts,
+                    speed_mean: m.speed_mean,
+                    speed_std_dev: m.speed_std_dev,
+                    speed_min: m.speed_min,
+                    speed_max: m.speed_max,
+                    speed_bin_width: m.speeds.bin_width,
+                    speed_counts: &m.speeds.counts,
+                };
+                serde_json::to_writer(&mut self.writer, &row)?;
+                writeln!(self.writer)?;
+            }
+        }
+        Ok(Some(m))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boid::BoundaryMode;
+    use crate::simulation::SimulationBuilder;
+    use glam::vec2;
+
+    fn flock(mode: BoundaryMode, boids: impl IntoIterator<Item = (Vec2, Vec2)>) -> Simulation {
+        let mut simulation = SimulationBuilder::new(Rectangle::new(-100.0, -100.0, 200.0, 200.0))
+            .boid_count(0)
+            .boundary_mode(mode)
+            .build();
+        for (position, velocity) in boids {
+            simulation.spawn(position, velocity);
+        }
+        simulation
+    }
+
+    fn measure(simulation: &Simulation) -> FlockMetrics {
+        Metrics::new(simulation.bounds(), 12.0).measure(simulation)
+    }
+
+    fn lattice(spacing: f32) -> impl Iterator<Item = Vec2> {
+        (0..5).flat_map(move |i| (0..5).map(move |j| vec2(i as f32, j as f32) * spacing))
+    }
+
+    #[test]
+    fn an_aligned_flock_is_fully_polarized() {
+        let simulation = flock(
+            BoundaryMode::Reflect,
+            lattice(10.0).map(|position| (position, vec2(2.0, 1.0))),
+        );
+        let metrics = measure(&simulation);
+        assert_eq!(metrics.boids, 25);
+        assert!((metrics.polarization - 1.0).abs() < 1e-5);
+        assert!(metrics.milling < 1e-5);
+        assert!((metrics.speed_mean - vec2(2.0, 1.0).length()).abs() < 1e-5);
+        assert!(metrics.speed_std_dev < 1e-3);
+    }
+
+    #[test]
+    fn a_rotating_ring_mills() {
+        let ring = (0..24).map(|i| {
+            let angle = i as f32 / 24.0 * std::f32::consts::TAU;
+            let radial = vec2(angle.cos(), angle.sin());
+            (radial * 50.0, vec2(-radial.y, radial.x) * 3.0)
+        });
+        let metrics = measure(&flock(BoundaryMode::Reflect, ring));
+        assert!((metrics.milling - 1.0).abs() < 1e-4);
+        assert!(metrics.polarization < 1e-4);
+        assert!(metrics.centroid.length() < 1e-3);
+        assert!((metrics.spread - 50.0).abs() < 1e-3);
+        assert!((metrics.angular_momentum - 150.0).abs() < 1e-2);
+    }
+
+    #[test]
+    fn a_lattice_has_its_spacing_as_nearest_distance() {
+        let simulation = flock(
+            BoundaryMode::Reflect,
+            lattice(10.0).map(|position| (position, vec2(1.0, 0.0))),
+        );
+        let metrics = measure(&simulation);
+        assert!((metrics.mean_nearest_distance - 10.0).abs() < 1e-4);
+        // Corners have 2 neighbours within 12, edges 3 and the inside 4.
+        let expected = (4 * 2 + 12 * 3 + 9 * 4) as f32 / 25.0;
+        assert!((metrics.mean_neighbors - expected).abs() < 1e-5);
+        assert_eq!(metrics.neighbor_counts.counts[..5], [0, 0, 4, 12, 9]);
+    }
+
+    #[test]
+    fn the_torus_centroid_straddles_the_seam() {
+        let boids = [
+            vec2(95.0, -97.0),
+            vec2(-95.0, 97.0),
+            vec2(99.0, 99.0),
+            vec2(-99.0, -99.0),
+        ];
+        let simulation = flock(
+            BoundaryMode::Wrap,
+            boids.iter().map(|&position| (position, vec2(1.0, 0.0))),
+        );
+        let metrics = measure(&simulation);
+        let centroid = metrics.centroid;
+        let distance = |a: f32| {
+            (a - 100.0)
+                .rem_euclid(200.0)
+                .min((100.0 - a).rem_euclid(200.0))
+        };
+        assert!(
+            distance(centroid.x) < 1e-3 && distance(centroid.y) < 1e-3,
+            "{centroid:?}"
+        );
+        assert!(metrics.spread < 10.0);
+
+        let simulation = flock(
+            BoundaryMode::Reflect,
+            boids.iter().map(|&position| (position, vec2(1.0, 0.0))),
+        );
+        assert!(measure(&simulation).centroid.length() < 1e-3);
+    }
+}
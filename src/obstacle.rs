@@ -0,0 +1,495 @@
+use crate::behavior::STEERING_RATE;
+use crate::boid::Boid;
+use crate::spatial_hash::{Rectangle, SpatialHashGrid};
+use glam::Vec2;
+
+/// How far ahead boids look for obstacles, in seconds of travel.
+pub const OBSTACLE_LOOKAHEAD: f32 = 0.5;
+/// Distance from an obstacle's surface at which avoidance starts.
+pub const OBSTACLE_MARGIN: f32 = 10.0;
+pub const OBSTACLE_STRENGTH: f32 = 1.0;
+pub const OBSTACLE_CELL_SIZE: f32 = 64.0;
+
+/// Outline of an obstacle in its own frame, centred on its position.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Shape {
+    Circle {
+        radius: f32,
+    },
+    /// A rectangle; rotate the obstacle to tilt it.
+    Rect {
+        half_extents: Vec2,
+    },
+    /// Connected line segments through `points`. A closed polyline is a
+    /// polygon and has an inside; an open one is a thin wall.
+    Polyline {
+        points: Vec<Vec2>,
+        closed: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Obstacle {
+    pub position: Vec2,
+    /// Counter-clockwise rotation in radians. Circles ignore it.
+    pub rotation: f32,
+    pub shape: Shape,
+}
+
+impl Obstacle {
+    pub fn circle(position: Vec2, radius: f32) -> Self {
+        Obstacle {
+            position,
+            rotation: 0.0,
+            shape: Shape::Circle { radius },
+        }
+    }
+
+    pub fn rect(position: Vec2, size: Vec2, rotation: f32) -> Self {
+        Obstacle {
+            position,
+            rotation,
+            shape: Shape::Rect {
+                half_extents: size / 2.0,
+            },
+        }
+    }
+
+    /// A polygon (`closed`) or open polyline through `points`, which are
+    /// relative to `position`.
+    pub fn polyline(position: Vec2, points: Vec<Vec2>, closed: bool) -> Self {
+        Obstacle {
+            position,
+            rotation: 0.0,
+            shape: Shape::Polyline { points, closed },
+        }
+    }
+
+    /// Signed distance from `point` to the surface, negative inside, together
+    /// with the outward surface normal at the closest point.
+    pub fn signed_distance(&self, point: Vec2) -> (f32, Vec2) {
+        let local = rotate(point - self.position, -self.rotation);
+        let (distance, normal) = match &self.shape {
+            Shape::Circle { radius } => {
+                let length = local.length();
+                let normal = if length > 0.0 {
+                    local / length
+                } else {
+                    Vec2::new(1.0, 0.0)
+                };
+                (length - radius, normal)
+            }
+            Shape::Rect { half_extents } => {
+                let q = local.abs() - *half_extents;
+                let outside = q.max(Vec2::new(0.0, 0.0));
+                let sign = Vec2::new(signum(local.x), signum(local.y));
+                if outside.x > 0.0 || outside.y > 0.0 {
+                    (outside.length(), (outside * sign).normalize())
+                } else if q.x > q.y {
+                    (q.x, Vec2::new(sign.x, 0.0))
+                } else {
+                    (q.y, Vec2::new(0.0, sign.y))
+                }
+            }
+            Shape::Polyline { points, closed } => polyline_distance(points, *closed, local),
+        };
+        (distance, rotate(normal, self.rotation))
+    }
+
+    /// Axis-aligned box around the obstacle.
+    pub fn bounding_rect(&self) -> Rectangle {
+        let (min, max) = match &self.shape {
+            Shape::Circle { radius } => (-Vec2::new(*radius, *radius), Vec2::new(*radius, *radius)),
+            Shape::Rect { half_extents } => {
+                let (sin, cos) = self.rotation.sin_cos();
+                let extent = Vec2::new(
+                    cos.abs() * half_extents.x + sin.abs() * half_extents.y,
+                    sin.abs() * half_extents.x + cos.abs() * half_extents.y,
+                );
+                (-extent, extent)
+            }
+            Shape::Polyline { points, .. } => points.iter().fold(
+                (Vec2::splat(f32::INFINITY), Vec2::splat(f32::NEG_INFINITY)),
+                |(min, max), &point| {
+                    let point = rotate(point, self.rotation);
+                    (min.min(point), max.max(point))
+                },
+            ),
+        };
+        let size = max - min;
+        let min = self.position + min;
+        Rectangle::new(min.x, min.y, size.x, size.y)
+    }
+}
+
+/// Whether `rect` lies entirely inside `bounds`.
+fn within(rect: Rectangle, bounds: Rectangle) -> bool {
+    rect.x >= bounds.x
+        && rect.y >= bounds.y
+        && rect.x + rect.width <= bounds.x + bounds.width
+        && rect.y + rect.height <= bounds.y + bounds.height
+}
+
+fn signum(value: f32) -> f32 {
+    if value < 0.0 {
+        -1.0
+    } else {
+        1.0
+    }
+}
+
+fn rotate(v: Vec2, angle: f32) -> Vec2 {
+    let (sin, cos) = angle.sin_cos();
+    Vec2::new(v.x * cos - v.y * sin, v.x * sin + v.y * cos)
+}
+
+fn polyline_distance(points: &[Vec2], closed: bool, point: Vec2) -> (f32, Vec2) {
+    let segments = match points.len() {
+        0 => return (f32::INFINITY, Vec2::new(1.0, 0.0)),
+        1 => 1,
+        n if closed => n,
+        n => n - 1,
+    };
+    let mut best = (f32::INFINITY, Vec2::new(1.0, 0.0));
+    let mut inside = false;
+    for i in 0..segments {
+        let a = points[i];
+        let b = points[(i + 1) % points.len()];
+        let ab = b - a;
+        let t = if ab.length_squared() > 0.0 {
+            ((point - a).dot(ab) / ab.length_squared()).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        let away = point - (a + ab * t);
+        let distance = away.length();
+        if distance < best.0 {
+            let normal = if distance > 0.0 {
+                away / distance
+            } else {
+                Vec2::new(-ab.y, ab.x).normalize_or_zero()
+            };
+            best = (distance, normal);
+        }
+        // Crossing-number test for the inside of a polygon.
+        if (a.y > point.y) != (b.y > point.y) && point.x < a.x + (point.y - a.y) / ab.y * ab.x {
+            inside = !inside;
+        }
+    }
+    if closed && inside {
+        (-best.0, -best.1)
+    } else {
+        best
+    }
+}
+
+/// Handle to an obstacle in an `Obstacles` set. Handles are never reused.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ObstacleId(u32);
+
+impl ObstacleId {
+    pub fn slot(&self) -> u32 {
+        self.0
+    }
+}
+
+/// The obstacles in a simulation, indexed by their bounding boxes in a grid of
+/// their own, plus the parameters of the look-ahead avoidance steering.
+pub struct Obstacles {
+    slots: Vec<Option<Obstacle>>,
+    bounds: Rectangle,
+    /// Covers `bounds` only; the grid drops whatever lies outside them.
+    index: SpatialHashGrid,
+    /// Obstacles reaching outside `bounds`, which boids in unbounded or
+    /// soft-walled worlds can still run into there. Checked one by one.
+    outside: Vec<usize>,
+    /// Set whenever an obstacle is added, moved or removed; the index is
+    /// rebuilt at the start of the next step.
+    dirty: bool,
+    /// Seconds of travel boids look ahead.
+    pub lookahead: f32,
+    /// Distance from the surface at which avoidance starts.
+    pub margin: f32,
+    /// Scales the avoidance force.
+    pub strength: f32,
+}
+
+impl Obstacles {
+    pub fn new(bounds: Rectangle) -> Self {
+        Obstacles {
+            slots: Vec::new(),
+            bounds,
+            index: SpatialHashGrid::new(bounds, OBSTACLE_CELL_SIZE),
+            outside: Vec::new(),
+            dirty: false,
+            lookahead: OBSTACLE_LOOKAHEAD,
+            margin: OBSTACLE_MARGIN,
+            strength: OBSTACLE_STRENGTH,
+        }
+    }
+
+    /// Number of live obstacles.
+    pub fn len(&self) -> usize {
+        self.slots.iter().filter(|slot| slot.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.iter().all(Option::is_none)
+    }
+
+    pub fn add(&mut self, obstacle: Obstacle) -> ObstacleId {
+        self.slots.push(Some(obstacle));
+        self.dirty = true;
+        ObstacleId(self.slots.len() as u32 - 1)
+    }
+
+    pub fn remove(&mut self, id: ObstacleId) -> Option<Obstacle> {
+        let removed = self.slots.get_mut(id.0 as usize)?.take();
+        self.dirty |= removed.is_some();
+        removed
+    }
+
+    pub fn get(&self, id: ObstacleId) -> Option<&Obstacle> {
+        self.slots.get(id.0 as usize)?.as_ref()
+    }
+
+    /// Mutable access for moving or reshaping an obstacle at runtime.
+    pub fn get_mut(&mut self, id: ObstacleId) -> Option<&mut Obstacle> {
+        let obstacle = self.slots.get_mut(id.0 as usize)?.as_mut();
+        self.dirty |= obstacle.is_some();
+        obstacle
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ObstacleId, &Obstacle)> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, obstacle)| Some((ObstacleId(slot as u32), obstacle.as_ref()?)))
+    }
+
+    /// Every slot, including removed ones, for snapshots.
+    pub(crate) fn slots(&self) -> &[Option<Obstacle>] {
+        &self.slots
+    }
+
+    pub(crate) fn from_slots(bounds: Rectangle, slots: Vec<Option<Obstacle>>) -> Self {
+        let mut obstacles = Obstacles::new(bounds);
+        obstacles.slots = slots;
+        obstacles.dirty = true;
+        obstacles
+    }
+
+    pub(crate) fn refresh_index(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        self.index.clear();
+        self.outside.clear();
+        for (slot, obstacle) in self.slots.iter().enumerate() {
+            if let Some(obstacle) = obstacle {
+                let rect = obstacle.bounding_rect();
+                self.index.insert_rect(rect, slot);
+                if !within(rect, self.bounds) {
+                    self.outside.push(slot);
+                }
+            }
+        }
+        self.dirty = false;
+    }
+
+    /// Collects the distinct obstacles whose bounding boxes touch `rect`.
+    fn candidates(&self, rect: Rectangle, found: &mut Vec<usize>) {
+        found.clear();
+        self.index.query(rect, |slot| found.push(slot));
+        for &slot in &self.outside {
+            if let Some(obstacle) = &self.slots[slot] {
+                if obstacle.bounding_rect().intersects(&rect) {
+                    found.push(slot);
+                }
+            }
+        }
+        found.sort_unstable();
+        found.dedup();
+    }
+
+    /// Steers a boid around the obstacles in front of it. Probes along the
+    /// path the boid will cover in `lookahead` seconds and, for the most
+    /// threatening surface, turns the velocity along it and away from it.
+    pub(crate) fn avoidance(&self, boid: &Boid, found: &mut Vec<usize>) -> Vec2 {
+        if self.slots.is_empty() || self.margin <= 0.0 {
+            return Vec2::new(0.0, 0.0);
+        }
+        let ahead = boid.position + boid.velocity * self.lookahead;
+        let min = boid.position.min(ahead) - Vec2::splat(self.margin);
+        let max = boid.position.max(ahead) + Vec2::splat(self.margin);
+        self.candidates(
+            Rectangle::new(min.x, min.y, max.x - min.x, max.y - min.y),
+            found,
+        );
+
+        let mut threat: Option<(f32, Vec2)> = None;
+        for &slot in found.iter() {
+            let obstacle = match &self.slots[slot] {
+                Some(obstacle) => obstacle,
+                None => continue,
+            };
+            for probe in [0.0, 0.5, 1.0] {
+                let point = boid.position + boid.velocity * (self.lookahead * probe);
+                let (distance, normal) = obstacle.signed_distance(point);
+                // Nearer probes matter more than ones a full look-ahead away.
+                let weight = (1.0 - distance / self.margin).min(1.0) * (1.0 - 0.5 * probe);
+                if weight > 0.0 && threat.is_none_or(|(best, _)| weight > best) {
+                    threat = Some((weight, normal));
+                }
+            }
+        }
+        let (weight, normal) = match threat {
+            Some(threat) => threat,
+            None => return Vec2::new(0.0, 0.0),
+        };
+        let approach = boid.velocity.dot(normal).min(0.0);
+        let along = boid.velocity - normal * approach;
+        let desired =
+            (along.normalize_or_zero() + normal * weight).normalize_or_zero() * boid.max_speed;
+        (desired - boid.velocity) * weight * self.strength * STEERING_RATE
+    }
+
+    /// Moves a boid that ended up inside an obstacle back onto its surface
+    /// and drops the part of its velocity heading inwards.
+    pub(crate) fn resolve_penetration(&self, boid: &mut Boid, found: &mut Vec<usize>) {
+        if self.slots.is_empty() {
+            return;
+        }
+        self.candidates(boid.get_perception_rect(0.0), found);
+        for &slot in found.iter() {
+            if let Some(obstacle) = &self.slots[slot] {
+                let (distance, normal) = obstacle.signed_distance(boid.position);
+                if distance < 0.0 {
+                    boid.position -= normal * distance;
+                    boid.velocity -= normal * boid.velocity.dot(normal).min(0.0);
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "render")]
+    pub fn draw(&self, draw: &nannou::prelude::Draw) {
+        use nannou::prelude::{rgb, vec2};
+        let color = rgb(0.35, 0.35, 0.4);
+        for (_, obstacle) in self.iter() {
+            let (x, y) = (obstacle.position.x, obstacle.position.y);
+            match &obstacle.shape {
+                Shape::Circle { radius } => {
+                    draw.ellipse().x_y(x, y).radius(*radius).color(color);
+                }
+                Shape::Rect { half_extents } => {
+                    draw.rect()
+                        .x_y(x, y)
+                        .w_h(half_extents.x * 2.0, half_extents.y * 2.0)
+                        .z_radians(obstacle.rotation)
+                        .color(color);
+                }
+                Shape::Polyline { points, closed } => {
+                    let points = points.iter().map(|&p| {
+                        let p = obstacle.position + rotate(p, obstacle.rotation);
+                        vec2(p.x, p.y)
+                    });
+                    if *closed {
+                        draw.polygon().color(color).points(points);
+                    } else {
+                        draw.polyline().weight(2.0).color(color).points(points);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::vec2;
+    use std::f32::consts::FRAC_PI_2;
+
+    fn assert_distance(obstacle: &Obstacle, point: Vec2, distance: f32, normal: Vec2) {
+        let (d, n) = obstacle.signed_distance(point);
+        assert!(
+            (d - distance).abs() < 1e-4 && n.distance(normal) < 1e-4,
+            "{point:?}: got ({d}, {n:?}), expected ({distance}, {normal:?})"
+        );
+    }
+
+    #[test]
+    fn circle_distances() {
+        let circle = Obstacle::circle(vec2(10.0, 10.0), 5.0);
+        assert_distance(&circle, vec2(10.0, 18.0), 3.0, vec2(0.0, 1.0));
+        assert_distance(&circle, vec2(12.0, 10.0), -3.0, vec2(1.0, 0.0));
+        assert_distance(&circle, vec2(10.0, 10.0), -5.0, vec2(1.0, 0.0));
+    }
+
+    #[test]
+    fn rect_distances() {
+        let rect = Obstacle::rect(vec2(0.0, 0.0), vec2(40.0, 20.0), 0.0);
+        assert_distance(&rect, vec2(25.0, 0.0), 5.0, vec2(1.0, 0.0));
+        assert_distance(&rect, vec2(0.0, 8.0), -2.0, vec2(0.0, 1.0));
+        assert_distance(&rect, vec2(-19.0, -1.0), -1.0, vec2(-1.0, 0.0));
+        assert_distance(
+            &rect,
+            vec2(25.0, 15.0),
+            50f32.sqrt(),
+            vec2(1.0, 1.0).normalize(),
+        );
+
+        // A quarter turn stands it on end.
+        let rect = Obstacle::rect(vec2(100.0, 0.0), vec2(40.0, 20.0), FRAC_PI_2);
+        assert_distance(&rect, vec2(115.0, 0.0), 5.0, vec2(1.0, 0.0));
+        assert_distance(&rect, vec2(100.0, 18.0), -2.0, vec2(0.0, 1.0));
+        assert_distance(&rect, vec2(100.0, -23.0), 3.0, vec2(0.0, -1.0));
+    }
+
+    #[test]
+    fn polygon_distances() {
+        // A C opening to the right, so the notch is outside.
+        let points = vec![
+            vec2(0.0, 0.0),
+            vec2(40.0, 0.0),
+            vec2(40.0, 10.0),
+            vec2(10.0, 10.0),
+            vec2(10.0, 30.0),
+            vec2(40.0, 30.0),
+            vec2(40.0, 40.0),
+            vec2(0.0, 40.0),
+        ];
+        let polygon = Obstacle::polyline(vec2(0.0, 0.0), points, true);
+        assert_distance(&polygon, vec2(3.0, 20.0), -3.0, vec2(-1.0, 0.0));
+        assert_distance(&polygon, vec2(25.0, 3.0), -3.0, vec2(0.0, -1.0));
+        assert_distance(&polygon, vec2(25.0, 36.0), -4.0, vec2(0.0, 1.0));
+        assert_distance(&polygon, vec2(25.0, 20.0), 10.0, vec2(0.0, 1.0));
+        assert_distance(&polygon, vec2(14.0, 20.0), 4.0, vec2(1.0, 0.0));
+        assert_distance(&polygon, vec2(-6.0, 20.0), 6.0, vec2(-1.0, 0.0));
+    }
+
+    #[test]
+    fn open_polylines_have_no_inside() {
+        let wall = Obstacle::polyline(
+            vec2(0.0, 0.0),
+            vec![vec2(-20.0, 0.0), vec2(20.0, 0.0)],
+            false,
+        );
+        assert_distance(&wall, vec2(0.0, 5.0), 5.0, vec2(0.0, 1.0));
+        assert_distance(&wall, vec2(0.0, -5.0), 5.0, vec2(0.0, -1.0));
+        assert_distance(&wall, vec2(25.0, 0.0), 5.0, vec2(1.0, 0.0));
+
+        // The same points closed make a square with an inside.
+        let points = vec![
+            vec2(-10.0, -10.0),
+            vec2(10.0, -10.0),
+            vec2(10.0, 10.0),
+            vec2(-10.0, 10.0),
+        ];
+        let open = Obstacle::polyline(vec2(0.0, 0.0), points.clone(), false);
+        let closed = Obstacle::polyline(vec2(0.0, 0.0), points, true);
+        assert_distance(&open, vec2(-7.0, 2.0), 8.0, vec2(0.0, -1.0));
+        assert_distance(&closed, vec2(-7.0, 2.0), -3.0, vec2(-1.0, 0.0));
+    }
+}
@@ -0,0 +1,163 @@
+use crate::behavior::STEERING_RATE;
+use crate::boid::Boid;
+use crate::spatial_index::Topology;
+use glam::Vec2;
+
+pub const POINTER_RADIUS: f32 = 150.0;
+pub const POINTER_STRENGTH: f32 = 1.0;
+pub const POINTER_FALLOFF: f32 = 1.0;
+
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum PointerMode {
+    #[default]
+    Off,
+    /// Boids within the radius seek the pointer.
+    Attract,
+    /// Boids within the radius are pushed away from the pointer.
+    Repel,
+    /// Boids within the radius flee the pointer at full speed.
+    Predator,
+}
+
+/// A point of influence (usually the mouse) that steers nearby boids.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Pointer {
+    pub position: Vec2,
+    pub mode: PointerMode,
+    /// Boids further away than this are unaffected.
+    pub radius: f32,
+    pub strength: f32,
+    /// Exponent of the falloff curve: the weight is
+    /// `strength * (1 - distance / radius) ^ falloff`, so `0.0` is constant
+    /// across the radius and larger values concentrate the effect near the
+    /// pointer.
+    pub falloff: f32,
+}
+
+impl Default for Pointer {
+    fn default() -> Self {
+        Pointer {
+            position: Vec2::new(0.0, 0.0),
+            mode: PointerMode::Off,
+            radius: POINTER_RADIUS,
+            strength: POINTER_STRENGTH,
+            falloff: POINTER_FALLOFF,
+        }
+    }
+}
+
+impl Pointer {
+    /// Steering from the pointer for `boid`, reaching across the edges of a
+    /// torus like the flocking rules do.
+    pub fn force(&self, boid: &Boid, topology: Topology) -> Vec2 {
+        if self.mode == PointerMode::Off || self.radius <= 0.0 {
+            return Vec2::new(0.0, 0.0);
+        }
+        let to_pointer = topology.offset(boid.position, self.position);
+        let distance = to_pointer.length();
+        if distance >= self.radius || distance == 0.0 {
+            return Vec2::new(0.0, 0.0);
+        }
+        let weight = self.strength * (1.0 - distance / self.radius).powf(self.falloff);
+        let direction = to_pointer / distance;
+        match self.mode {
+            PointerMode::Off => Vec2::new(0.0, 0.0),
+            PointerMode::Attract => {
+                (direction * boid.max_speed - boid.velocity) * weight * STEERING_RATE
+            }
+            PointerMode::Repel => -direction * weight * boid.max_force,
+            PointerMode::Predator => {
+                (-direction * boid.max_speed - boid.velocity) * weight * STEERING_RATE
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SimulationConfig;
+    use crate::id::BoidId;
+    use crate::spatial_hash::Rectangle;
+
+    fn boid(position: Vec2) -> Boid {
+        let config = SimulationConfig::default();
+        Boid {
+            position,
+            velocity: Vec2::new(0.0, 0.0),
+            acceleration: Vec2::new(0.0, 0.0),
+            max_speed: config.max_velocity,
+            max_force: config.max_force,
+            radius: config.radius,
+            id: BoidId::from_raw(0, 0),
+            species: 0,
+        }
+    }
+
+    fn pointer(mode: PointerMode) -> Pointer {
+        Pointer {
+            mode,
+            radius: 100.0,
+            ..Pointer::default()
+        }
+    }
+
+    #[test]
+    fn modes_steer_towards_or_away_from_the_pointer() {
+        let near = boid(Vec2::new(50.0, 0.0));
+        let attract = pointer(PointerMode::Attract).force(&near, Topology::Plane);
+        assert!(attract.x < 0.0 && attract.y == 0.0);
+        for mode in [PointerMode::Repel, PointerMode::Predator] {
+            let away = pointer(mode).force(&near, Topology::Plane);
+            assert!(away.x > 0.0 && away.y == 0.0, "{mode:?}");
+        }
+        // Repel pushes with max_force scaled by the falloff weight.
+        let repel = pointer(PointerMode::Repel).force(&near, Topology::Plane);
+        assert!((repel.x - 0.5 * near.max_force).abs() < 1e-3);
+
+        assert_eq!(
+            pointer(PointerMode::Off).force(&near, Topology::Plane),
+            Vec2::new(0.0, 0.0)
+        );
+        let far = boid(Vec2::new(150.0, 0.0));
+        assert_eq!(
+            pointer(PointerMode::Attract).force(&far, Topology::Plane),
+            Vec2::new(0.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn falloff_shapes_the_weight() {
+        let repel = |falloff: f32, distance: f32| {
+            let pointer = Pointer {
+                falloff,
+                ..pointer(PointerMode::Repel)
+            };
+            pointer
+                .force(&boid(Vec2::new(distance, 0.0)), Topology::Plane)
+                .x
+        };
+        let max_force = SimulationConfig::default().max_force;
+        // No falloff is constant across the radius.
+        assert!((repel(0.0, 10.0) - max_force).abs() < 1e-3);
+        assert!((repel(0.0, 90.0) - max_force).abs() < 1e-3);
+        // A steeper falloff weakens the push further out.
+        assert!((repel(2.0, 50.0) - 0.25 * max_force).abs() < 1e-3);
+        assert!(repel(2.0, 50.0) < repel(1.0, 50.0));
+    }
+
+    #[test]
+    fn pointer_reaches_across_the_edges_of_a_torus() {
+        let boid = boid(Vec2::new(-95.0, 0.0));
+        let pointer = Pointer {
+            position: Vec2::new(95.0, 0.0),
+            mode: PointerMode::Attract,
+            radius: 20.0,
+            ..Pointer::default()
+        };
+        let torus = Topology::Torus(Rectangle::new(-100.0, -100.0, 200.0, 200.0));
+        let force = pointer.force(&boid, torus);
+        assert!(force.x < 0.0 && force.y == 0.0);
+        assert_eq!(pointer.force(&boid, Topology::Plane), Vec2::new(0.0, 0.0));
+    }
+}
@@ -0,0 +1,283 @@
+use crate::behavior::STEERING_RATE;
+use crate::boid::{Boid, Boids};
+use crate::id::BoidId;
+use crate::spatial_index::{SpatialIndex, Topology};
+use crate::species::{Role, SpeciesTable};
+use glam::Vec2;
+use std::collections::{HashMap, HashSet};
+
+pub const PREDATOR_FEAR_RADIUS: f32 = 50.0;
+pub const PREDATOR_HUNT_RADIUS: f32 = 100.0;
+pub const PREDATOR_CAPTURE_RADIUS: f32 = 3.0;
+
+/// Which prey a predator chases.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum PursuitTarget {
+    /// The closest prey within the hunt radius.
+    #[default]
+    Nearest,
+    /// The centre of the grid cell holding the most prey within the hunt
+    /// radius. Cells are as wide as the largest perception radius.
+    Densest,
+}
+
+/// How predator species hunt and how prey species react to them.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Predation {
+    /// Prey flee predators closer than this. Zero disables fleeing.
+    pub fear_radius: f32,
+    pub flee_strength: f32,
+    /// Predators only see prey within this distance.
+    pub hunt_radius: f32,
+    pub target: PursuitTarget,
+    /// Prey this close to a predator are caught and removed at the end of the
+    /// step. Zero disables capture.
+    pub capture_radius: f32,
+}
+
+impl Default for Predation {
+    fn default() -> Self {
+        Predation {
+            fear_radius: PREDATOR_FEAR_RADIUS,
+            flee_strength: 1.0,
+            hunt_radius: PREDATOR_HUNT_RADIUS,
+            target: PursuitTarget::Nearest,
+            capture_radius: 0.0,
+        }
+    }
+}
+
+/// A prey boid caught by a predator.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Capture {
+    pub predator: BoidId,
+    pub prey: BoidId,
+    /// Where the prey was when it was caught.
+    pub position: Vec2,
+}
+
+impl Predation {
+    /// Steers a prey boid away from the predators at `threats`, harder the
+    /// closer the nearest one is.
+    pub fn flee(&self, boid: &Boid, threats: &[Vec2]) -> Vec2 {
+        if self.fear_radius <= 0.0 {
+            return Vec2::new(0.0, 0.0);
+        }
+        let mut away = Vec2::new(0.0, 0.0);
+        let mut weight: f32 = 0.0;
+        for &threat in threats {
+            let offset = boid.position - threat;
+            let distance = offset.length();
+            if distance >= self.fear_radius || distance == 0.0 {
+                continue;
+            }
+            let closeness = 1.0 - distance / self.fear_radius;
+            away += offset / distance * closeness;
+            weight = weight.max(closeness);
+        }
+        if weight == 0.0 {
+            return Vec2::new(0.0, 0.0);
+        }
+        let desired = away.normalize_or_zero() * boid.max_speed;
+        (desired - boid.velocity) * weight * self.flee_strength * STEERING_RATE
+    }
+
+    /// Steers a predator at full speed towards where a target moving with
+    /// `target_velocity` will be by the time the predator gets there.
+    pub fn pursue(&self, boid: &Boid, target_position: Vec2, target_velocity: Vec2) -> Vec2 {
+        let lead = (target_position - boid.position).length() / boid.max_speed;
+        let predicted = target_position + target_velocity * lead;
+        let desired = (predicted - boid.position).normalize_or_zero() * boid.max_speed;
+        (desired - boid.velocity) * STEERING_RATE
+    }
+
+    /// Position and velocity of what the predator `i` should chase, if any
+    /// prey is within the hunt radius. On a torus the position is the copy of
+    /// the target nearest the predator, which may lie across an edge.
+    /// `cell_size` sizes the cells `PursuitTarget::Densest` counts prey in.
+    pub(crate) fn find_prey(
+        &self,
+        boids: &Boids,
+        index: &impl SpatialIndex,
+        topology: Topology,
+        species: &SpeciesTable,
+        i: usize,
+        cell_size: f32,
+    ) -> Option<(Vec2, Vec2)> {
+        let (positions, velocities, kinds) =
+            (boids.positions(), boids.velocities(), boids.species());
+        let position = positions[i];
+        let seen = |j: usize| position + topology.offset(position, positions[j]);
+        match self.target {
+            PursuitTarget::Nearest => {
+                let mut nearest: Option<(f32, usize)> = None;
+                topology.query_radius(
+                    index,
+                    position,
+                    self.hunt_radius,
+                    |j| positions[j],
+                    |j, distance_sq| {
+                        if species.role(kinds[j]) == Role::Prey
+                            && nearest.is_none_or(|(best, _)| distance_sq < best)
+                        {
+                            nearest = Some((distance_sq, j));
+                        }
+                    },
+                );
+                nearest.map(|(_, prey)| (seen(prey), velocities[prey]))
+            }
+            PursuitTarget::Densest => {
+                let mut cells: HashMap<(i32, i32), (usize, Vec2, Vec2)> = HashMap::new();
+                topology.query_radius(
+                    index,
+                    position,
+                    self.hunt_radius,
+                    |j| positions[j],
+                    |j, _| {
+                        if species.role(kinds[j]) != Role::Prey {
+                            return;
+                        }
+                        let other_position = seen(j);
+                        let cell = (
+                            (other_position.x / cell_size).floor() as i32,
+                            (other_position.y / cell_size).floor() as i32,
+                        );
+                        let zero = Vec2::new(0.0, 0.0);
+                        let (count, position, velocity) =
+                            cells.entry(cell).or_insert((0, zero, zero));
+                        *count += 1;
+                        *position += other_position;
+                        *velocity += velocities[j];
+                    },
+                );
+                // Ties go to the lowest row, then column, whatever order the
+                // index reported the prey in.
+                cells
+                    .into_iter()
+                    .max_by(|(a, x), (b, y)| {
+                        x.0.cmp(&y.0)
+                            .then_with(|| b.1.cmp(&a.1))
+                            .then_with(|| b.0.cmp(&a.0))
+                    })
+                    .map(|(_, (count, position, velocity))| {
+                        (position / count as f32, velocity / count as f32)
+                    })
+            }
+        }
+    }
+
+    /// Prey caught by the predators at `predators` (indices into `boids`).
+    /// Each prey is caught at most once, by the first predator to reach it.
+    pub(crate) fn captures(
+        &self,
+        boids: &Boids,
+        index: &impl SpatialIndex,
+        topology: Topology,
+        species: &SpeciesTable,
+        predators: &[usize],
+    ) -> Vec<Capture> {
+        let mut captures = Vec::new();
+        if self.capture_radius <= 0.0 {
+            return captures;
+        }
+        let (positions, ids, kinds) = (boids.positions(), boids.ids(), boids.species());
+        let mut caught = HashSet::new();
+        for &i in predators {
+            topology.query_radius(
+                index,
+                positions[i],
+                self.capture_radius,
+                |j| positions[j],
+                |j, _| {
+                    if species.role(kinds[j]) == Role::Prey && caught.insert(j) {
+                        captures.push(Capture {
+                            predator: ids[i],
+                            prey: ids[j],
+                            position: positions[j],
+                        });
+                    }
+                },
+            );
+        }
+        captures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SimulationConfig;
+    use crate::simulation::{Simulation, SimulationBuilder};
+    use crate::spatial_hash::Rectangle;
+    use glam::vec2;
+
+    /// A predator at the origin and one prey `distance` to its right, both
+    /// flying up.
+    fn chase(predation: Predation, distance: f32) -> (Simulation, BoidId, BoidId) {
+        let mut simulation = SimulationBuilder::new(Rectangle::new(-200.0, -200.0, 400.0, 400.0))
+            .boid_count(0)
+            .predation(predation)
+            .build();
+        let predators = simulation
+            .add_predator_species(SimulationConfig::default())
+            .unwrap();
+        let predator = simulation.spawn_species(predators, vec2(0.0, 0.0), vec2(0.0, 10.0));
+        let prey = simulation.spawn(vec2(distance, 0.0), vec2(0.0, 10.0));
+        (simulation, predator, prey)
+    }
+
+    #[test]
+    fn predators_steer_towards_lone_prey() {
+        for target in [PursuitTarget::Nearest, PursuitTarget::Densest] {
+            let predation = Predation {
+                target,
+                ..Predation::default()
+            };
+            let (mut simulation, predator, _) = chase(predation, 30.0);
+            simulation.navigate();
+            let velocity = simulation.get(predator).unwrap().velocity;
+            assert!(velocity.x > 0.0, "{target:?}: {velocity:?}");
+        }
+    }
+
+    #[test]
+    fn prey_steers_away_from_predators() {
+        let (mut simulation, _, prey) = chase(Predation::default(), 30.0);
+        simulation.navigate();
+        let velocity = simulation.get(prey).unwrap().velocity;
+        assert!(velocity.x > 0.0, "{velocity:?}");
+
+        let predation = Predation {
+            fear_radius: 0.0,
+            ..Predation::default()
+        };
+        let (mut simulation, _, prey) = chase(predation, 30.0);
+        simulation.navigate();
+        assert_eq!(simulation.get(prey).unwrap().velocity.x, 0.0);
+    }
+
+    #[test]
+    fn captured_prey_is_removed_and_reported_once() {
+        let predation = Predation {
+            capture_radius: PREDATOR_CAPTURE_RADIUS,
+            ..Predation::default()
+        };
+        let (mut simulation, predator, prey) = chase(predation, 1.0);
+        simulation.navigate();
+        let captures = simulation.take_captures();
+        assert_eq!(captures.len(), 1);
+        assert_eq!((captures[0].predator, captures[0].prey), (predator, prey));
+        assert!(simulation.get(prey).is_none());
+        assert!(simulation.get(predator).is_some());
+        assert_eq!(simulation.boid_count(), 1);
+        assert!(simulation.take_captures().is_empty());
+        simulation.navigate();
+        assert!(simulation.take_captures().is_empty());
+
+        // Out of reach, nothing is caught.
+        let (mut simulation, _, prey) = chase(predation, 30.0);
+        simulation.navigate();
+        assert!(simulation.take_captures().is_empty());
+        assert!(simulation.get(prey).is_some());
+    }
+}
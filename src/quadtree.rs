@@ -1,32 +1,63 @@
-use nannou::{draw::mesh::vertex::Color, prelude::Vec2};
 use crate::spatial_hash::Rectangle;
+use crate::spatial_index::{push_nearest, SpatialIndex};
+use glam::Vec2;
 
 const MAX_CAPACITY_QUADTREE: usize = 4;
+/// Nodes this deep hold any number of objects instead of splitting, so that
+/// many objects at the same point can't split forever.
+const MAX_DEPTH_QUADTREE: usize = 16;
 
 pub trait HasLocation {
     fn get_location(&self) -> Vec2;
 }
 
-pub struct QuadTree<'a, T>
+impl<T: HasLocation> HasLocation for &T {
+    fn get_location(&self) -> Vec2 {
+        (**self).get_location()
+    }
+}
+
+/// An index filed at a position, for using a `QuadTree` as a `SpatialIndex`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Indexed {
+    pub position: Vec2,
+    pub index: usize,
+}
+
+impl HasLocation for Indexed {
+    fn get_location(&self) -> Vec2 {
+        self.position
+    }
+}
+
+/// Holds copies of its objects; store references (`QuadTree<&Boid>`) to index
+/// things that live elsewhere.
+pub struct QuadTree<T>
 where
-    T: HasLocation,
+    T: HasLocation + Copy,
 {
     boundary: Rectangle,
-    objects: Vec<&'a T>,
+    depth: usize,
+    objects: Vec<T>,
     is_divided: bool,
-    top_right: Option<Box<QuadTree<'a, T>>>,
-    top_left: Option<Box<QuadTree<'a, T>>>,
-    bottom_right: Option<Box<QuadTree<'a, T>>>,
-    bottom_left: Option<Box<QuadTree<'a, T>>>,
+    top_right: Option<Box<QuadTree<T>>>,
+    top_left: Option<Box<QuadTree<T>>>,
+    bottom_right: Option<Box<QuadTree<T>>>,
+    bottom_left: Option<Box<QuadTree<T>>>,
 }
 
-impl<'a, T> QuadTree<'a, T>
+impl<T> QuadTree<T>
 where
-    T: HasLocation,
+    T: HasLocation + Copy,
 {
     pub fn new(boundary: Rectangle) -> Self {
+        Self::with_depth(boundary, 0)
+    }
+
+    fn with_depth(boundary: Rectangle, depth: usize) -> Self {
         QuadTree {
             boundary,
+            depth,
             objects: Vec::new(),
             is_divided: false,
             top_left: None,
@@ -46,36 +77,126 @@ where
         let bl = Rectangle::new(x, y + h_h, h_w, h_h);
         let br = Rectangle::new(x + h_w, y + h_h, h_w, h_h);
 
-        self.top_left = Some(Box::new(QuadTree::new(tl)));
-        self.top_right = Some(Box::new(QuadTree::new(tr)));
-        self.bottom_left = Some(Box::new(QuadTree::new(bl)));
-        self.bottom_right = Some(Box::new(QuadTree::new(br)));
+        let depth = self.depth + 1;
+        self.top_left = Some(Box::new(QuadTree::with_depth(tl, depth)));
+        self.top_right = Some(Box::new(QuadTree::with_depth(tr, depth)));
+        self.bottom_left = Some(Box::new(QuadTree::with_depth(bl, depth)));
+        self.bottom_right = Some(Box::new(QuadTree::with_depth(br, depth)));
+    }
+
+    fn children(&self) -> [&QuadTree<T>; 4] {
+        [
+            self.top_left.as_ref().unwrap(),
+            self.top_right.as_ref().unwrap(),
+            self.bottom_left.as_ref().unwrap(),
+            self.bottom_right.as_ref().unwrap(),
+        ]
     }
 
-    pub fn query(&self, rect: Rectangle) -> Vec<&'a T> {
-        let mut found: Vec<&T> = Vec::new();
-        found = self.query_tree(rect, found);
+    /// Empties the tree, keeping its boundary.
+    pub fn clear(&mut self) {
+        *self = QuadTree::new(self.boundary);
+    }
+
+    pub fn query(&self, rect: Rectangle) -> Vec<T> {
+        let mut found = Vec::new();
+        self.query_tree(rect, &mut |object| found.push(object));
         found
     }
 
-    fn query_tree(&self, rect: Rectangle, mut found: Vec<&'a T>) -> Vec<&'a T> {
+    fn query_tree(&self, rect: Rectangle, callback: &mut impl FnMut(T)) {
         if self.boundary.intersects(&rect) {
-            self.objects.iter().for_each(|object| {
+            self.objects.iter().for_each(|&object| {
                 if rect.point_inside_rect(object.get_location()) {
-                    found.push(&object);
+                    callback(object);
                 }
             });
             if self.is_divided {
-                found = self.top_left.as_ref().unwrap().query_tree(rect, found);
-                found = self.top_right.as_ref().unwrap().query_tree(rect, found);
-                found = self.bottom_left.as_ref().unwrap().query_tree(rect, found);
-                found = self.bottom_right.as_ref().unwrap().query_tree(rect, found);
+                for child in self.children() {
+                    child.query_tree(rect, callback);
+                }
             }
         }
+    }
+
+    /// Objects within `radius` of `center`, each with its squared distance.
+    /// Nodes the circle doesn't reach are skipped.
+    pub fn query_radius(&self, center: Vec2, radius: f32) -> Vec<(T, f32)> {
+        let mut found = Vec::new();
+        self.query_radius_tree(center, radius * radius, &mut |object, distance_squared| {
+            found.push((object, distance_squared))
+        });
         found
     }
 
+    fn query_radius_tree(&self, center: Vec2, radius_sq: f32, callback: &mut impl FnMut(T, f32)) {
+        if self.boundary.distance_squared_to(center) > radius_sq {
+            return;
+        }
+        for &object in &self.objects {
+            let distance_squared = (object.get_location() - center).length_squared();
+            if distance_squared <= radius_sq {
+                callback(object, distance_squared);
+            }
+        }
+        if self.is_divided {
+            for child in self.children() {
+                child.query_radius_tree(center, radius_sq, callback);
+            }
+        }
+    }
+
+    /// Refills `found` with the `k` objects nearest to `center` that `accept`
+    /// lets through, as `(distance_squared, object)` pairs, nearest first.
+    /// Children are searched nearest first and skipped once they can't hold
+    /// anything nearer than the current k-th.
+    pub fn query_nearest<A>(&self, center: Vec2, k: usize, mut accept: A, found: &mut Vec<(f32, T)>)
+    where
+        A: FnMut(&T) -> bool,
+    {
+        found.clear();
+        if k > 0 {
+            let mut keep = |object: &T| accept(object).then_some(*object);
+            self.query_nearest_tree(center, k, &mut keep, found);
+        }
+    }
+
+    /// `keep` maps each object to what is stored in `found`, or `None` to
+    /// skip it.
+    fn query_nearest_tree<K, U>(
+        &self,
+        center: Vec2,
+        k: usize,
+        keep: &mut K,
+        found: &mut Vec<(f32, U)>,
+    ) where
+        K: FnMut(&T) -> Option<U>,
+    {
+        for object in &self.objects {
+            let value = match keep(object) {
+                Some(value) => value,
+                None => continue,
+            };
+            let distance_squared = (object.get_location() - center).length_squared();
+            push_nearest(found, k, distance_squared, value);
+        }
+        if self.is_divided {
+            let mut children = self
+                .children()
+                .map(|child| (child.boundary.distance_squared_to(center), child));
+            children.sort_by(|a, b| a.0.total_cmp(&b.0));
+            for (distance_squared, child) in children {
+                if found.len() == k && distance_squared >= found[k - 1].0 {
+                    break;
+                }
+                child.query_nearest_tree(center, k, keep, found);
+            }
+        }
+    }
+
+    #[cfg(feature = "render")]
     pub fn draw(&self, draw: &nannou::prelude::Draw) {
+        use nannou::draw::mesh::vertex::Color;
         let w = self.boundary.width;
         let h = self.boundary.height;
         draw.rect()
@@ -86,19 +207,20 @@ where
             .stroke_weight(1.0)
             .stroke_color(Color::new(1.0, 1.0, 1.0, 0.1));
         if self.is_divided {
-            self.top_left.as_ref().unwrap().draw(draw);
-            self.top_right.as_ref().unwrap().draw(draw);
-            self.bottom_left.as_ref().unwrap().draw(draw);
-            self.bottom_right.as_ref().unwrap().draw(draw);
+            for child in self.children() {
+                child.draw(draw);
+            }
         }
     }
 
-    pub fn insert(&mut self, object: &'a T) {
+    pub fn insert(&mut self, object: T) {
         if !self.boundary.point_inside_rect(object.get_location()) {
             return;
         }
 
-        if !self.is_divided && self.objects.len() < MAX_CAPACITY_QUADTREE {
+        if !self.is_divided
+            && (self.objects.len() < MAX_CAPACITY_QUADTREE || self.depth >= MAX_DEPTH_QUADTREE)
+        {
             self.objects.push(object);
             return;
         }
@@ -115,16 +237,152 @@ where
         self.insert_into_children(object);
     }
 
-    fn insert_into_children(&mut self, object: &'a T) {
+    fn insert_into_children(&mut self, object: T) {
         let loc = object.get_location();
-        if self.top_left.as_ref().unwrap().boundary.point_inside_rect(loc) {
+        if self
+            .top_left
+            .as_ref()
+            .unwrap()
+            .boundary
+            .point_inside_rect(loc)
+        {
             self.top_left.as_mut().unwrap().insert(object);
-        } else if self.top_right.as_ref().unwrap().boundary.point_inside_rect(loc) {
+        } else if self
+            .top_right
+            .as_ref()
+            .unwrap()
+            .boundary
+            .point_inside_rect(loc)
+        {
             self.top_right.as_mut().unwrap().insert(object);
-        } else if self.bottom_left.as_ref().unwrap().boundary.point_inside_rect(loc) {
+        } else if self
+            .bottom_left
+            .as_ref()
+            .unwrap()
+            .boundary
+            .point_inside_rect(loc)
+        {
             self.bottom_left.as_mut().unwrap().insert(object);
-        } else if self.bottom_right.as_ref().unwrap().boundary.point_inside_rect(loc) {
+        } else if self
+            .bottom_right
+            .as_ref()
+            .unwrap()
+            .boundary
+            .point_inside_rect(loc)
+        {
             self.bottom_right.as_mut().unwrap().insert(object);
         }
     }
 }
+
+impl SpatialIndex for QuadTree<Indexed> {
+    fn clear(&mut self) {
+        QuadTree::clear(self);
+    }
+
+    fn insert(&mut self, position: Vec2, index: usize) {
+        QuadTree::insert(self, Indexed { position, index });
+    }
+
+    fn query<F>(&self, rect: Rectangle, mut callback: F)
+    where
+        F: FnMut(usize),
+    {
+        self.query_tree(rect, &mut |object: Indexed| callback(object.index));
+    }
+
+    fn query_radius<P, F>(&self, center: Vec2, radius: f32, _position: P, mut callback: F)
+    where
+        P: Fn(usize) -> Vec2,
+        F: FnMut(usize, f32),
+    {
+        self.query_radius_tree(
+            center,
+            radius * radius,
+            &mut |object: Indexed, distance_squared| callback(object.index, distance_squared),
+        );
+    }
+
+    fn query_nearest<P, A>(
+        &self,
+        center: Vec2,
+        k: usize,
+        _position: P,
+        mut accept: A,
+        found: &mut Vec<(f32, usize)>,
+    ) where
+        P: Fn(usize) -> Vec2,
+        A: FnMut(usize) -> bool,
+    {
+        found.clear();
+        if k > 0 {
+            let mut keep = |object: &Indexed| accept(object.index).then_some(object.index);
+            self.query_nearest_tree(center, k, &mut keep, found);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    fn brute_force(points: &[Vec2], center: Vec2, radius: f32) -> Vec<usize> {
+        (0..points.len())
+            .filter(|&i| (points[i] - center).length_squared() <= radius * radius)
+            .collect()
+    }
+
+    #[test]
+    fn query_radius_matches_brute_force() {
+        let boundary = Rectangle::new(-64.0, -64.0, 128.0, 128.0);
+        let mut rng = ChaCha8Rng::seed_from_u64(5);
+        let mut points: Vec<Vec2> = (0..500)
+            .map(|_| Vec2::new(rng.gen_range(-64.0..64.0), rng.gen_range(-64.0..64.0)))
+            .collect();
+        // Points on the lines the first splits fall on, and a pile at one
+        // point deep enough to hit the depth limit.
+        for t in [-48.0, -32.0, -16.0, 0.0, 16.0, 32.0] {
+            points.push(Vec2::new(t, 0.0));
+            points.push(Vec2::new(0.0, t));
+            points.push(Vec2::new(t, t));
+        }
+        points.extend([Vec2::new(7.0, -9.0); 40]);
+
+        let mut tree = QuadTree::new(boundary);
+        for (index, &position) in points.iter().enumerate() {
+            tree.insert(Indexed { position, index });
+        }
+        assert!(tree.is_divided);
+
+        let mut centers: Vec<Vec2> = (0..200)
+            .map(|_| Vec2::new(rng.gen_range(-70.0..70.0), rng.gen_range(-70.0..70.0)))
+            .collect();
+        // Circles centred on and straddling node corners and edges.
+        centers.extend([
+            Vec2::new(0.0, 0.0),
+            Vec2::new(32.0, 32.0),
+            Vec2::new(-32.0, 0.5),
+            Vec2::new(16.0, -16.0),
+            Vec2::new(7.0, -9.0),
+            Vec2::new(-64.0, 64.0),
+        ]);
+        for center in centers {
+            for radius in [0.0, 1.0, 5.0, 17.0, 40.0, 200.0] {
+                let found = tree.query_radius(center, radius);
+                for &(object, distance_squared) in &found {
+                    let expected = (object.position - center).length_squared();
+                    assert_eq!(distance_squared, expected);
+                }
+                let mut found: Vec<usize> = found.iter().map(|(object, _)| object.index).collect();
+                found.sort_unstable();
+                assert_eq!(
+                    found,
+                    brute_force(&points, center, radius),
+                    "{center:?} {radius}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use crate::config::SimulationConfig;
+use crate::simulation::Simulation;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TrajectoryFormat {
+    Csv,
+    Ndjson,
+}
+
+impl TrajectoryFormat {
+    /// NDJSON for `.ndjson`/`.jsonl`/`.json` files, CSV for anything else.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("ndjson") | Some("jsonl") | Some("json") => TrajectoryFormat::Ndjson,
+            _ => TrajectoryFormat::Csv,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RunHeader<'a> {
+    seed: u64,
+    boids: usize,
+    bounds: [f32; 4],
+    boundary_mode: String,
+    neighbor_mode: String,
+    integrator: String,
+    time_step: f32,
+    index_kind: String,
+    predation: String,
+    every: u64,
+    species: &'a [SimulationConfig],
+}
+
+/// Streams every boid's id, species, position, velocity and acceleration to a
+/// sink every `every` steps. The first line describes the run as JSON: behind a
+/// `# run: ` comment for CSV, as a `{"run": ...}` object for NDJSON.
+pub struct TrajectoryRecorder<W: Write> {
+    writer: W,
+    format: TrajectoryFormat,
+    every: u64,
+    step: u64,
+}
+
+impl TrajectoryRecorder<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>, every: u64, simulation: &Simulation) -> io::Result<Self> {
+        let path = path.as_ref();
+        let writer = BufWriter::new(File::create(path)?);
+        TrajectoryRecorder::new(writer, TrajectoryFormat::from_path(path), every, simulation)
+    }
+}
+
+impl<W: Write> TrajectoryRecorder<W> {
+    pub fn new(
+        mut writer: W,
+        format: TrajectoryFormat,
+        every: u64,
+        simulation: &Simulation,
+    ) -> io::Result<Self> {
+        let bounds = simulation.bounds();
+        let header = serde_json::to_string(&RunHeader {
+            seed: simulation.seed(),
+            boids: simulation.boid_count(),
+            bounds: [bounds.x, bounds.y, bounds.width, bounds.height],
+            boundary_mode: format!("{:?}", simulation.boundary_mode),
+            neighbor_mode: format!("{:?}", simulation.neighbor_mode),
+            integrator: format!("{:?}", simulation.integrator),
+            time_step: simulation.time_step(),
+            index_kind: format!("{:?}", simulation.index_kind()),
+            predation: format!("{:?}", simulation.predation),
+            every,
+            species: simulation.species().configs(),
+        })?;
+        match format {
+            TrajectoryFormat::Csv => {
+                writeln!(writer, "# run: {}", header)?;
+                writeln!(writer, "step,slot,generation,species,px,py,vx,vy,ax,ay")?;
+            }
+            TrajectoryFormat::Ndjson => writeln!(writer, "{{\"run\":{}}}", header)?,
+        }
+        Ok(TrajectoryRecorder {
+            writer,
+            format,
+            every: every.max(1),
+            step: 0,
+        })
+    }
+
+    /// Call once per simulation step; writes a frame on every `every`th call.
+    pub fn record(&mut self, simulation: &Simulation) -> io::Result<()> {
+        let step = self.step;
+        self.step += 1;
+        if !step.is_multiple_of(self.every) {
+            return Ok(());
+        }
+        for boid in simulation.iter() {
+            let (p, v, a) = (boid.position, boid.velocity, boid.acceleration);
+            match self.format {
+                TrajectoryFormat::Csv => writeln!(
+                    self.writer,
+                    "{},{},{},{},{},{},{},{},{},{}",
+                    step,
+                    boid.id.slot(),
+                    boid.id.generation(),
+                    boid.species,
+                    p.x,
+                    p.y,
+                    v.x,
+                    v.y,
+                    a.x,
+                    a.y
+                )?,
+                TrajectoryFormat::Ndjson => writeln!(
+                    self.writer,
+                    "{{\"step\":{},\"id\":[{},{}],\"species\":{},\"position\":[{},{}],\"velocity\":[{},{}],\"acceleration\":[{},{}]}}",
+                    step,
+                    boid.id.slot(),
+                    boid.id.generation(),
+                    boid.species,
+                    p.x,
+                    p.y,
+                    v.x,
+                    v.y,
+                    a.x,
+                    a.y
+                )?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::SimulationBuilder;
+    use crate::spatial_hash::Rectangle;
+    use serde_json::Value;
+
+    fn record(format: TrajectoryFormat) -> (Simulation, String) {
+        let mut simulation = SimulationBuilder::new(Rectangle::new(0.0, 0.0, 100.0, 100.0))
+            .boid_count(5)
+            .seed(11)
+            .build();
+        let mut recorder = TrajectoryRecorder::new(Vec::new(), format, 1, &simulation).unwrap();
+        for _ in 0..2 {
+            simulation.navigate();
+            recorder.record(&simulation).unwrap();
+        }
+        let text = String::from_utf8(recorder.into_inner()).unwrap();
+        (simulation, text)
+    }
+
+    fn check_header(header: &Value, simulation: &Simulation) {
+        assert_eq!(header["seed"], 11);
+        assert_eq!(header["boids"], 5);
+        assert_eq!(header["boundary_mode"], "Wrap");
+        assert_eq!(header["neighbor_mode"], "Metric");
+        assert_eq!(header["integrator"], "SemiImplicitEuler");
+        assert_eq!(
+            header["time_step"].as_f64().unwrap() as f32,
+            simulation.time_step()
+        );
+        assert_eq!(header["index_kind"], "Grid");
+        assert!(header["predation"]
+            .as_str()
+            .unwrap()
+            .starts_with("Predation {"));
+        assert_eq!(header["every"], 1);
+        assert_eq!(header["species"].as_array().unwrap().len(), 1);
+    }
+
+    /// `(slot, generation)` of every boid, in the order `iter` yields them.
+    fn ids(simulation: &Simulation) -> Vec<(u64, u64)> {
+        simulation
+            .iter()
+            .map(|boid| (boid.id.slot() as u64, boid.id.generation() as u64))
+            .collect()
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let (simulation, text) = record(TrajectoryFormat::Csv);
+        let mut lines = text.lines();
+        let header = lines.next().unwrap().strip_prefix("# run: ").unwrap();
+        check_header(&serde_json::from_str(header).unwrap(), &simulation);
+        assert_eq!(
+            lines.next(),
+            Some("step,slot,generation,species,px,py,vx,vy,ax,ay")
+        );
+        let rows: Vec<Vec<&str>> = lines.map(|line| line.split(',').collect()).collect();
+        assert_eq!(rows.len(), 2 * 5);
+        for (step, frame) in rows.chunks(5).enumerate() {
+            let mut seen = Vec::new();
+            for row in frame {
+                assert_eq!(row.len(), 10);
+                assert_eq!(row[0], step.to_string());
+                seen.push((row[1].parse().unwrap(), row[2].parse().unwrap()));
+            }
+            assert_eq!(seen, ids(&simulation));
+        }
+        let last = rows.last().unwrap();
+        let boid = simulation.boid(4);
+        assert_eq!(last[4].parse::<f32>().unwrap(), boid.position.x);
+        assert_eq!(last[7].parse::<f32>().unwrap(), boid.velocity.y);
+    }
+
+    #[test]
+    fn ndjson_round_trip() {
+        let (simulation, text) = record(TrajectoryFormat::Ndjson);
+        let lines: Vec<Value> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        check_header(&lines[0]["run"], &simulation);
+        let rows = &lines[1..];
+        assert_eq!(rows.len(), 2 * 5);
+        for (step, frame) in rows.chunks(5).enumerate() {
+            let seen: Vec<(u64, u64)> = frame
+                .iter()
+                .map(|row| {
+                    assert_eq!(row["step"], step as u64);
+                    let id = &row["id"];
+                    (id[0].as_u64().unwrap(), id[1].as_u64().unwrap())
+                })
+                .collect();
+            assert_eq!(seen, ids(&simulation));
+        }
+        let boid = simulation.boid(4);
+        let last = rows.last().unwrap();
+        assert_eq!(
+            last["position"][0].as_f64().unwrap() as f32,
+            boid.position.x
+        );
+        assert_eq!(
+            last["velocity"][1].as_f64().unwrap() as f32,
+            boid.velocity.y
+        );
+    }
+}
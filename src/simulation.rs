@@ -1,146 +1,1312 @@
+use crate::behavior::*;
 use crate::boid::*;
+use crate::config::{ConfigError, SimulationConfig};
+use crate::hashed_grid::HashedGrid;
+use crate::id::{BoidId, BoidIds};
+use crate::integrator::{self, FixedTimestep, Integrator};
+use crate::obstacle::{Obstacle, Obstacles};
+use crate::pointer::Pointer;
+use crate::predation::{Capture, Predation, PREDATOR_FEAR_RADIUS};
 use crate::spatial_hash::*;
-use nannou::prelude::*;
-use nannou::rand;
+use crate::spatial_index::{AnyIndex, IndexKind, SpatialIndex};
+use crate::species::{Interaction, Role, SpeciesId, SpeciesTable};
+use glam::{vec2, Vec2};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use rayon::prelude::*;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-pub struct Simulation {
-    boid_count: i32,
-    mouse_position: Vec2,
-    pub boids: Vec<Boid>,
+mod kernel;
+mod resort;
+mod snapshot;
+
+/// Per-worker buffers reused from boid to boid during `navigate`.
+#[derive(Default)]
+struct Scratch {
+    neighbors: Vec<Neighbor>,
+    candidates: kernel::Candidates,
+    nearest: Vec<(f32, usize)>,
+    threats: Vec<Vec2>,
+    obstacles: Vec<usize>,
+}
+
+/// Wall-clock time one `navigate` step spent in each of its phases.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct StepTimings {
+    /// Re-sorting boids, on steps where `Simulation::resort` is due.
+    pub sort: Duration,
+    /// Rebuilding the boid index, the predator grid and the obstacle index.
+    pub index: Duration,
+    /// Neighbour queries and steering forces for every boid, and captures.
+    pub forces: Duration,
+    /// Moving the boids, boundaries, obstacle penetration and despawning
+    /// captured prey.
+    pub integration: Duration,
+}
+
+impl StepTimings {
+    pub fn total(&self) -> Duration {
+        self.sort + self.index + self.forces + self.integration
+    }
+}
+
+pub use resort::{Resort, SortOrder};
+pub use snapshot::{SnapshotError, SNAPSHOT_VERSION};
+
+/// Builds a `Simulation` whose initial state is driven entirely by one seeded
+/// RNG. Given the same seed and parameters, two simulations produce
+/// bit-identical `boids` after any number of `navigate` calls, whatever the
+/// thread count: forces are computed per boid and collected in index order.
+pub struct SimulationBuilder {
     bounds: Rectangle,
-    grid: SpatialHashGrid,
-    pub boid_cohesion_factor: f32,
-    pub boid_seperation_factor: f32,
-    pub boid_alignment_factor: f32,
+    boid_count: usize,
+    config: SimulationConfig,
+    extra_species: Vec<(SimulationConfig, Role, usize)>,
+    boundary_mode: BoundaryMode,
+    predation: Predation,
+    obstacles: Vec<Obstacle>,
+    neighbor_mode: NeighborMode,
+    index_kind: IndexKind,
+    resort: Option<Resort>,
+    integrator: Integrator,
+    time_step: FixedTimestep,
+    seed: Option<u64>,
+    threads: Option<usize>,
 }
 
-impl Simulation {
-    pub fn new(boid_count: i32, bounds: Rectangle) -> Self {
-        let half_width = bounds.width / 2.0;
-        let half_height = bounds.height / 2.0;
-
-        let mut boids = Vec::with_capacity(boid_count as usize);
-        for i in 0..boid_count {
-            let rx = rand::random_range::<f32>(-half_width, half_width);
-            let ry = rand::random_range::<f32>(-half_height, half_height);
-            let pt = Vec2::new(rx, ry);
-            let vx = rand::random_range::<f32>(-2.0, 2.0);
-            let vy = rand::random_range::<f32>(-2.0, 2.0);
-            let v = Vec2::new(vx, vy);
-            boids.push(Boid {
-                position: pt,
-                velocity: v,
-                radius: BOID_RADIUS,
-                acceleration: vec2(0.0, 0.0),
-                max_speed: BOID_MAX_VELOCITY,
-                max_force: BOID_MAX_FORCE,
-                index: i,
-            });
+impl SimulationBuilder {
+    pub fn new(bounds: Rectangle) -> Self {
+        SimulationBuilder {
+            bounds,
+            boid_count: 0,
+            config: SimulationConfig::default(),
+            extra_species: Vec::new(),
+            boundary_mode: BoundaryMode::default(),
+            predation: Predation::default(),
+            obstacles: Vec::new(),
+            neighbor_mode: NeighborMode::default(),
+            index_kind: IndexKind::default(),
+            resort: None,
+            integrator: Integrator::default(),
+            time_step: FixedTimestep::default(),
+            seed: None,
+            threads: None,
         }
+    }
+
+    pub fn boid_count(mut self, boid_count: usize) -> Self {
+        self.boid_count = boid_count;
+        self
+    }
+
+    /// Parameters of species 0, which `boid_count` boids belong to. `build`
+    /// panics if this or any other species' config fails
+    /// `SimulationConfig::validate`.
+    pub fn config(mut self, config: SimulationConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Adds another species with `boid_count` boids of its own. Species are
+    /// numbered from 1 in the order they are added. `config` must be valid,
+    /// as for `config`.
+    pub fn species(mut self, config: SimulationConfig, boid_count: usize) -> Self {
+        self.extra_species.push((config, Role::Prey, boid_count));
+        self
+    }
+
+    /// Adds a predator species with `boid_count` boids, numbered like
+    /// `species`, and with a config that must be valid likewise.
+    pub fn predators(mut self, config: SimulationConfig, boid_count: usize) -> Self {
+        self.extra_species
+            .push((config, Role::Predator, boid_count));
+        self
+    }
+
+    pub fn predation(mut self, predation: Predation) -> Self {
+        self.predation = predation;
+        self
+    }
+
+    pub fn obstacle(mut self, obstacle: Obstacle) -> Self {
+        self.obstacles.push(obstacle);
+        self
+    }
+
+    pub fn neighbor_mode(mut self, neighbor_mode: NeighborMode) -> Self {
+        self.neighbor_mode = neighbor_mode;
+        self
+    }
+
+    /// Ignored in favour of `IndexKind::Hashed` while the boundary mode lets
+    /// boids leave the bounds.
+    pub fn index_kind(mut self, index_kind: IndexKind) -> Self {
+        self.index_kind = index_kind;
+        self
+    }
+
+    /// Re-sorts boids by `order` every `every` steps, starting with the
+    /// first, to keep neighbours close in memory. Off by default.
+    pub fn resort(mut self, order: SortOrder, every: u32) -> Self {
+        self.resort = Some(Resort { order, every });
+        self
+    }
 
-        let grid = SpatialHashGrid::new(bounds, BOID_BOUNDS_SIZE);
+    pub fn boundary_mode(mut self, boundary_mode: BoundaryMode) -> Self {
+        self.boundary_mode = boundary_mode;
+        self
+    }
+
+    pub fn integrator(mut self, integrator: Integrator) -> Self {
+        self.integrator = integrator;
+        self
+    }
+
+    /// Length of one `navigate` step in seconds. Panics unless `seconds` is
+    /// positive and finite.
+    pub fn time_step(mut self, seconds: f32) -> Self {
+        assert_valid_step(seconds);
+        self.time_step = FixedTimestep::new(seconds);
+        self
+    }
+
+    /// Seed for every random draw the simulation makes. When unset a seed is
+    /// taken from the OS and can be read back with `Simulation::seed`.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Runs `navigate` on a dedicated rayon pool of this many threads instead
+    /// of the global pool.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    pub fn build(self) -> Simulation {
+        let configs = std::iter::once(&self.config)
+            .chain(self.extra_species.iter().map(|(config, _, _)| config));
+        for config in configs {
+            if let Err(err) = config.validate() {
+                panic!("invalid species config: {}", err);
+            }
+        }
+        let seed = self.seed.unwrap_or_else(rand::random);
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let mut ids = BoidIds::new();
+        let mut species = SpeciesTable::new(self.config);
+        let mut boids = Boids::default();
+        random_boids(
+            &mut rng,
+            &mut ids,
+            &mut boids,
+            0,
+            self.boid_count,
+            self.bounds,
+            &self.config,
+        );
+        for (config, role, boid_count) in &self.extra_species {
+            let id = species.add_with_role(*config, *role);
+            random_boids(
+                &mut rng,
+                &mut ids,
+                &mut boids,
+                id,
+                *boid_count,
+                self.bounds,
+                config,
+            );
+        }
+        let pool = self.threads.map(|threads| {
+            Arc::new(
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .expect("failed to build simulation thread pool"),
+            )
+        });
+        let index = AnyIndex::new(
+            self.boundary_mode.index_kind(self.index_kind),
+            self.bounds,
+            species.max_perception_radius(),
+        );
+        let mut obstacles = Obstacles::new(self.bounds);
+        for obstacle in self.obstacles {
+            obstacles.add(obstacle);
+        }
 
         Simulation {
-            boid_count,
             boids,
-            bounds,
-            grid,
-            mouse_position: vec2(0.0, 0.0),
-            boid_seperation_factor: BOID_SEPERATION_FACTOR,
-            boid_cohesion_factor: BOID_COHESION_FACTOR,
-            boid_alignment_factor: BOID_ALIGNMENT_FACTOR,
+            ids,
+            bounds: self.bounds,
+            index,
+            index_kind: self.index_kind,
+            species,
+            behaviors: flocking(),
+            boundary_mode: self.boundary_mode,
+            predation: self.predation,
+            predator_grid: HashedGrid::covering(self.bounds, PREDATOR_FEAR_RADIUS),
+            captures: Vec::new(),
+            obstacles,
+            neighbor_mode: self.neighbor_mode,
+            resort: self.resort,
+            steps_since_resort: 0,
+            integrator: self.integrator,
+            time_step: self.time_step,
+            timings: StepTimings::default(),
+            seed,
+            rng,
+            pool,
+            pointer: Pointer::default(),
+        }
+    }
+}
+
+fn assert_valid_step(seconds: f32) {
+    assert!(
+        integrator::is_valid_step(seconds),
+        "time step must be positive and finite, not {seconds}"
+    );
+}
+
+fn random_boids(
+    rng: &mut ChaCha8Rng,
+    ids: &mut BoidIds,
+    boids: &mut Boids,
+    species: SpeciesId,
+    boid_count: usize,
+    bounds: Rectangle,
+    config: &SimulationConfig,
+) {
+    let half_width = bounds.width / 2.0;
+    let half_height = bounds.height / 2.0;
+    let initial_speed = config.max_velocity * 2.0 / 3.0;
+
+    boids.reserve(boid_count);
+    for _ in 0..boid_count {
+        let rx = rng.gen_range(-half_width..half_width);
+        let ry = rng.gen_range(-half_height..half_height);
+        let pt = Vec2::new(rx, ry);
+        let vx = rng.gen_range(-initial_speed..initial_speed);
+        let vy = rng.gen_range(-initial_speed..initial_speed);
+        let v = Vec2::new(vx, vy);
+        let id = ids.allocate(boids.len());
+        boids.push(&new_boid(pt, v, id, species, config));
+    }
+}
+
+fn new_boid(
+    position: Vec2,
+    velocity: Vec2,
+    id: BoidId,
+    species: SpeciesId,
+    config: &SimulationConfig,
+) -> Boid {
+    Boid {
+        position,
+        velocity,
+        radius: config.radius,
+        acceleration: vec2(0.0, 0.0),
+        max_speed: config.max_velocity,
+        max_force: config.max_force,
+        id,
+        species,
+    }
+}
+
+pub struct Simulation {
+    boids: Boids,
+    ids: BoidIds,
+    bounds: Rectangle,
+    /// Every boid, rebuilt each step for neighbour, prey and capture lookups.
+    index: AnyIndex,
+    /// The kind `index` should be whenever `boundary_mode` allows it.
+    index_kind: IndexKind,
+    seed: u64,
+    rng: ChaCha8Rng,
+    pool: Option<Arc<rayon::ThreadPool>>,
+    species: SpeciesTable,
+    behaviors: Vec<WeightedBehavior>,
+    pub boundary_mode: BoundaryMode,
+    pub predation: Predation,
+    /// Predators only, rebuilt each step for prey to look up threats. Items
+    /// are positions in that step's list of predator indices.
+    predator_grid: HashedGrid,
+    captures: Vec<Capture>,
+    obstacles: Obstacles,
+    pub neighbor_mode: NeighborMode,
+    pub resort: Option<Resort>,
+    /// Steps since the last re-sort, modulo `Resort::every`.
+    steps_since_resort: u32,
+    pub integrator: Integrator,
+    time_step: FixedTimestep,
+    timings: StepTimings,
+    pub pointer: Pointer,
+}
+
+impl Simulation {
+    /// `boid_count` boids of the default species in `bounds`; see
+    /// `SimulationBuilder` for everything else.
+    pub fn new(boid_count: usize, bounds: Rectangle) -> Self {
+        SimulationBuilder::new(bounds)
+            .boid_count(boid_count)
+            .build()
+    }
+
+    /// Every live boid, densely packed, one array per field. The order
+    /// changes when boids are despawned or re-sorted, so hold on to
+    /// `BoidId`s rather than indices.
+    pub fn boids(&self) -> &Boids {
+        &self.boids
+    }
+
+    pub fn boids_mut(&mut self) -> &mut Boids {
+        &mut self.boids
+    }
+
+    /// A copy of boid `i` of `boids`, with its species' limits.
+    pub fn boid(&self, i: usize) -> Boid {
+        self.boids
+            .get(i, self.species.config(self.boids.species()[i]))
+    }
+
+    /// Copies of every live boid, in `boids` order.
+    pub fn iter(&self) -> impl Iterator<Item = Boid> + '_ {
+        (0..self.boids.len()).map(|i| self.boid(i))
+    }
+
+    pub fn get(&self, id: BoidId) -> Option<Boid> {
+        self.ids.dense_index(id).map(|i| self.boid(i))
+    }
+
+    /// Runs `f` on a copy of the boid and stores back its position, velocity
+    /// and acceleration. Returns `None`, without calling `f`, if `id` is
+    /// stale.
+    pub fn modify<R>(&mut self, id: BoidId, f: impl FnOnce(&mut Boid) -> R) -> Option<R> {
+        let i = self.ids.dense_index(id)?;
+        let mut boid = self.boid(i);
+        let result = f(&mut boid);
+        self.boids.set(i, &boid);
+        Some(result)
+    }
+
+    pub fn contains(&self, id: BoidId) -> bool {
+        self.ids.contains(id)
+    }
+
+    /// Adds a boid of species 0. It takes part in the next `navigate`, which
+    /// rebuilds the index from the live boids.
+    pub fn spawn(&mut self, position: Vec2, velocity: Vec2) -> BoidId {
+        self.spawn_species(0, position, velocity)
+    }
+
+    /// Adds a boid of `species` using that species' limits. Panics if the
+    /// species does not exist.
+    pub fn spawn_species(&mut self, species: SpeciesId, position: Vec2, velocity: Vec2) -> BoidId {
+        let id = self.ids.allocate(self.boids.len());
+        let config = self.species.config(species);
+        let boid = new_boid(position, velocity, id, species, config);
+        self.boids.push(&boid);
+        id
+    }
+
+    /// Removes a boid, returning it, or `None` if `id` is stale. The last boid
+    /// is moved into the freed place, so indices into `boids` are invalidated.
+    pub fn despawn(&mut self, id: BoidId) -> Option<Boid> {
+        let dense = self.ids.free(id)?;
+        let boid = self.boid(dense);
+        self.boids.swap_remove(dense);
+        if let Some(&moved) = self.boids.ids().get(dense) {
+            self.ids.relocate(moved, dense);
+        }
+        Some(boid)
+    }
+
+    pub fn bounds(&self) -> Rectangle {
+        self.bounds
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn rng(&mut self) -> &mut ChaCha8Rng {
+        &mut self.rng
+    }
+
+    /// Parameters of species 0.
+    pub fn config(&self) -> &SimulationConfig {
+        self.species.config(0)
+    }
+
+    /// Swaps in a new config for species 0, unless it fails
+    /// `SimulationConfig::validate`.
+    pub fn set_config(&mut self, config: SimulationConfig) -> Result<(), ConfigError> {
+        self.set_species_config(0, config)
+    }
+
+    pub fn species(&self) -> &SpeciesTable {
+        &self.species
+    }
+
+    /// Adds a species without boids, or returns why `config` is invalid.
+    pub fn add_species(&mut self, config: SimulationConfig) -> Result<SpeciesId, ConfigError> {
+        self.add_species_with_role(config, Role::Prey)
+    }
+
+    pub fn add_predator_species(
+        &mut self,
+        config: SimulationConfig,
+    ) -> Result<SpeciesId, ConfigError> {
+        self.add_species_with_role(config, Role::Predator)
+    }
+
+    fn add_species_with_role(
+        &mut self,
+        config: SimulationConfig,
+        role: Role,
+    ) -> Result<SpeciesId, ConfigError> {
+        config.validate()?;
+        let species = self.species.add_with_role(config, role);
+        self.resize_index();
+        Ok(species)
+    }
+
+    pub fn obstacles(&self) -> &Obstacles {
+        &self.obstacles
+    }
+
+    /// Obstacles can be added, moved and removed between steps.
+    pub fn obstacles_mut(&mut self) -> &mut Obstacles {
+        &mut self.obstacles
+    }
+
+    /// Drains the captures made since the last call. Captured prey have
+    /// already been despawned.
+    pub fn take_captures(&mut self) -> Vec<Capture> {
+        std::mem::take(&mut self.captures)
+    }
+
+    /// Swaps in new parameters for one species, resizing the grid if
+    /// needed. Its boids take their limits from the new config at once. An
+    /// invalid config is rejected and the old one kept.
+    pub fn set_species_config(
+        &mut self,
+        species: SpeciesId,
+        config: SimulationConfig,
+    ) -> Result<(), ConfigError> {
+        config.validate()?;
+        self.species.set_config(species, config);
+        self.resize_index();
+        Ok(())
+    }
+
+    /// Sets how boids of `species` react to boids of `other`. Panics if
+    /// either species doesn't exist.
+    pub fn set_interaction(
+        &mut self,
+        species: SpeciesId,
+        other: SpeciesId,
+        interaction: Interaction,
+    ) {
+        self.species.set_interaction(species, other, interaction);
+    }
+
+    /// Grid cells must stay as wide as the largest perception radius.
+    fn resize_index(&mut self) {
+        let cell_size = self.species.max_perception_radius();
+        if self.index.cell_size().is_some_and(|size| size != cell_size) {
+            self.index = AnyIndex::new(self.index.kind(), self.bounds, cell_size);
+        }
+    }
+
+    /// The structure boids are looked up in: the requested one, or the
+    /// hashed grid while `boundary_mode` lets boids leave the bounds.
+    pub fn index_kind(&self) -> IndexKind {
+        self.boundary_mode.index_kind(self.index_kind)
+    }
+
+    /// The kind last asked for, kept while the hashed grid stands in for it.
+    pub fn requested_index_kind(&self) -> IndexKind {
+        self.index_kind
+    }
+
+    /// Switches the structure boids are looked up in; takes effect on the next
+    /// step. The grid and the quadtree only cover the bounds, so while
+    /// `boundary_mode` lets boids leave them the hashed grid is used instead,
+    /// and `kind` comes back once they are kept inside again.
+    pub fn set_index_kind(&mut self, kind: IndexKind) {
+        self.index_kind = kind;
+        self.resolve_index();
+    }
+
+    /// Rebuilds `index` if `index_kind` says it should be of another kind.
+    fn resolve_index(&mut self) {
+        let kind = self.index_kind();
+        if kind != self.index.kind() {
+            self.index = AnyIndex::new(kind, self.bounds, self.species.max_perception_radius());
+        }
+    }
+
+    /// Registers an extra steering rule alongside the ones already present.
+    pub fn add_behavior(&mut self, weight: f32, behavior: impl SteeringBehavior + 'static) {
+        self.behaviors.push(WeightedBehavior::new(weight, behavior));
+    }
+
+    /// Removes every steering rule, including the default flocking ones.
+    pub fn clear_behaviors(&mut self) {
+        self.behaviors.clear();
+    }
+
+    pub fn behaviors(&self) -> &[WeightedBehavior] {
+        &self.behaviors
+    }
+
+    pub fn behaviors_mut(&mut self) -> &mut Vec<WeightedBehavior> {
+        &mut self.behaviors
+    }
+
+    /// Behaviours whose reach exceeds the radius their species' neighbours
+    /// are queried within. Empty in topological mode, where the query has no
+    /// fixed radius and `follow_radius` stretches to the k-th neighbour.
+    pub fn check_coverage(&self) -> Vec<CoverageIssue> {
+        if let NeighborMode::Topological { .. } = self.neighbor_mode {
+            return Vec::new();
+        }
+        let mut issues = Vec::new();
+        for (species, config) in self.species.configs().iter().enumerate() {
+            let query_radius = config.perception_radius();
+            for (behavior, weighted) in self.behaviors.iter().enumerate() {
+                let radius = weighted.behavior.radius(config);
+                if radius > query_radius {
+                    issues.push(CoverageIssue {
+                        species: species as SpeciesId,
+                        behavior,
+                        radius,
+                        query_radius,
+                    });
+                }
+            }
         }
+        issues
+    }
+
+    /// Length of one `navigate` step in seconds.
+    pub fn time_step(&self) -> f32 {
+        self.time_step.step
+    }
+
+    /// Panics unless `seconds` is positive and finite.
+    pub fn set_time_step(&mut self, seconds: f32) {
+        assert_valid_step(seconds);
+        self.time_step.step = seconds;
     }
 
-    fn steer(&mut self, point: Vec2) {
-        for boid in &mut self.boids {
-            let mut steer = boid.velocity - (boid.position - point);
-            steer = steer.clamp_length_max(boid.max_force);
-            boid.apply_force(steer);
+    /// Advances the simulation by `elapsed` seconds of wall-clock time, running
+    /// as many fixed `navigate` steps as have accumulated. Returns the number
+    /// of steps taken.
+    pub fn advance(&mut self, elapsed: f32) -> u32 {
+        self.advance_with(elapsed, |_| {})
+    }
+
+    /// Like `advance`, calling `after_step` after each step, so per-step
+    /// work such as recording sees every state exactly once however the
+    /// steps fall across frames.
+    pub fn advance_with(&mut self, elapsed: f32, mut after_step: impl FnMut(&mut Self)) -> u32 {
+        let steps = self.time_step.advance(elapsed);
+        for _ in 0..steps {
+            self.navigate();
+            after_step(self);
         }
+        steps
+    }
+
+    /// How long the last `navigate` took, phase by phase.
+    pub fn timings(&self) -> StepTimings {
+        self.timings
     }
 
+    /// Runs exactly one fixed time step.
     pub fn navigate(&mut self) {
-        self.grid.clear();
-        for (i, boid) in self.boids.iter().enumerate() {
-            self.grid.insert(boid.position, i);
+        match self.pool.clone() {
+            Some(pool) => pool.install(|| self.navigate_parallel()),
+            None => self.navigate_parallel(),
+        }
+    }
+
+    fn navigate_parallel(&mut self) {
+        let dt = self.time_step.step;
+        let integrator = self.integrator;
+        let bounds = self.bounds;
+        let boundary_mode = self.boundary_mode;
+        let mut timings = StepTimings::default();
+        let mut clock = Instant::now();
+        let mut lap = || {
+            let now = Instant::now();
+            let elapsed = now - clock;
+            clock = now;
+            elapsed
+        };
+        if integrator.drifts_first() {
+            self.boids.par_update(
+                &self.species,
+                || (),
+                |_, _, b| {
+                    integrator.drift(b, dt);
+                    b.apply_boundary(bounds, boundary_mode);
+                },
+            );
+        }
+
+        let drift = lap();
+        self.resort_if_due();
+        timings.sort = lap();
+        // `boundary_mode` may have changed since the index was chosen.
+        self.resolve_index();
+        let positions = self.boids.positions();
+        self.index.rebuild(positions.len(), |i| positions[i]);
+
+        let predators: Vec<usize> = if self.species.has_predators() {
+            let species = &self.species;
+            let kinds = self.boids.species();
+            (0..kinds.len())
+                .filter(|&i| species.role(kinds[i]) == Role::Predator)
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let fear_radius = self.predation.fear_radius;
+        let fleeing = !predators.is_empty() && fear_radius > 0.0;
+        if fleeing {
+            if self.predator_grid.cell_size() != fear_radius {
+                self.predator_grid = HashedGrid::covering(bounds, fear_radius);
+            }
+            let positions = self.boids.positions();
+            self.predator_grid
+                .rebuild(predators.len(), |k| positions[predators[k]]);
         }
 
+        self.obstacles.refresh_index();
+        timings.index = lap();
+
         let boids = &self.boids;
-        let grid = &self.grid;
-        let sep_factor = self.boid_seperation_factor;
-        let coh_factor = self.boid_cohesion_factor;
-        let ali_factor = self.boid_alignment_factor;
-        let avoid_radius_sq = BOID_AVOID_RADIUS * BOID_AVOID_RADIUS;
-        let follow_radius_sq = BOID_FOLLOW_RADIUS * BOID_FOLLOW_RADIUS;
+        let positions = boids.positions();
+        let velocities = boids.velocities();
+        let kinds = boids.species();
+        let index = &self.index;
+        let obstacles = &self.obstacles;
+        let predator_grid = &self.predator_grid;
+        let species = &self.species;
+        let behaviors = &self.behaviors;
+        let pointer = &self.pointer;
+        let predation = &self.predation;
+        let neighbor_mode = self.neighbor_mode;
+        let cell_size = species.max_perception_radius();
+        let topology = boundary_mode.topology(bounds);
+        // Built-in rules only need sums, which the kernel gathers without
+        // building a `Neighbor` per neighbour.
+        let fused = behaviors
+            .iter()
+            .all(|weighted| weighted.behavior.uses_sums());
 
         let forces: Vec<Vec2> = (0..boids.len())
             .into_par_iter()
-            .map(|i| {
-                let boid = &boids[i];
-                let mut separation = Vec2::new(0.0, 0.0);
-                let mut count_separation = 0;
-                let mut cohesion = Vec2::new(0.0, 0.0);
-                let mut count_cohesion = 0;
-                let mut alignment = Vec2::new(0.0, 0.0);
-                let mut count_alignment = 0;
-
-                grid.query(boid.get_perception_rect(), |other_idx| {
-                    if other_idx == i {
-                        return;
+            .map_init(Scratch::default, |scratch, i| {
+                let config = species.config(kinds[i]);
+                let boid = boids.get(i, config);
+                let offset_to = |j: usize| topology.offset(boid.position, positions[j]);
+                let interaction = |j: usize| {
+                    let interaction = species.interaction(boid.species, kinds[j]);
+                    (j != i && interaction != Interaction::Ignore).then_some(interaction)
+                };
+                let perceive = |j: usize, interaction: Interaction, config: &SimulationConfig| {
+                    let other = boids.get(j, species.config(kinds[j]));
+                    Neighbor::perceive(&boid, other, offset_to(j), interaction, config)
+                };
+                let Scratch {
+                    neighbors,
+                    candidates,
+                    nearest,
+                    threats,
+                    obstacles: found,
+                } = scratch;
+                neighbors.clear();
+                candidates.clear();
+                let mut add = |j: usize, config: &SimulationConfig| {
+                    let interaction = match interaction(j) {
+                        Some(interaction) => interaction,
+                        None => return,
+                    };
+                    if fused {
+                        let separation_radius = match interaction {
+                            Interaction::Flock => config.avoid_radius,
+                            _ => config.follow_radius,
+                        };
+                        candidates.push(
+                            offset_to(j),
+                            velocities[j],
+                            separation_radius * separation_radius,
+                            interaction == Interaction::Flock,
+                        );
+                    } else {
+                        neighbors.extend(perceive(j, interaction, config));
                     }
-                    let other = &boids[other_idx];
-                    let diff = boid.position - other.position;
-                    let dist_sq = diff.length_squared();
-                    if dist_sq <= avoid_radius_sq {
-                        separation += diff;
-                        count_separation += 1;
+                };
+                let config = match neighbor_mode {
+                    NeighborMode::Metric => {
+                        topology.query_radius(
+                            index,
+                            boid.position,
+                            config.perception_radius(),
+                            |j| positions[j],
+                            |j, _| add(j, config),
+                        );
+                        *config
                     }
-                    if dist_sq <= follow_radius_sq {
-                        cohesion += other.position;
-                        alignment += other.velocity;
-                        count_cohesion += 1;
-                        count_alignment += 1;
+                    NeighborMode::Topological { k } => {
+                        topology.query_nearest(
+                            index,
+                            boid.position,
+                            k,
+                            |j| positions[j],
+                            |j| {
+                                interaction(j).is_some_and(|interaction| {
+                                    perceive(j, interaction, config).is_some()
+                                })
+                            },
+                            nearest,
+                        );
+                        // Stretch the follow radius to the k-th nearest so
+                        // cohesion and alignment see all k.
+                        let mut stretched = *config;
+                        if let Some(&(distance_squared, _)) = nearest.last() {
+                            stretched.follow_radius =
+                                stretched.follow_radius.max(distance_squared.sqrt());
+                        }
+                        for &(_, j) in nearest.iter() {
+                            add(j, &stretched);
+                        }
+                        stretched
                     }
-                });
+                };
+                let config = &config;
 
-                let mut net = vec2(0.0, 0.0);
-                if count_separation > 0 {
-                    separation = separation / count_separation as f32;
-                    separation = separation.normalize();
-                    net += separation * sep_factor;
+                let mut net = if fused {
+                    let sums = candidates.sums(boid.velocity, config);
+                    steering_force_from_sums(behaviors, &boid, &sums, config)
+                } else {
+                    steering_force(behaviors, &boid, neighbors, config)
+                };
+                match species.role(boid.species) {
+                    Role::Prey if fleeing => {
+                        threats.clear();
+                        topology.query_radius(
+                            predator_grid,
+                            boid.position,
+                            fear_radius,
+                            |k| positions[predators[k]],
+                            |k, _| threats.push(boid.position + offset_to(predators[k])),
+                        );
+                        net += predation.flee(&boid, threats);
+                    }
+                    Role::Predator => {
+                        if let Some((position, velocity)) =
+                            predation.find_prey(boids, index, topology, species, i, cell_size)
+                        {
+                            net += predation.pursue(&boid, position, velocity);
+                        }
+                    }
+                    Role::Prey => {}
                 }
-                if count_cohesion > 0 {
-                    cohesion = cohesion / count_cohesion as f32;
-                    cohesion = (cohesion - boid.position).normalize();
-                    alignment = alignment / count_alignment as f32;
-                    alignment *= BOID_MAX_VELOCITY;
-                    net += (alignment - boid.velocity) * ali_factor;
-                    net += cohesion * coh_factor;
+                net += obstacles.avoidance(&boid, found);
+                net += pointer.force(&boid, topology);
+                if let BoundaryMode::SoftWalls { margin, strength } = boundary_mode {
+                    net += boid.wall_force(bounds, margin, strength);
                 }
                 net
             })
             .collect();
+        let captures = predation.captures(boids, index, topology, species, &predators);
+        timings.forces = lap();
 
-        self.boids.par_iter_mut().enumerate().for_each(|(i, b)| {
-            b.apply_force(forces[i]);
-            b.update(self.bounds);
-        });
+        let obstacles = &self.obstacles;
+        self.boids
+            .par_update(&self.species, Vec::new, |found, i, b| {
+                integrator.integrate(b, forces[i], dt);
+                if !integrator.drifts_first() {
+                    b.apply_boundary(bounds, boundary_mode);
+                }
+                obstacles.resolve_penetration(b, found);
+            });
+
+        for capture in &captures {
+            self.despawn(capture.prey);
+        }
+        self.captures.extend(captures);
+        timings.integration = drift + lap();
+        self.timings = timings;
+    }
+
+    pub fn boid_count(&self) -> usize {
+        self.boids.len()
     }
 
-    pub fn update(&mut self, mouse_position: Vec2) {
-        self.mouse_position = mouse_position;
-        self.navigate();
+    /// Moves the pointer and advances by `elapsed` seconds.
+    pub fn update(&mut self, mouse_position: Vec2, elapsed: f32) {
+        self.pointer.position = mouse_position;
+        self.advance(elapsed);
     }
 
+    #[cfg(feature = "render")]
     pub fn draw(&self, draw: &nannou::prelude::Draw) {
-        let positions: Vec<Vec3> = self
-            .boids
+        use nannou::prelude::{vec3, Rgb, Vec3};
+        self.obstacles.draw(draw);
+        const SPECIES_COLORS: [(f32, f32, f32); 6] = [
+            (1.0, 1.0, 1.0),
+            (1.0, 0.55, 0.2),
+            (0.3, 0.7, 1.0),
+            (0.5, 1.0, 0.4),
+            (1.0, 0.4, 0.7),
+            (1.0, 0.9, 0.3),
+        ];
+        let positions = self.boids.positions();
+        if self.species.len() == 1 {
+            let positions: Vec<Vec3> = positions.iter().map(|p| vec3(p.x, p.y, 0.0)).collect();
+            draw.point_mode().mesh().points(positions);
+            return;
+        }
+        let points: Vec<(Vec3, Rgb)> = positions
             .iter()
-            .map(|b| vec3(b.position.x, b.position.y, 0.0))
+            .zip(self.boids.species())
+            .map(|(p, &species)| {
+                let (r, g, bl) = SPECIES_COLORS[species as usize % SPECIES_COLORS.len()];
+                (vec3(p.x, p.y, 0.0), Rgb::new(r, g, bl))
+            })
             .collect();
+        draw.point_mode().mesh().points_colored(points);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bounds() -> Rectangle {
+        Rectangle::new(-100.0, -100.0, 200.0, 200.0)
+    }
+
+    #[test]
+    fn boundaries_boids_can_leave_use_the_hashed_grid() {
+        for mode in [
+            BoundaryMode::Unbounded,
+            BoundaryMode::SoftWalls {
+                margin: 10.0,
+                strength: 1.0,
+            },
+        ] {
+            let mut simulation = SimulationBuilder::new(bounds())
+                .boundary_mode(mode)
+                .index_kind(IndexKind::Grid)
+                .build();
+            assert_eq!(simulation.index_kind(), IndexKind::Hashed);
+            simulation.set_index_kind(IndexKind::QuadTree);
+            assert_eq!(simulation.index_kind(), IndexKind::Hashed);
+            assert_eq!(simulation.requested_index_kind(), IndexKind::QuadTree);
+
+            // The requested kind comes back once boids are kept inside.
+            simulation.boundary_mode = BoundaryMode::Wrap;
+            simulation.navigate();
+            assert_eq!(simulation.index.kind(), IndexKind::QuadTree);
+            assert_eq!(simulation.index_kind(), IndexKind::QuadTree);
+        }
+
+        let mut simulation = SimulationBuilder::new(bounds())
+            .index_kind(IndexKind::Grid)
+            .build();
+        assert_eq!(simulation.index_kind(), IndexKind::Grid);
+        simulation.boundary_mode = BoundaryMode::Unbounded;
+        simulation.navigate();
+        assert_eq!(simulation.index.kind(), IndexKind::Hashed);
+        simulation.boundary_mode = BoundaryMode::Reflect;
+        simulation.navigate();
+        assert_eq!(simulation.index.kind(), IndexKind::Grid);
+    }
+
+    #[test]
+    fn boids_outside_the_bounds_avoid_obstacles() {
+        let mut simulation = SimulationBuilder::new(bounds())
+            .boid_count(0)
+            .boundary_mode(BoundaryMode::Unbounded)
+            .obstacle(Obstacle::circle(vec2(300.0, 50.0), 20.0))
+            .build();
+        let id = simulation.spawn(vec2(200.0, 55.0), vec2(120.0, 0.0));
+        for _ in 0..120 {
+            simulation.navigate();
+            let position = simulation.get(id).unwrap().position;
+            assert!(position.distance(vec2(300.0, 50.0)) >= 20.0);
+        }
+        assert!(simulation.get(id).unwrap().position.x > 300.0);
+    }
+
+    #[test]
+    fn advance_with_runs_once_per_step() {
+        let mut simulation = SimulationBuilder::new(bounds()).boid_count(10).build();
+        let step = simulation.time_step();
+        let mut calls = 0;
+        assert_eq!(simulation.advance_with(step * 0.5, |_| calls += 1), 0);
+        assert_eq!(calls, 0);
+        assert_eq!(simulation.advance_with(step * 3.0, |_| calls += 1), 3);
+        assert_eq!(calls, 3);
+    }
+
+    fn flock(boundary_mode: BoundaryMode) -> Simulation {
+        SimulationBuilder::new(bounds())
+            .boid_count(300)
+            .seed(7)
+            .boundary_mode(boundary_mode)
+            .build()
+    }
+
+    #[test]
+    fn navigate_keeps_boids_finite_and_within_their_limits() {
+        let mut simulation = flock(BoundaryMode::Wrap);
+        let ids: Vec<BoidId> = simulation.boids().ids().to_vec();
+        for _ in 0..100 {
+            simulation.navigate();
+            for boid in simulation.iter() {
+                assert!(boid.position.is_finite() && boid.velocity.is_finite());
+                assert!(boid.velocity.length() <= boid.max_speed * 1.0001);
+                assert!(boid.acceleration.length() <= boid.max_force * 1.0001);
+            }
+        }
+        assert_eq!(simulation.boid_count(), ids.len());
+        assert!(ids.iter().all(|&id| simulation.contains(id)));
+    }
+
+    #[test]
+    fn navigate_is_reproducible_from_the_seed() {
+        let mut first = flock(BoundaryMode::Wrap);
+        let mut second = flock(BoundaryMode::Wrap);
+        for _ in 0..50 {
+            first.navigate();
+            second.navigate();
+        }
+        assert_eq!(first.boids().positions(), second.boids().positions());
+        assert_eq!(first.boids().velocities(), second.boids().velocities());
+    }
+
+    #[test]
+    fn close_boids_separate() {
+        let mut simulation = SimulationBuilder::new(bounds()).boid_count(0).build();
+        let a = simulation.spawn(vec2(-1.0, 0.0), vec2(0.0, 0.0));
+        let b = simulation.spawn(vec2(1.0, 0.0), vec2(0.0, 0.0));
+        simulation.navigate();
+        let (a, b) = (simulation.get(a).unwrap(), simulation.get(b).unwrap());
+        assert!(a.velocity.x < 0.0 && b.velocity.x > 0.0);
+        assert!(a.position.distance(b.position) > 2.0);
+    }
+
+    #[test]
+    fn switching_the_index_mid_run_does_not_change_the_flight() {
+        let mut fixed = flock(BoundaryMode::Wrap);
+        let mut switched = flock(BoundaryMode::Wrap);
+        let kinds = [IndexKind::QuadTree, IndexKind::Hashed, IndexKind::Grid];
+        for step in 0..12 {
+            switched.set_index_kind(kinds[step % kinds.len()]);
+            assert_eq!(switched.index.kind(), kinds[step % kinds.len()]);
+            fixed.navigate();
+            switched.navigate();
+        }
+        // Backends report neighbours in different orders, so sums may round
+        // differently.
+        for (a, b) in fixed.iter().zip(switched.iter()) {
+            assert_eq!(a.id, b.id);
+            assert!(a.position.distance(b.position) < 1e-2, "{:?}", a.id);
+            assert!(a.velocity.distance(b.velocity) < 1e-2, "{:?}", a.id);
+        }
+    }
+
+    #[test]
+    fn topological_neighbours_reach_past_the_follow_radius() {
+        let run = |neighbor_mode: NeighborMode| {
+            let mut simulation = SimulationBuilder::new(bounds())
+                .boid_count(0)
+                .neighbor_mode(neighbor_mode)
+                .build();
+            // Far beyond `follow_radius` of each other.
+            let ids = [vec2(0.0, 0.0), vec2(30.0, 0.0), vec2(-60.0, 0.0)]
+                .map(|position| simulation.spawn(position, vec2(0.0, 0.0)));
+            simulation.navigate();
+            ids.map(|id| simulation.get(id).unwrap().velocity.x)
+        };
+        assert_eq!(run(NeighborMode::Metric), [0.0; 3]);
+        // Each boid coheres with its nearest neighbour alone.
+        let [a, b, c] = run(NeighborMode::Topological { k: 1 });
+        assert!(a > 0.0 && b < 0.0 && c > 0.0, "{a} {b} {c}");
+        // With both others counted, the far one wins.
+        let [a, _, _] = run(NeighborMode::Topological { k: 2 });
+        assert!(a < 0.0, "{a}");
+    }
+
+    fn inside(bounds: Rectangle, position: Vec2, slack: f32) -> bool {
+        position.x >= bounds.x - slack
+            && position.y >= bounds.y - slack
+            && position.x <= bounds.x + bounds.width + slack
+            && position.y <= bounds.y + bounds.height + slack
+    }
+
+    #[test]
+    fn wrap_keeps_boids_inside_the_bounds() {
+        let mut simulation = flock(BoundaryMode::Wrap);
+        for _ in 0..200 {
+            simulation.navigate();
+            let bounds = simulation.bounds();
+            assert!(simulation
+                .iter()
+                .all(|boid| inside(bounds, boid.position, 0.0)));
+        }
+    }
+
+    #[test]
+    fn reflect_keeps_bodies_inside_the_bounds() {
+        let mut simulation = flock(BoundaryMode::Reflect);
+        for _ in 0..200 {
+            simulation.navigate();
+            let bounds = simulation.bounds();
+            assert!(simulation
+                .iter()
+                .all(|boid| inside(bounds, boid.position, -boid.radius)));
+        }
+    }
+
+    #[test]
+    fn soft_walls_turn_boids_back() {
+        let margin = 30.0;
+        let mut simulation = flock(BoundaryMode::SoftWalls {
+            margin,
+            strength: 1.0,
+        });
+        for _ in 0..200 {
+            simulation.navigate();
+            let bounds = simulation.bounds();
+            assert!(simulation
+                .iter()
+                .all(|boid| inside(bounds, boid.position, margin)));
+        }
+    }
+
+    #[test]
+    fn unbounded_lets_boids_go() {
+        let mut simulation = SimulationBuilder::new(bounds())
+            .boid_count(0)
+            .boundary_mode(BoundaryMode::Unbounded)
+            .build();
+        let id = simulation.spawn(vec2(90.0, 0.0), vec2(120.0, 0.0));
+        for _ in 0..60 {
+            simulation.navigate();
+        }
+        let boid = simulation.get(id).unwrap();
+        assert!((boid.position.x - 210.0).abs() < 1.0);
+        assert_eq!(boid.velocity, vec2(120.0, 0.0));
+    }
+
+    #[test]
+    fn thread_count_does_not_change_the_result() {
+        let run = |threads: usize| {
+            let mut simulation = SimulationBuilder::new(bounds())
+                .boid_count(1000)
+                .seed(11)
+                .threads(threads)
+                .build();
+            for _ in 0..100 {
+                simulation.navigate();
+            }
+            simulation
+        };
+        let bits = |simulation: &Simulation| -> Vec<[u32; 4]> {
+            let boids = simulation.boids();
+            boids
+                .positions()
+                .iter()
+                .zip(boids.velocities())
+                .map(|(p, v)| [p.x.to_bits(), p.y.to_bits(), v.x.to_bits(), v.y.to_bits()])
+                .collect()
+        };
+        assert_eq!(bits(&run(1)), bits(&run(4)));
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        let mut simulation = SimulationBuilder::new(bounds()).boid_count(10).build();
+        let invalid = SimulationConfig {
+            max_velocity: f32::NAN,
+            ..SimulationConfig::default()
+        };
+        assert!(simulation.set_config(invalid).is_err());
+        assert_eq!(*simulation.config(), SimulationConfig::default());
+        assert!(simulation.add_species(invalid).is_err());
+        assert!(simulation.add_predator_species(invalid).is_err());
+        assert_eq!(simulation.species().len(), 1);
+
+        let wider = SimulationConfig {
+            follow_radius: 25.0,
+            ..SimulationConfig::default()
+        };
+        assert!(simulation.set_config(wider).is_ok());
+        assert_eq!(simulation.config().follow_radius, 25.0);
+        assert_eq!(simulation.add_species(wider).unwrap(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid species config")]
+    fn building_with_an_invalid_config_panics() {
+        let invalid = SimulationConfig {
+            view_angle: 0.0,
+            ..SimulationConfig::default()
+        };
+        SimulationBuilder::new(bounds())
+            .species(invalid, 10)
+            .build();
+    }
+
+    struct Reaching(f32);
+
+    impl SteeringBehavior for Reaching {
+        fn steer(&self, _: &Boid, _: &[Neighbor], _: &SimulationConfig) -> Vec2 {
+            Vec2::new(0.0, 0.0)
+        }
+
+        fn radius(&self, _: &SimulationConfig) -> f32 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn coverage_lists_behaviours_reaching_past_the_perception_radius() {
+        let mut simulation = SimulationBuilder::new(bounds()).boid_count(0).build();
+        assert!(simulation.check_coverage().is_empty());
+        let perception_radius = simulation.config().perception_radius();
+        simulation.add_behavior(1.0, Reaching(perception_radius));
+        assert!(simulation.check_coverage().is_empty());
+        simulation.add_behavior(1.0, Reaching(perception_radius * 2.0));
+        let issues = simulation.check_coverage();
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].behavior, 4);
+        assert_eq!(issues[0].radius, perception_radius * 2.0);
+    }
+
+    /// Always pushes the same way.
+    struct Push(Vec2);
+
+    impl SteeringBehavior for Push {
+        fn steer(&self, _: &Boid, _: &[Neighbor], _: &SimulationConfig) -> Vec2 {
+            self.0
+        }
+
+        fn radius(&self, _: &SimulationConfig) -> f32 {
+            0.0
+        }
+    }
+
+    /// Steers towards every neighbour it is handed.
+    struct Gather;
+
+    impl SteeringBehavior for Gather {
+        fn steer(&self, _: &Boid, neighbors: &[Neighbor], _: &SimulationConfig) -> Vec2 {
+            neighbors
+                .iter()
+                .fold(Vec2::new(0.0, 0.0), |sum, neighbor| sum + neighbor.offset)
+        }
+
+        fn radius(&self, config: &SimulationConfig) -> f32 {
+            config.perception_radius()
+        }
+    }
+
+    #[test]
+    fn custom_behaviours_replace_the_flocking_rules() {
+        let mut simulation = SimulationBuilder::new(bounds()).boid_count(0).build();
+        let a = simulation.spawn(vec2(-1.0, 0.0), vec2(0.0, 10.0));
+        let b = simulation.spawn(vec2(1.0, 0.0), vec2(0.0, 10.0));
+        assert_eq!(simulation.behaviors().len(), 3);
+
+        // With no rules at all nothing steers, not even separation.
+        simulation.clear_behaviors();
+        simulation.navigate();
+        assert_eq!(simulation.get(a).unwrap().velocity, vec2(0.0, 10.0));
+
+        simulation.add_behavior(0.5, Push(vec2(0.0, 120.0)));
+        simulation.navigate();
+        let expected = 10.0 + 60.0 * simulation.time_step();
+        for id in [a, b] {
+            let velocity = simulation.get(id).unwrap().velocity;
+            assert!((velocity.y - expected).abs() < 1e-4, "{velocity:?}");
+            assert_eq!(velocity.x, 0.0);
+        }
+
+        // Behaviours see the neighbours `navigate` found.
+        simulation.clear_behaviors();
+        simulation.add_behavior(1.0, Gather);
+        simulation.navigate();
+        let (a, b) = (simulation.get(a).unwrap(), simulation.get(b).unwrap());
+        assert!(a.velocity.x > 0.0 && b.velocity.x < 0.0);
+    }
+
+    #[test]
+    fn despawning_keeps_other_ids_valid() {
+        let mut simulation = SimulationBuilder::new(bounds()).boid_count(0).build();
+        let ids: Vec<BoidId> = (0..3)
+            .map(|i| simulation.spawn(vec2(i as f32 * 10.0, 0.0), vec2(1.0, i as f32)))
+            .collect();
+        let middle = simulation.despawn(ids[1]).unwrap();
+        assert_eq!(middle.position, vec2(10.0, 0.0));
+        assert_eq!(simulation.boid_count(), 2);
+        assert!(simulation.get(ids[1]).is_none());
+        assert!(simulation.despawn(ids[1]).is_none());
+        // The last boid was moved into the freed place and still resolves.
+        let last = simulation.get(ids[2]).unwrap();
+        assert_eq!(
+            (last.position, last.velocity),
+            (vec2(20.0, 0.0), vec2(1.0, 2.0))
+        );
+        assert_eq!(simulation.boids().ids()[1], ids[2]);
+        assert_eq!(simulation.get(ids[0]).unwrap().position, vec2(0.0, 0.0));
+
+        // The freed slot is reused, but the old id doesn't match the new boid.
+        let reused = simulation.spawn(vec2(-5.0, 0.0), vec2(0.0, 0.0));
+        assert_eq!(reused.slot(), ids[1].slot());
+        assert_ne!(reused, ids[1]);
+        assert!(simulation.get(ids[1]).is_none());
+        assert!(simulation.modify(ids[1], |_| ()).is_none());
+        assert_eq!(simulation.get(reused).unwrap().position, vec2(-5.0, 0.0));
+    }
+
+    #[test]
+    #[should_panic(expected = "time step must be positive")]
+    fn builder_rejects_a_zero_time_step() {
+        SimulationBuilder::new(bounds()).time_step(0.0);
+    }
+
+    #[test]
+    fn set_time_step_rejects_bad_steps() {
+        let mut simulation = SimulationBuilder::new(bounds()).boid_count(0).build();
+        simulation.set_time_step(0.5);
+        assert_eq!(simulation.time_step(), 0.5);
+        assert_eq!(simulation.advance(1.2), 2);
+        for seconds in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                simulation.set_time_step(seconds)
+            }));
+            assert!(result.is_err(), "{seconds}");
+            assert_eq!(simulation.time_step(), 0.5);
+        }
+    }
 
-        draw.point_mode().mesh().points(positions);
+    #[test]
+    fn new_spawns_the_requested_number_of_boids() {
+        assert_eq!(Simulation::new(0, bounds()).boid_count(), 0);
+        assert_eq!(Simulation::new(25, bounds()).boid_count(), 25);
     }
 }
@@ -0,0 +1,261 @@
+//! The flocking force kernel: a boid's candidate neighbours, gathered field
+//! by field from the boid arrays, are summed `LANES` at a time into the
+//! `NeighborSums` the built-in rules steer by.
+
+use crate::behavior::NeighborSums;
+use crate::config::SimulationConfig;
+use glam::Vec2;
+
+/// Candidates processed together. Every lane loop below is branch-free
+/// arithmetic over fixed-size arrays, which the compiler turns into SIMD
+/// instructions.
+const LANES: usize = 8;
+
+/// One boid's candidate neighbours, one array per field, padded to whole
+/// lanes before summing.
+#[derive(Default)]
+pub(super) struct Candidates {
+    offset_x: Vec<f32>,
+    offset_y: Vec<f32>,
+    velocity_x: Vec<f32>,
+    velocity_y: Vec<f32>,
+    /// Squared distance within which the candidate counts for separation.
+    separation_radius_sq: Vec<f32>,
+    /// 1 for flockmates, which cohesion and alignment follow; 0 otherwise.
+    flock: Vec<f32>,
+}
+
+impl Candidates {
+    pub(super) fn clear(&mut self) {
+        self.offset_x.clear();
+        self.offset_y.clear();
+        self.velocity_x.clear();
+        self.velocity_y.clear();
+        self.separation_radius_sq.clear();
+        self.flock.clear();
+    }
+
+    /// Adds a neighbour at `offset` from the steering boid.
+    pub(super) fn push(
+        &mut self,
+        offset: Vec2,
+        velocity: Vec2,
+        separation_radius_sq: f32,
+        flock: bool,
+    ) {
+        self.offset_x.push(offset.x);
+        self.offset_y.push(offset.y);
+        self.velocity_x.push(velocity.x);
+        self.velocity_y.push(velocity.y);
+        self.separation_radius_sq.push(separation_radius_sq);
+        self.flock.push(if flock { 1.0 } else { 0.0 });
+    }
+
+    /// Fills the last lanes with candidates that add nothing: out of
+    /// separation range and not flockmates.
+    fn pad(&mut self) {
+        while !self.flock.len().is_multiple_of(LANES) {
+            self.push(Vec2::new(0.0, 0.0), Vec2::new(0.0, 0.0), -1.0, false);
+        }
+    }
+
+    /// What `NeighborSums::from_neighbors` gives for the neighbours a boid
+    /// moving at `velocity` perceives among the candidates: the same view
+    /// cone and weighting as `Neighbor::perceive`, up to rounding.
+    pub(super) fn sums(&mut self, velocity: Vec2, config: &SimulationConfig) -> NeighborSums {
+        self.pad();
+        let follow_radius_sq = config.follow_radius * config.follow_radius;
+        let view_cone = config.view_angle < 360.0;
+        let angle_weighting = config.angle_weighting > 0.0;
+        let distance_weighting = config.distance_weighting > 0.0 && config.follow_radius > 0.0;
+        let speed_squared = velocity.length_squared();
+        let directional = (view_cone || angle_weighting) && speed_squared > 0.0;
+        let heading = velocity / speed_squared.sqrt();
+        let half_angle = (config.view_angle * 0.5).to_radians();
+        let half_angle_cos = half_angle.cos();
+
+        let mut separation_x = [0.0; LANES];
+        let mut separation_y = [0.0; LANES];
+        let mut separation_weight = [0.0; LANES];
+        let mut cohesion_x = [0.0; LANES];
+        let mut cohesion_y = [0.0; LANES];
+        let mut alignment_x = [0.0; LANES];
+        let mut alignment_y = [0.0; LANES];
+        let mut flock_weight = [0.0; LANES];
+
+        for start in (0..self.flock.len()).step_by(LANES) {
+            let lanes = start..start + LANES;
+            let offset_x = lane(&self.offset_x[lanes.clone()]);
+            let offset_y = lane(&self.offset_y[lanes.clone()]);
+            let velocity_x = lane(&self.velocity_x[lanes.clone()]);
+            let velocity_y = lane(&self.velocity_y[lanes.clone()]);
+            let separation_radius_sq = lane(&self.separation_radius_sq[lanes.clone()]);
+            let flock = lane(&self.flock[lanes]);
+
+            let mut distance_squared = [0.0; LANES];
+            for l in 0..LANES {
+                distance_squared[l] = offset_x[l] * offset_x[l] + offset_y[l] * offset_y[l];
+            }
+            let mut weight = [1.0; LANES];
+            if directional {
+                // A neighbour on top of the boid has no direction and is
+                // always seen in full.
+                let mut cos = [0.0; LANES];
+                for l in 0..LANES {
+                    cos[l] = (heading.x * offset_x[l] + heading.y * offset_y[l])
+                        / distance_squared[l].sqrt();
+                }
+                if view_cone {
+                    for l in 0..LANES {
+                        if distance_squared[l] > 0.0 && cos[l] < half_angle_cos {
+                            weight[l] = 0.0;
+                        }
+                    }
+                }
+                if angle_weighting {
+                    // `acos` has no SIMD form; this loop stays scalar.
+                    for l in 0..LANES {
+                        if distance_squared[l] > 0.0 {
+                            let angle = cos[l].clamp(-1.0, 1.0).acos();
+                            weight[l] *=
+                                1.0 - config.angle_weighting * (angle / half_angle).min(1.0);
+                        }
+                    }
+                }
+            }
+            if distance_weighting {
+                for l in 0..LANES {
+                    let distance = distance_squared[l].sqrt();
+                    weight[l] *= 1.0
+                        - config.distance_weighting * (distance / config.follow_radius).min(1.0);
+                }
+            }
+            for l in 0..LANES {
+                let separate = if distance_squared[l] <= separation_radius_sq[l] {
+                    weight[l]
+                } else {
+                    0.0
+                };
+                separation_x[l] -= offset_x[l] * separate;
+                separation_y[l] -= offset_y[l] * separate;
+                separation_weight[l] += separate;
+                let follow = if distance_squared[l] <= follow_radius_sq {
+                    weight[l] * flock[l]
+                } else {
+                    0.0
+                };
+                cohesion_x[l] += offset_x[l] * follow;
+                cohesion_y[l] += offset_y[l] * follow;
+                alignment_x[l] += velocity_x[l] * follow;
+                alignment_y[l] += velocity_y[l] * follow;
+                flock_weight[l] += follow;
+            }
+        }
+
+        let sum = |lanes: [f32; LANES]| lanes.iter().sum::<f32>();
+        NeighborSums {
+            separation: Vec2::new(sum(separation_x), sum(separation_y)),
+            separation_weight: sum(separation_weight),
+            cohesion: Vec2::new(sum(cohesion_x), sum(cohesion_y)),
+            alignment: Vec2::new(sum(alignment_x), sum(alignment_y)),
+            flock_weight: sum(flock_weight),
+        }
+    }
+}
+
+fn lane(values: &[f32]) -> &[f32; LANES] {
+    values
+        .try_into()
+        .expect("candidates are padded to whole lanes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behavior::Neighbor;
+    use crate::boid::Boid;
+    use crate::id::BoidId;
+    use crate::species::Interaction;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    fn boid(position: Vec2, velocity: Vec2, config: &SimulationConfig) -> Boid {
+        Boid {
+            position,
+            velocity,
+            acceleration: Vec2::new(0.0, 0.0),
+            max_speed: config.max_velocity,
+            max_force: config.max_force,
+            radius: config.radius,
+            id: BoidId::from_raw(0, 0),
+            species: 0,
+        }
+    }
+
+    fn assert_close(a: Vec2, b: Vec2) {
+        assert!(
+            (a - b).length() <= 1e-3 * (1.0 + b.length()),
+            "{} != {}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn sums_match_the_scalar_neighbor_sums() {
+        let weighted = SimulationConfig {
+            view_angle: 270.0,
+            angle_weighting: 0.5,
+            distance_weighting: 0.5,
+            ..SimulationConfig::default()
+        };
+        let mut rng = ChaCha8Rng::seed_from_u64(12);
+        let mut candidates = Candidates::default();
+        for config in [SimulationConfig::default(), weighted] {
+            let reach = config.perception_radius();
+            // Every tail length, and a few whole lanes.
+            for count in 0..=3 * LANES + 1 {
+                let velocity =
+                    Vec2::new(rng.gen_range(-100.0..100.0), rng.gen_range(-100.0..100.0));
+                let me = boid(Vec2::new(0.0, 0.0), velocity, &config);
+                let mut neighbors = Vec::new();
+                candidates.clear();
+                for n in 0..count {
+                    // One neighbour right on top of the boid.
+                    let offset = if n == 0 {
+                        Vec2::new(0.0, 0.0)
+                    } else {
+                        Vec2::new(rng.gen_range(-reach..reach), rng.gen_range(-reach..reach))
+                    };
+                    let velocity =
+                        Vec2::new(rng.gen_range(-100.0..100.0), rng.gen_range(-100.0..100.0));
+                    let interaction = if rng.gen_bool(0.7) {
+                        Interaction::Flock
+                    } else {
+                        Interaction::Avoid
+                    };
+                    let separation_radius = match interaction {
+                        Interaction::Flock => config.avoid_radius,
+                        _ => config.follow_radius,
+                    };
+                    candidates.push(
+                        offset,
+                        velocity,
+                        separation_radius * separation_radius,
+                        interaction == Interaction::Flock,
+                    );
+                    let other = boid(offset, velocity, &config);
+                    neighbors.extend(Neighbor::perceive(&me, other, offset, interaction, &config));
+                }
+                let fused = candidates.sums(velocity, &config);
+                let scalar = NeighborSums::from_neighbors(&neighbors, &config);
+                assert_close(fused.separation, scalar.separation);
+                assert_close(fused.cohesion, scalar.cohesion);
+                assert_close(fused.alignment, scalar.alignment);
+                let weights =
+                    |sums: NeighborSums| Vec2::new(sums.separation_weight, sums.flock_weight);
+                assert_close(weights(fused), weights(scalar));
+            }
+        }
+    }
+}
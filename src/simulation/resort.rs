@@ -0,0 +1,154 @@
+//! Re-sorting boids in memory so that boids close together in space are
+//! close together in the arrays, and a neighbour query touches a few cache
+//! lines instead of one per neighbour.
+
+use super::Simulation;
+use glam::Vec2;
+use rayon::prelude::*;
+
+/// The key boids are sorted by. Both work on cells the size of the largest
+/// perception radius, so a cell's boids end up next to each other.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum SortOrder {
+    /// Cells along a Z-order (Morton) curve, which keeps nearby cells
+    /// together in both directions, not only along a row.
+    #[default]
+    Morton,
+    /// Cells row by row, the order `SpatialHashGrid` stores them in.
+    Cell,
+}
+
+/// Re-sort boids by `order` on the first step and every `every` steps after.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Resort {
+    pub order: SortOrder,
+    pub every: u32,
+}
+
+impl Simulation {
+    /// Reorders `boids` by `order` now. `BoidId`s keep pointing at the same
+    /// boids; indices into `boids` do not.
+    pub fn sort_boids(&mut self, order: SortOrder) {
+        match self.pool.clone() {
+            Some(pool) => pool.install(|| self.sort_boids_parallel(order)),
+            None => self.sort_boids_parallel(order),
+        }
+    }
+
+    /// Sorts if `resort` says this step is due.
+    pub(super) fn resort_if_due(&mut self) {
+        let resort = match self.resort {
+            Some(resort) => resort,
+            None => return,
+        };
+        if self.steps_since_resort == 0 {
+            self.sort_boids_parallel(resort.order);
+        }
+        self.steps_since_resort = (self.steps_since_resort + 1) % resort.every.max(1);
+    }
+
+    fn sort_boids_parallel(&mut self, order: SortOrder) {
+        let origin = Vec2::new(self.bounds.x, self.bounds.y);
+        let cell_size = self.species.max_perception_radius();
+        let mut keyed: Vec<(u64, usize)> = self
+            .boids
+            .positions()
+            .par_iter()
+            .enumerate()
+            .map(|(i, &position)| {
+                let (x, y) = cell(position, origin, cell_size);
+                let key = match order {
+                    SortOrder::Morton => spread(x) | spread(y) << 1,
+                    SortOrder::Cell => (y as u64) << 32 | x as u64,
+                };
+                (key, i)
+            })
+            .collect();
+        // Keys tie within a cell; the index breaks the tie, so the result is
+        // the same whatever the thread count.
+        keyed.par_sort_unstable();
+        if keyed.iter().enumerate().all(|(i, &(_, from))| i == from) {
+            return;
+        }
+        let order: Vec<usize> = keyed.into_iter().map(|(_, from)| from).collect();
+        self.boids.permute(&order);
+        for (dense, &id) in self.boids.ids().iter().enumerate() {
+            self.ids.relocate(id, dense);
+        }
+    }
+}
+
+/// Column and row of the cell containing `position`, counted from `origin`
+/// and offset so that cells left of or below it still sort first.
+fn cell(position: Vec2, origin: Vec2, cell_size: f32) -> (u32, u32) {
+    let cell = ((position - origin) / cell_size).floor();
+    let unsigned = |coordinate: f32| (coordinate as i32 as u32) ^ (1 << 31);
+    (unsigned(cell.x), unsigned(cell.y))
+}
+
+/// The bits of `value` moved to the even bit positions of a `u64`.
+fn spread(value: u32) -> u64 {
+    let mut value = value as u64;
+    value = (value | value << 16) & 0x0000_ffff_0000_ffff;
+    value = (value | value << 8) & 0x00ff_00ff_00ff_00ff;
+    value = (value | value << 4) & 0x0f0f_0f0f_0f0f_0f0f;
+    value = (value | value << 2) & 0x3333_3333_3333_3333;
+    (value | value << 1) & 0x5555_5555_5555_5555
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::id::BoidId;
+    use crate::simulation::SimulationBuilder;
+    use crate::spatial_hash::Rectangle;
+
+    fn builder() -> SimulationBuilder {
+        SimulationBuilder::new(Rectangle::new(0.0, 0.0, 400.0, 300.0))
+            .boid_count(300)
+            .seed(7)
+    }
+
+    fn state(simulation: &Simulation) -> Vec<(BoidId, Vec2, Vec2)> {
+        let boids = simulation.boids();
+        let mut state: Vec<_> = (0..boids.len())
+            .map(|i| (boids.ids()[i], boids.positions()[i], boids.velocities()[i]))
+            .collect();
+        state.sort_by_key(|&(id, _, _)| (id.slot(), id.generation()));
+        state
+    }
+
+    #[test]
+    fn sorting_keeps_ids_on_their_boids() {
+        for order in [SortOrder::Morton, SortOrder::Cell] {
+            let mut simulation = builder().build();
+            let before = state(&simulation);
+            simulation.sort_boids(order);
+            assert_eq!(state(&simulation), before);
+            for &(id, position, velocity) in &before {
+                let boid = simulation.get(id).unwrap();
+                assert_eq!((boid.position, boid.velocity), (position, velocity));
+            }
+            let moved = (0..simulation.boid_count())
+                .filter(|&i| simulation.boids().ids()[i] != before[i].0)
+                .count();
+            assert!(moved > 0, "{order:?} left the boids in spawn order");
+        }
+    }
+
+    #[test]
+    fn sorting_does_not_change_the_flight() {
+        let mut plain = builder().build();
+        let mut sorted = builder().resort(SortOrder::Morton, 3).build();
+        for _ in 0..10 {
+            plain.navigate();
+            sorted.navigate();
+        }
+        // Neighbours are summed in a different order, so allow for rounding.
+        for (a, b) in state(&plain).into_iter().zip(state(&sorted)) {
+            assert_eq!(a.0, b.0);
+            assert!(a.1.distance(b.1) < 1e-2, "{a:?} vs {b:?}");
+            assert!(a.2.distance(b.2) < 1e-2, "{a:?} vs {b:?}");
+        }
+    }
+}
@@ -0,0 +1,880 @@
+//! Versioned little-endian binary snapshots of a `Simulation`.
+//!
+//! Layout (version 11): magic, version, seed, RNG state, bounds, species
+//! configs, roles and interaction matrix, boundary mode, integrator and clock,
+//! neighbour mode, requested index kind, re-sort schedule, pointer, predation,
+//! obstacles, id slots, then every boid.
+//! Steering behaviours, the thread pool, step timings and undrained captures
+//! are not saved; a loaded simulation starts with the default flocking rules
+//! on the global rayon pool.
+
+use super::{Resort, Simulation, SortOrder, StepTimings};
+use crate::behavior::{flocking, NeighborMode};
+use crate::boid::{Boid, Boids, BoundaryMode};
+use crate::config::SimulationConfig;
+use crate::hashed_grid::HashedGrid;
+use crate::id::{BoidId, BoidIds};
+use crate::integrator::{self, FixedTimestep, Integrator};
+use crate::obstacle::{Obstacle, Obstacles, Shape, OBSTACLE_CELL_SIZE};
+use crate::pointer::{Pointer, PointerMode};
+use crate::predation::{Predation, PursuitTarget, PREDATOR_FEAR_RADIUS};
+use crate::spatial_hash::Rectangle;
+use crate::spatial_index::{AnyIndex, IndexKind};
+use crate::species::{Interaction, Role, SpeciesId, SpeciesTable};
+use glam::Vec2;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 4] = b"BOID";
+pub const SNAPSHOT_VERSION: u16 = 11;
+// Lengths come from the file, so don't trust them for up-front allocation.
+const PREALLOCATE_LIMIT: usize = 1 << 16;
+/// Most grid cells a loaded snapshot may ask for, at the perception radius,
+/// the obstacle cell size or the fear radius.
+const MAX_GRID_CELLS: f32 = (1 << 24) as f32;
+/// Most catch-up steps per `advance` a loaded snapshot may ask for.
+const MAX_STEPS_LIMIT: u32 = 1 << 10;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    Invalid(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "snapshot i/o failed: {}", err),
+            SnapshotError::BadMagic => write!(f, "not a boid snapshot"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            SnapshotError::Invalid(msg) => write!(f, "invalid snapshot: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        SnapshotError::Io(err)
+    }
+}
+
+impl Simulation {
+    /// Writes the full simulation state so `load_snapshot` can resume the run
+    /// exactly where it left off.
+    pub fn save_snapshot<W: Write>(&self, writer: W) -> Result<(), SnapshotError> {
+        let mut out = Writer(writer);
+        out.bytes(MAGIC)?;
+        out.u16(SNAPSHOT_VERSION)?;
+        out.u64(self.seed)?;
+        out.bytes(&self.rng.get_seed())?;
+        out.u64(self.rng.get_stream())?;
+        out.u128(self.rng.get_word_pos())?;
+
+        out.rect(self.bounds)?;
+        let species = &self.species;
+        out.len(species.len())?;
+        for (i, config) in species.configs().iter().enumerate() {
+            out.config(config)?;
+            out.u8(match species.role(i as SpeciesId) {
+                Role::Prey => 0,
+                Role::Predator => 1,
+            })?;
+        }
+        for a in 0..species.len() as SpeciesId {
+            for b in 0..species.len() as SpeciesId {
+                out.u8(match species.interaction(a, b) {
+                    Interaction::Ignore => 0,
+                    Interaction::Avoid => 1,
+                    Interaction::Flock => 2,
+                })?;
+            }
+        }
+
+        match self.boundary_mode {
+            BoundaryMode::Wrap => out.u8(0)?,
+            BoundaryMode::Reflect => out.u8(1)?,
+            BoundaryMode::SoftWalls { margin, strength } => {
+                out.u8(2)?;
+                out.f32(margin)?;
+                out.f32(strength)?;
+            }
+            BoundaryMode::Unbounded => out.u8(3)?,
+        }
+
+        out.u8(match self.integrator {
+            Integrator::ExplicitEuler => 0,
+            Integrator::SemiImplicitEuler => 1,
+            Integrator::VelocityVerlet => 2,
+        })?;
+        out.f32(self.time_step.step)?;
+        out.u32(self.time_step.max_steps)?;
+        out.f32(self.time_step.accumulator())?;
+
+        match self.neighbor_mode {
+            NeighborMode::Metric => out.u8(0)?,
+            NeighborMode::Topological { k } => {
+                out.u8(1)?;
+                out.len(k)?;
+            }
+        }
+        out.u8(match self.index_kind {
+            IndexKind::Grid => 0,
+            IndexKind::QuadTree => 1,
+            IndexKind::Hashed => 2,
+        })?;
+        match self.resort {
+            None => out.u8(0)?,
+            Some(resort) => {
+                out.u8(match resort.order {
+                    SortOrder::Morton => 1,
+                    SortOrder::Cell => 2,
+                })?;
+                out.u32(resort.every)?;
+            }
+        }
+        out.u32(self.steps_since_resort)?;
+
+        let pointer = &self.pointer;
+        out.vec2(pointer.position)?;
+        out.u8(match pointer.mode {
+            PointerMode::Off => 0,
+            PointerMode::Attract => 1,
+            PointerMode::Repel => 2,
+            PointerMode::Predator => 3,
+        })?;
+        out.f32(pointer.radius)?;
+        out.f32(pointer.strength)?;
+        out.f32(pointer.falloff)?;
+
+        let predation = &self.predation;
+        out.f32(predation.fear_radius)?;
+        out.f32(predation.flee_strength)?;
+        out.f32(predation.hunt_radius)?;
+        out.u8(match predation.target {
+            PursuitTarget::Nearest => 0,
+            PursuitTarget::Densest => 1,
+        })?;
+        out.f32(predation.capture_radius)?;
+
+        let obstacles = &self.obstacles;
+        out.f32(obstacles.lookahead)?;
+        out.f32(obstacles.margin)?;
+        out.f32(obstacles.strength)?;
+        out.len(obstacles.slots().len())?;
+        for slot in obstacles.slots() {
+            match slot {
+                Some(obstacle) => {
+                    out.u8(1)?;
+                    out.obstacle(obstacle)?;
+                }
+                None => out.u8(0)?,
+            }
+        }
+
+        let generations: Vec<u32> = self.ids.generations().collect();
+        out.len(generations.len())?;
+        for generation in generations {
+            out.u32(generation)?;
+        }
+        let free = self.ids.free_slots();
+        out.len(free.len())?;
+        for &slot in free {
+            out.u32(slot)?;
+        }
+
+        out.len(self.boids.len())?;
+        for boid in self.iter() {
+            out.vec2(boid.position)?;
+            out.vec2(boid.velocity)?;
+            out.vec2(boid.acceleration)?;
+            out.u32(boid.id.slot())?;
+            out.u32(boid.id.generation())?;
+            out.u16(boid.species)?;
+        }
+        out.0.flush()?;
+        Ok(())
+    }
+
+    pub fn load_snapshot<R: Read>(reader: R) -> Result<Simulation, SnapshotError> {
+        let mut input = Reader(reader);
+        let mut magic = [0; 4];
+        input.bytes(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = input.u16()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let seed = input.u64()?;
+        let mut rng_seed = [0; 32];
+        input.bytes(&mut rng_seed)?;
+        let mut rng = ChaCha8Rng::from_seed(rng_seed);
+        rng.set_stream(input.u64()?);
+        rng.set_word_pos(input.u128()?);
+
+        let bounds = input.rect()?;
+        let finite = [bounds.x, bounds.y, bounds.width, bounds.height]
+            .iter()
+            .all(|value| value.is_finite());
+        if !finite || bounds.width <= 0.0 || bounds.height <= 0.0 {
+            return Err(SnapshotError::Invalid(format!("bounds {:?}", bounds)));
+        }
+        let species_count = input.len()?;
+        if species_count == 0 || species_count > SpeciesId::MAX as usize {
+            return Err(SnapshotError::Invalid(format!("{} species", species_count)));
+        }
+        let mut species = SpeciesTable::new(input.config()?);
+        species.set_role(0, input.role()?);
+        for _ in 1..species_count {
+            species.add_with_role(input.config()?, input.role()?);
+        }
+        for a in 0..species_count as SpeciesId {
+            for b in 0..species_count as SpeciesId {
+                let interaction = match input.u8()? {
+                    0 => Interaction::Ignore,
+                    1 => Interaction::Avoid,
+                    2 => Interaction::Flock,
+                    tag => return Err(SnapshotError::Invalid(format!("interaction {}", tag))),
+                };
+                species.set_interaction(a, b, interaction);
+            }
+        }
+        check_grid(
+            bounds,
+            species.max_perception_radius().min(OBSTACLE_CELL_SIZE),
+        )?;
+
+        let boundary_mode = match input.u8()? {
+            0 => BoundaryMode::Wrap,
+            1 => BoundaryMode::Reflect,
+            2 => {
+                let (margin, strength) = (input.f32()?, input.f32()?);
+                check_parameters(&[("wall margin", margin), ("wall strength", strength)])?;
+                BoundaryMode::SoftWalls { margin, strength }
+            }
+            3 => BoundaryMode::Unbounded,
+            tag => return Err(SnapshotError::Invalid(format!("boundary mode {}", tag))),
+        };
+
+        let integrator = match input.u8()? {
+            0 => Integrator::ExplicitEuler,
+            1 => Integrator::SemiImplicitEuler,
+            2 => Integrator::VelocityVerlet,
+            tag => return Err(SnapshotError::Invalid(format!("integrator {}", tag))),
+        };
+        let mut time_step = FixedTimestep::new(input.f32()?);
+        time_step.max_steps = input.u32()?;
+        time_step.set_accumulator(input.f32()?);
+        if !integrator::is_valid_step(time_step.step) {
+            return Err(SnapshotError::Invalid(format!(
+                "time step {}",
+                time_step.step
+            )));
+        }
+        if time_step.max_steps == 0 || time_step.max_steps > MAX_STEPS_LIMIT {
+            return Err(SnapshotError::Invalid(format!(
+                "{} steps per advance",
+                time_step.max_steps
+            )));
+        }
+        // `advance` never leaves more than one step's worth behind.
+        if !(0.0..=time_step.step).contains(&time_step.accumulator()) {
+            return Err(SnapshotError::Invalid(format!(
+                "accumulated time {}",
+                time_step.accumulator()
+            )));
+        }
+
+        let neighbor_mode = match input.u8()? {
+            0 => NeighborMode::Metric,
+            1 => NeighborMode::Topological { k: input.len()? },
+            tag => return Err(SnapshotError::Invalid(format!("neighbor mode {}", tag))),
+        };
+        let index_kind = match input.u8()? {
+            0 => IndexKind::Grid,
+            1 => IndexKind::QuadTree,
+            2 => IndexKind::Hashed,
+            tag => return Err(SnapshotError::Invalid(format!("index kind {}", tag))),
+        };
+        let sort_order = match input.u8()? {
+            0 => None,
+            1 => Some(SortOrder::Morton),
+            2 => Some(SortOrder::Cell),
+            tag => return Err(SnapshotError::Invalid(format!("sort order {}", tag))),
+        };
+        let resort = match sort_order {
+            Some(order) => Some(Resort {
+                order,
+                every: input.u32()?,
+            }),
+            None => None,
+        };
+        let steps_since_resort = input.u32()?;
+
+        let position = input.vec2()?;
+        let mode = match input.u8()? {
+            0 => PointerMode::Off,
+            1 => PointerMode::Attract,
+            2 => PointerMode::Repel,
+            3 => PointerMode::Predator,
+            tag => return Err(SnapshotError::Invalid(format!("pointer mode {}", tag))),
+        };
+        let pointer = Pointer {
+            position,
+            mode,
+            radius: input.f32()?,
+            strength: input.f32()?,
+            falloff: input.f32()?,
+        };
+        if !pointer.position.is_finite() {
+            return Err(SnapshotError::Invalid(format!(
+                "pointer position {}",
+                pointer.position
+            )));
+        }
+        check_parameters(&[
+            ("pointer radius", pointer.radius),
+            ("pointer strength", pointer.strength),
+            ("pointer falloff", pointer.falloff),
+        ])?;
+
+        let fear_radius = input.f32()?;
+        let flee_strength = input.f32()?;
+        let hunt_radius = input.f32()?;
+        let target = match input.u8()? {
+            0 => PursuitTarget::Nearest,
+            1 => PursuitTarget::Densest,
+            tag => return Err(SnapshotError::Invalid(format!("pursuit target {}", tag))),
+        };
+        let predation = Predation {
+            fear_radius,
+            flee_strength,
+            hunt_radius,
+            target,
+            capture_radius: input.f32()?,
+        };
+        check_parameters(&[
+            ("fear radius", predation.fear_radius),
+            ("flee strength", predation.flee_strength),
+            ("hunt radius", predation.hunt_radius),
+            ("capture radius", predation.capture_radius),
+        ])?;
+        // Prey look up predators in a grid of cells this size.
+        if predation.fear_radius > 0.0 {
+            check_grid(bounds, predation.fear_radius)?;
+        }
+
+        let lookahead = input.f32()?;
+        let margin = input.f32()?;
+        let strength = input.f32()?;
+        check_parameters(&[
+            ("obstacle lookahead", lookahead),
+            ("obstacle margin", margin),
+            ("obstacle strength", strength),
+        ])?;
+        let obstacle_count = input.len()?;
+        let mut slots = Vec::with_capacity(obstacle_count.min(PREALLOCATE_LIMIT));
+        for _ in 0..obstacle_count {
+            slots.push(match input.u8()? {
+                0 => None,
+                1 => Some(input.obstacle()?),
+                tag => return Err(SnapshotError::Invalid(format!("obstacle slot {}", tag))),
+            });
+        }
+        let mut obstacles = Obstacles::from_slots(bounds, slots);
+        obstacles.lookahead = lookahead;
+        obstacles.margin = margin;
+        obstacles.strength = strength;
+
+        let slot_count = input.len()?;
+        let mut generations = Vec::with_capacity(slot_count.min(PREALLOCATE_LIMIT));
+        for _ in 0..slot_count {
+            generations.push(input.u32()?);
+        }
+        let free_count = input.len()?;
+        let mut free = Vec::with_capacity(free_count.min(PREALLOCATE_LIMIT));
+        for _ in 0..free_count {
+            free.push(input.u32()?);
+        }
+
+        let boid_count = input.len()?;
+        let mut boids = Boids::default();
+        boids.reserve(boid_count.min(PREALLOCATE_LIMIT));
+        for _ in 0..boid_count {
+            let position = input.vec2()?;
+            let velocity = input.vec2()?;
+            let acceleration = input.vec2()?;
+            let id = BoidId::from_raw(input.u32()?, input.u32()?);
+            let kind = input.u16()?;
+            if kind as usize >= species_count {
+                return Err(SnapshotError::Invalid("boid of unknown species".into()));
+            }
+            let config = species.config(kind);
+            boids.push(&Boid {
+                position,
+                velocity,
+                acceleration,
+                max_speed: config.max_velocity,
+                max_force: config.max_force,
+                radius: config.radius,
+                id,
+                species: kind,
+            });
+        }
+        let ids = BoidIds::from_parts(generations, free, boids.ids().iter().copied())
+            .ok_or_else(|| SnapshotError::Invalid("boid ids do not match id slots".into()))?;
+
+        Ok(Simulation {
+            boids,
+            ids,
+            bounds,
+            index: AnyIndex::new(
+                boundary_mode.index_kind(index_kind),
+                bounds,
+                species.max_perception_radius(),
+            ),
+            index_kind,
+            seed,
+            rng,
+            pool: None,
+            species,
+            behaviors: flocking(),
+            boundary_mode,
+            predation,
+            predator_grid: HashedGrid::covering(bounds, PREDATOR_FEAR_RADIUS),
+            captures: Vec::new(),
+            obstacles,
+            neighbor_mode,
+            resort,
+            steps_since_resort,
+            integrator,
+            time_step,
+            timings: StepTimings::default(),
+            pointer,
+        })
+    }
+}
+
+/// Fails unless every named value is finite and not negative.
+fn check_parameters(parameters: &[(&str, f32)]) -> Result<(), SnapshotError> {
+    for &(name, value) in parameters {
+        if !value.is_finite() || value < 0.0 {
+            return Err(SnapshotError::Invalid(format!("{} {}", name, value)));
+        }
+    }
+    Ok(())
+}
+
+/// Fails if a grid over `bounds` with cells of `cell_size` would have more
+/// than `MAX_GRID_CELLS` cells.
+fn check_grid(bounds: Rectangle, cell_size: f32) -> Result<(), SnapshotError> {
+    if (bounds.width / cell_size).ceil() * (bounds.height / cell_size).ceil() > MAX_GRID_CELLS {
+        return Err(SnapshotError::Invalid(format!(
+            "bounds {:?} too large for cells of {}",
+            bounds, cell_size
+        )));
+    }
+    Ok(())
+}
+
+struct Writer<W>(W);
+
+impl<W: Write> Writer<W> {
+    fn bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.0.write_all(bytes)
+    }
+
+    fn u8(&mut self, value: u8) -> io::Result<()> {
+        self.bytes(&[value])
+    }
+
+    fn u16(&mut self, value: u16) -> io::Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn u32(&mut self, value: u32) -> io::Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn u64(&mut self, value: u64) -> io::Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn u128(&mut self, value: u128) -> io::Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn len(&mut self, len: usize) -> io::Result<()> {
+        self.u32(len as u32)
+    }
+
+    fn f32(&mut self, value: f32) -> io::Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn vec2(&mut self, value: Vec2) -> io::Result<()> {
+        self.f32(value.x)?;
+        self.f32(value.y)
+    }
+
+    fn rect(&mut self, rect: Rectangle) -> io::Result<()> {
+        self.f32(rect.x)?;
+        self.f32(rect.y)?;
+        self.f32(rect.width)?;
+        self.f32(rect.height)
+    }
+
+    fn config(&mut self, config: &SimulationConfig) -> io::Result<()> {
+        for value in [
+            config.radius,
+            config.max_velocity,
+            config.max_force,
+            config.avoid_radius,
+            config.follow_radius,
+            config.seperation_factor,
+            config.cohesion_factor,
+            config.alignment_factor,
+            config.view_angle,
+            config.distance_weighting,
+            config.angle_weighting,
+        ] {
+            self.f32(value)?;
+        }
+        Ok(())
+    }
+
+    fn obstacle(&mut self, obstacle: &Obstacle) -> io::Result<()> {
+        self.vec2(obstacle.position)?;
+        self.f32(obstacle.rotation)?;
+        match &obstacle.shape {
+            Shape::Circle { radius } => {
+                self.u8(0)?;
+                self.f32(*radius)
+            }
+            Shape::Rect { half_extents } => {
+                self.u8(1)?;
+                self.vec2(*half_extents)
+            }
+            Shape::Polyline { points, closed } => {
+                self.u8(2)?;
+                self.u8(*closed as u8)?;
+                self.len(points.len())?;
+                for &point in points {
+                    self.vec2(point)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+struct Reader<R>(R);
+
+impl<R: Read> Reader<R> {
+    fn bytes(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.0.read_exact(buf)
+    }
+
+    fn array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.bytes(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn u128(&mut self) -> io::Result<u128> {
+        Ok(u128::from_le_bytes(self.array()?))
+    }
+
+    fn len(&mut self) -> io::Result<usize> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn f32(&mut self) -> io::Result<f32> {
+        Ok(f32::from_le_bytes(self.array()?))
+    }
+
+    fn vec2(&mut self) -> io::Result<Vec2> {
+        Ok(Vec2::new(self.f32()?, self.f32()?))
+    }
+
+    fn rect(&mut self) -> io::Result<Rectangle> {
+        Ok(Rectangle::new(
+            self.f32()?,
+            self.f32()?,
+            self.f32()?,
+            self.f32()?,
+        ))
+    }
+
+    fn config(&mut self) -> Result<SimulationConfig, SnapshotError> {
+        let config = SimulationConfig {
+            radius: self.f32()?,
+            max_velocity: self.f32()?,
+            max_force: self.f32()?,
+            avoid_radius: self.f32()?,
+            follow_radius: self.f32()?,
+            seperation_factor: self.f32()?,
+            cohesion_factor: self.f32()?,
+            alignment_factor: self.f32()?,
+            view_angle: self.f32()?,
+            distance_weighting: self.f32()?,
+            angle_weighting: self.f32()?,
+        };
+        config
+            .validate()
+            .map_err(|err| SnapshotError::Invalid(err.to_string()))?;
+        Ok(config)
+    }
+
+    fn role(&mut self) -> Result<Role, SnapshotError> {
+        match self.u8()? {
+            0 => Ok(Role::Prey),
+            1 => Ok(Role::Predator),
+            tag => Err(SnapshotError::Invalid(format!("role {}", tag))),
+        }
+    }
+
+    fn obstacle(&mut self) -> Result<Obstacle, SnapshotError> {
+        let position = self.vec2()?;
+        let rotation = self.f32()?;
+        let shape = match self.u8()? {
+            0 => Shape::Circle {
+                radius: self.f32()?,
+            },
+            1 => Shape::Rect {
+                half_extents: self.vec2()?,
+            },
+            2 => {
+                let closed = self.u8()? != 0;
+                let count = self.len()?;
+                let mut points = Vec::with_capacity(count.min(PREALLOCATE_LIMIT));
+                for _ in 0..count {
+                    points.push(self.vec2()?);
+                }
+                Shape::Polyline { points, closed }
+            }
+            tag => return Err(SnapshotError::Invalid(format!("obstacle shape {}", tag))),
+        };
+        Ok(Obstacle {
+            position,
+            rotation,
+            shape,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::SimulationBuilder;
+    use super::*;
+
+    fn simulation() -> Simulation {
+        let bounds = Rectangle::new(-100.0, -100.0, 200.0, 200.0);
+        let mut simulation = SimulationBuilder::new(bounds)
+            .boid_count(200)
+            .seed(3)
+            .predators(SimulationConfig::default(), 2)
+            .obstacle(Obstacle::circle(Vec2::new(30.0, 0.0), 15.0))
+            .resort(SortOrder::Morton, 7)
+            .build();
+        for _ in 0..10 {
+            simulation.navigate();
+        }
+        simulation
+    }
+
+    fn round_trip(simulation: &Simulation) -> Result<Simulation, SnapshotError> {
+        let mut bytes = Vec::new();
+        simulation.save_snapshot(&mut bytes)?;
+        Simulation::load_snapshot(bytes.as_slice())
+    }
+
+    fn bits(simulation: &Simulation) -> Vec<(BoidId, [u32; 4])> {
+        let boids = simulation.boids();
+        boids
+            .ids()
+            .iter()
+            .zip(boids.positions().iter().zip(boids.velocities()))
+            .map(|(&id, (p, v))| {
+                (
+                    id,
+                    [p.x.to_bits(), p.y.to_bits(), v.x.to_bits(), v.y.to_bits()],
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn loaded_simulation_resumes_where_the_saved_one_left_off() {
+        let mut original = simulation();
+        let mut loaded = round_trip(&original).unwrap();
+        assert_eq!(bits(&loaded), bits(&original));
+        assert_eq!(loaded.time_step, original.time_step);
+        for _ in 0..30 {
+            original.navigate();
+            loaded.navigate();
+        }
+        assert_eq!(bits(&loaded), bits(&original));
+    }
+
+    fn assert_invalid(simulation: &Simulation) {
+        match round_trip(simulation) {
+            Err(SnapshotError::Invalid(_)) => {}
+            Err(err) => panic!("expected an invalid snapshot, got {}", err),
+            Ok(_) => panic!("expected an invalid snapshot, got a simulation"),
+        }
+    }
+
+    #[test]
+    fn snapshots_keep_the_requested_index_kind() {
+        let mut original = simulation();
+        original.set_index_kind(IndexKind::QuadTree);
+        original.boundary_mode = BoundaryMode::Unbounded;
+        original.navigate();
+        let mut loaded = round_trip(&original).unwrap();
+        assert_eq!(loaded.index.kind(), IndexKind::Hashed);
+        assert_eq!(loaded.requested_index_kind(), IndexKind::QuadTree);
+        loaded.boundary_mode = BoundaryMode::Wrap;
+        loaded.navigate();
+        assert_eq!(loaded.index.kind(), IndexKind::QuadTree);
+    }
+
+    #[test]
+    fn bad_time_steps_are_rejected() {
+        for step in [0.0, -1.0 / 60.0, f32::NAN, f32::INFINITY] {
+            let mut simulation = simulation();
+            simulation.time_step.step = step;
+            assert_invalid(&simulation);
+        }
+        for max_steps in [0, u32::MAX] {
+            let mut simulation = simulation();
+            simulation.time_step.max_steps = max_steps;
+            assert_invalid(&simulation);
+        }
+        for accumulator in [-1.0, 10.0, f32::NAN] {
+            let mut simulation = simulation();
+            simulation.time_step.set_accumulator(accumulator);
+            assert_invalid(&simulation);
+        }
+    }
+
+    #[test]
+    fn bad_bounds_are_rejected() {
+        for bounds in [
+            Rectangle::new(0.0, 0.0, 0.0, 100.0),
+            Rectangle::new(0.0, 0.0, 100.0, -100.0),
+            Rectangle::new(f32::NAN, 0.0, 100.0, 100.0),
+            Rectangle::new(0.0, 0.0, f32::INFINITY, 100.0),
+            Rectangle::new(0.0, 0.0, 1e9, 1e9),
+        ] {
+            let mut simulation = simulation();
+            simulation.bounds = bounds;
+            assert_invalid(&simulation);
+        }
+    }
+
+    /// Saves a flock with slots 3 and 7 freed, and returns the bytes and
+    /// where the free list starts.
+    fn with_free_slots() -> (Vec<u8>, usize) {
+        let bounds = Rectangle::new(-100.0, -100.0, 200.0, 200.0);
+        let mut simulation = SimulationBuilder::new(bounds)
+            .boid_count(10)
+            .seed(4)
+            .build();
+        for slot in [3, 7] {
+            let id = simulation
+                .boids()
+                .ids()
+                .iter()
+                .copied()
+                .find(|id| id.slot() == slot);
+            simulation.despawn(id.unwrap()).unwrap();
+        }
+        let mut bytes = Vec::new();
+        simulation.save_snapshot(&mut bytes).unwrap();
+        let free: Vec<u8> = [2u32, 3, 7, 8]
+            .iter()
+            .flat_map(|n| n.to_le_bytes())
+            .collect();
+        let at = bytes
+            .windows(free.len())
+            .position(|window| window == free.as_slice())
+            .unwrap();
+        (bytes, at)
+    }
+
+    fn load(bytes: &[u8]) -> Result<Simulation, SnapshotError> {
+        Simulation::load_snapshot(bytes)
+    }
+
+    #[test]
+    fn corrupt_free_lists_are_rejected() {
+        let (bytes, at) = with_free_slots();
+        assert!(load(&bytes).is_ok());
+
+        // The same slot freed twice would be handed out twice.
+        let mut duplicated = bytes.clone();
+        duplicated[at + 8..at + 12].copy_from_slice(&3u32.to_le_bytes());
+        assert!(matches!(load(&duplicated), Err(SnapshotError::Invalid(_))));
+
+        // A dead slot missing from the free list would leak.
+        let mut missing = bytes.clone();
+        missing[at..at + 4].copy_from_slice(&1u32.to_le_bytes());
+        missing.drain(at + 8..at + 12);
+        assert!(matches!(load(&missing), Err(SnapshotError::Invalid(_))));
+
+        // A live slot listed as free.
+        let mut live = bytes;
+        live[at + 8..at + 12].copy_from_slice(&0u32.to_le_bytes());
+        assert!(matches!(load(&live), Err(SnapshotError::Invalid(_))));
+    }
+
+    #[test]
+    fn bad_parameters_are_rejected() {
+        let changes: [fn(&mut Simulation); 8] = [
+            |simulation| simulation.predation.fear_radius = 1e-3,
+            |simulation| simulation.predation.fear_radius = f32::NAN,
+            |simulation| simulation.predation.hunt_radius = -1.0,
+            |simulation| simulation.predation.capture_radius = f32::INFINITY,
+            |simulation| simulation.pointer.falloff = -2.0,
+            |simulation| simulation.pointer.position = Vec2::new(f32::NAN, 0.0),
+            |simulation| simulation.obstacles_mut().lookahead = f32::NAN,
+            |simulation| {
+                simulation.boundary_mode = BoundaryMode::SoftWalls {
+                    margin: -5.0,
+                    strength: 1.0,
+                }
+            },
+        ];
+        for change in changes {
+            let mut simulation = simulation();
+            change(&mut simulation);
+            assert_invalid(&simulation);
+        }
+    }
+}
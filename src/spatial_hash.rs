@@ -1,4 +1,10 @@
-use nannou::prelude::*;
+use crate::spatial_index::{push_nearest, SpatialIndex};
+use glam::Vec2;
+use rayon::prelude::*;
+use std::ops::Range;
+
+/// `rebuild` sorts at least this many items per rayon task.
+const MIN_ITEMS_PER_TASK: usize = 1 << 14;
 
 #[derive(Debug, Copy, Clone)]
 pub struct Rectangle {
@@ -25,6 +31,18 @@ impl Rectangle {
             || rect.y + rect.height < self.y)
     }
 
+    /// Squared distance from `point` to the nearest point of the rectangle;
+    /// zero if the point is inside.
+    pub fn distance_squared_to(&self, point: Vec2) -> f32 {
+        let dx = (self.x - point.x)
+            .max(point.x - (self.x + self.width))
+            .max(0.0);
+        let dy = (self.y - point.y)
+            .max(point.y - (self.y + self.height))
+            .max(0.0);
+        dx * dx + dy * dy
+    }
+
     pub fn point_inside_rect(&self, point: Vec2) -> bool {
         self.x <= point.x
             && self.y <= point.y
@@ -33,38 +51,238 @@ impl Rectangle {
     }
 }
 
+/// Items sorted into numbered cells, stored compactly: the items of every
+/// cell sit next to each other in one array, cell after cell, along with
+/// their positions, so queries never touch the caller's data to read a
+/// position.
+pub(crate) struct CellLists {
+    /// Cell `c` holds `indices[cell_start[c]..cell_start[c + 1]]`.
+    cell_start: Vec<usize>,
+    indices: Vec<usize>,
+    positions: Vec<Vec2>,
+    /// Per-task buffers reused by `rebuild`.
+    tasks: Vec<SortTask>,
+}
+
+impl Default for CellLists {
+    fn default() -> Self {
+        CellLists::new(0)
+    }
+}
+
+/// One task's share of a `rebuild`: its items counting-sorted by cell on
+/// their own.
+#[derive(Default)]
+struct SortTask {
+    cells: Vec<usize>,
+    cell_start: Vec<usize>,
+    indices: Vec<usize>,
+    positions: Vec<Vec2>,
+}
+
+impl SortTask {
+    /// Counting sort of items `range` into this task's buffers.
+    fn sort<P, C>(&mut self, num_cells: usize, range: Range<usize>, position: &P, cell_of: &C)
+    where
+        P: Fn(usize) -> Vec2,
+        C: Fn(Vec2) -> Option<usize>,
+    {
+        self.cells.clear();
+        self.cells.extend(
+            range
+                .clone()
+                .map(|i| cell_of(position(i)).unwrap_or(num_cells)),
+        );
+        self.cell_start.clear();
+        self.cell_start.resize(num_cells + 2, 0);
+        for &cell in &self.cells {
+            self.cell_start[cell + 1] += 1;
+        }
+        for cell in 0..=num_cells {
+            self.cell_start[cell + 1] += self.cell_start[cell];
+        }
+        // Items without a cell are counted in an extra last cell and dropped.
+        let len = self.cell_start[num_cells];
+        self.cell_start.truncate(num_cells + 1);
+        self.indices.clear();
+        self.indices.resize(len, 0);
+        self.positions.clear();
+        self.positions.resize(len, Vec2::new(0.0, 0.0));
+        let mut next = self.cell_start.clone();
+        for (i, &cell) in range.zip(&self.cells) {
+            if cell < num_cells {
+                let at = next[cell];
+                next[cell] += 1;
+                self.indices[at] = i;
+                self.positions[at] = position(i);
+            }
+        }
+    }
+
+    fn count(&self, cell: usize) -> usize {
+        self.cell_start[cell + 1] - self.cell_start[cell]
+    }
+}
+
+impl CellLists {
+    pub(crate) fn new(num_cells: usize) -> Self {
+        CellLists {
+            cell_start: vec![0; num_cells + 1],
+            indices: Vec::new(),
+            positions: Vec::new(),
+            tasks: Vec::new(),
+        }
+    }
+
+    fn num_cells(&self) -> usize {
+        self.cell_start.len() - 1
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.cell_start.iter_mut().for_each(|start| *start = 0);
+        self.indices.clear();
+        self.positions.clear();
+    }
+
+    /// Replaces the contents with items `0..count`, item `i` at `position(i)`
+    /// in cell `cell_of(position(i))`; items without a cell are dropped.
+    /// Items are sorted into cells in parallel: each task counting-sorts a
+    /// contiguous run of items, then bands of cells are filled from every
+    /// task in item order, so cells list their items in ascending order
+    /// whatever the thread count.
+    pub(crate) fn rebuild<P, C>(&mut self, count: usize, position: P, cell_of: C)
+    where
+        P: Fn(usize) -> Vec2 + Sync,
+        C: Fn(Vec2) -> Option<usize> + Sync,
+    {
+        let num_cells = self.num_cells();
+        let num_tasks = rayon::current_num_threads()
+            .min(count.div_ceil(MIN_ITEMS_PER_TASK))
+            .max(1);
+        let per_task = count.div_ceil(num_tasks);
+        let mut tasks = std::mem::take(&mut self.tasks);
+        tasks.resize_with(num_tasks, SortTask::default);
+        tasks.par_iter_mut().enumerate().for_each(|(t, task)| {
+            let start = (t * per_task).min(count);
+            let end = (start + per_task).min(count);
+            task.sort(num_cells, start..end, &position, &cell_of);
+        });
+
+        if num_tasks == 1 {
+            // Nothing to merge; take the task's arrays as they are.
+            let task = &mut tasks[0];
+            std::mem::swap(&mut self.cell_start, &mut task.cell_start);
+            std::mem::swap(&mut self.indices, &mut task.indices);
+            std::mem::swap(&mut self.positions, &mut task.positions);
+            self.tasks = tasks;
+            return;
+        }
+
+        for cell in 0..num_cells {
+            let count: usize = tasks.iter().map(|task| task.count(cell)).sum();
+            self.cell_start[cell + 1] = self.cell_start[cell] + count;
+        }
+        let len = self.cell_start[num_cells];
+        self.indices.resize(len, 0);
+        self.positions.resize(len, Vec2::new(0.0, 0.0));
+
+        // Split the output into bands of whole cells, one per task.
+        let cells_per_band = num_cells.div_ceil(num_tasks);
+        let mut bands = Vec::with_capacity(num_tasks);
+        let mut indices = &mut self.indices[..];
+        let mut positions = &mut self.positions[..];
+        for band_start in (0..num_cells).step_by(cells_per_band) {
+            let band_end = (band_start + cells_per_band).min(num_cells);
+            let band_len = self.cell_start[band_end] - self.cell_start[band_start];
+            let (band_indices, rest_indices) = indices.split_at_mut(band_len);
+            let (band_positions, rest_positions) = positions.split_at_mut(band_len);
+            indices = rest_indices;
+            positions = rest_positions;
+            bands.push((band_start..band_end, band_indices, band_positions));
+        }
+        bands
+            .into_par_iter()
+            .for_each(|(cells, band_indices, band_positions)| {
+                let mut at = 0;
+                for cell in cells {
+                    for task in &tasks {
+                        let range = task.cell_start[cell]..task.cell_start[cell + 1];
+                        let end = at + range.len();
+                        band_indices[at..end].copy_from_slice(&task.indices[range.clone()]);
+                        band_positions[at..end].copy_from_slice(&task.positions[range]);
+                        at = end;
+                    }
+                }
+            });
+        self.tasks = tasks;
+    }
+
+    /// Adds one item to the end of `cell`, shifting everything after it.
+    /// Fine for a handful of items; use `rebuild` for many.
+    pub(crate) fn insert(&mut self, cell: usize, position: Vec2, index: usize) {
+        let at = self.cell_start[cell + 1];
+        self.indices.insert(at, index);
+        self.positions.insert(at, position);
+        for start in &mut self.cell_start[cell + 1..] {
+            *start += 1;
+        }
+    }
+
+    /// The indices and positions of the items in `cell`.
+    pub(crate) fn cell(&self, cell: usize) -> (&[usize], &[Vec2]) {
+        let range = self.cell_start[cell]..self.cell_start[cell + 1];
+        (&self.indices[range.clone()], &self.positions[range])
+    }
+
+    /// The indices and positions of every item, cell by cell.
+    pub(crate) fn all(&self) -> (&[usize], &[Vec2]) {
+        (&self.indices, &self.positions)
+    }
+}
+
+/// A uniform grid over `bounds`. Positions outside it are dropped; use a
+/// `HashedGrid` for an unbounded plane.
 pub struct SpatialHashGrid {
     cell_size: f32,
     inv_cell_size: f32,
     bounds: Rectangle,
-    cells: Vec<Vec<usize>>,
     grid_width: usize,
     grid_height: usize,
+    cells: CellLists,
 }
 
 impl SpatialHashGrid {
     pub fn new(bounds: Rectangle, cell_size: f32) -> Self {
         let grid_width = (bounds.width / cell_size).ceil().max(1.0) as usize;
         let grid_height = (bounds.height / cell_size).ceil().max(1.0) as usize;
-        let num_cells = grid_width * grid_height;
-        let mut cells = Vec::with_capacity(num_cells);
-        for _ in 0..num_cells {
-            cells.push(Vec::new());
-        }
         SpatialHashGrid {
             cell_size,
             inv_cell_size: 1.0 / cell_size,
             bounds,
-            cells,
             grid_width,
             grid_height,
+            cells: CellLists::new(grid_width * grid_height),
         }
     }
 
+    pub fn cell_size(&self) -> f32 {
+        self.cell_size
+    }
+
     pub fn clear(&mut self) {
-        for cell in &mut self.cells {
-            cell.clear();
-        }
+        self.cells.clear();
+    }
+
+    /// Replaces the contents with items `0..count`, item `i` at
+    /// `position(i)`, sorting them into cells in parallel.
+    pub fn rebuild<P>(&mut self, count: usize, position: P)
+    where
+        P: Fn(usize) -> Vec2 + Sync,
+    {
+        // `cell_of` borrows the whole grid, so sort into the lists detached.
+        let mut cells = std::mem::take(&mut self.cells);
+        cells.rebuild(count, position, |position| self.cell_of(position));
+        self.cells = cells;
     }
 
     fn cell_coords(&self, x: f32, y: f32) -> (isize, isize) {
@@ -80,32 +298,371 @@ impl SpatialHashGrid {
         Some((cy as usize) * self.grid_width + (cx as usize))
     }
 
-    pub fn insert(&mut self, position: Vec2, index: usize) {
+    fn cell_of(&self, position: Vec2) -> Option<usize> {
         let (cx, cy) = self.cell_coords(position.x, position.y);
-        if let Some(idx) = self.cell_index(cx, cy) {
-            self.cells[idx].push(index);
+        self.cell_index(cx, cy)
+    }
+
+    /// Adds one item to the end of its cell, shifting everything after it.
+    /// Fine for a handful of items; use `rebuild` for many.
+    pub fn insert(&mut self, position: Vec2, index: usize) {
+        if let Some(idx) = self.cell_of(position) {
+            self.cells.insert(idx, position, index);
+        }
+    }
+
+    /// Inserts `index` into every cell `rect` overlaps, for items too large to
+    /// file under a single point. Queries may then report it more than once.
+    /// Its stored position is the centre of `rect`.
+    pub fn insert_rect(&mut self, rect: Rectangle, index: usize) {
+        let center = Vec2::new(rect.x + rect.width / 2.0, rect.y + rect.height / 2.0);
+        let (min_cx, min_cy, max_cx, max_cy) = self.cell_range(rect);
+        for cy in min_cy..=max_cy {
+            for cx in min_cx..=max_cx {
+                if let Some(idx) = self.cell_index(cx, cy) {
+                    self.cells.insert(idx, center, index);
+                }
+            }
         }
     }
 
+    /// Cell coordinates `(min_cx, min_cy, max_cx, max_cy)` covered by `rect`,
+    /// clipped to the grid.
+    fn cell_range(&self, rect: Rectangle) -> (isize, isize, isize, isize) {
+        let (min_cx, min_cy) = self.cell_coords(rect.x, rect.y);
+        let (max_cx, max_cy) = self.cell_coords(rect.x + rect.width, rect.y + rect.height);
+        (
+            min_cx.max(0),
+            min_cy.max(0),
+            max_cx.min(self.grid_width as isize - 1),
+            max_cy.min(self.grid_height as isize - 1),
+        )
+    }
+
     pub fn query<F>(&self, rect: Rectangle, mut callback: F)
     where
         F: FnMut(usize),
     {
-        let min_cx =
-            ((rect.x - self.bounds.x) * self.inv_cell_size).floor() as isize;
-        let min_cy =
-            ((rect.y - self.bounds.y) * self.inv_cell_size).floor() as isize;
-        let max_cx = ((rect.x + rect.width - self.bounds.x) * self.inv_cell_size).floor() as isize;
-        let max_cy = ((rect.y + rect.height - self.bounds.y) * self.inv_cell_size).floor() as isize;
+        self.query_cells(rect, |cell| {
+            for &index in cell {
+                callback(index);
+            }
+        });
+    }
+
+    /// Like `query`, but hands over the indices one cell at a time.
+    pub fn query_cells<F>(&self, rect: Rectangle, mut callback: F)
+    where
+        F: FnMut(&[usize]),
+    {
+        let (min_cx, min_cy, max_cx, max_cy) = self.cell_range(rect);
+        for cy in min_cy..=max_cy {
+            for cx in min_cx..=max_cx {
+                if let Some(idx) = self.cell_index(cx, cy) {
+                    callback(self.cells.cell(idx).0);
+                }
+            }
+        }
+    }
 
+    /// Calls `callback(index, distance_squared)` for every item within
+    /// `radius` of `center`, skipping cells the circle doesn't reach.
+    pub fn query_radius<F>(&self, center: Vec2, radius: f32, mut callback: F)
+    where
+        F: FnMut(usize, f32),
+    {
+        let radius_sq = radius * radius;
+        let rect = Rectangle::new(
+            center.x - radius,
+            center.y - radius,
+            radius * 2.0,
+            radius * 2.0,
+        );
+        let (min_cx, min_cy, max_cx, max_cy) = self.cell_range(rect);
         for cy in min_cy..=max_cy {
             for cx in min_cx..=max_cx {
+                let cell = Rectangle::new(
+                    self.bounds.x + cx as f32 * self.cell_size,
+                    self.bounds.y + cy as f32 * self.cell_size,
+                    self.cell_size,
+                    self.cell_size,
+                );
+                if cell.distance_squared_to(center) > radius_sq {
+                    continue;
+                }
                 if let Some(idx) = self.cell_index(cx, cy) {
-                    for &index in &self.cells[idx] {
-                        callback(index);
+                    let (indices, positions) = self.cells.cell(idx);
+                    for (&index, &position) in indices.iter().zip(positions) {
+                        let distance_squared = (position - center).length_squared();
+                        if distance_squared <= radius_sq {
+                            callback(index, distance_squared);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Finds the `k` nearest items to `center` that `accept` lets through,
+    /// searching rings of cells outwards from the one holding `center` until
+    /// no unvisited cell can hold anything nearer. `found` is refilled with
+    /// `(distance_squared, index)` pairs, nearest first; it ends up shorter
+    /// than `k` only if the whole grid holds fewer acceptable items.
+    pub fn query_nearest<A>(
+        &self,
+        center: Vec2,
+        k: usize,
+        mut accept: A,
+        found: &mut Vec<(f32, usize)>,
+    ) where
+        A: FnMut(usize) -> bool,
+    {
+        found.clear();
+        if k == 0 {
+            return;
+        }
+        let (cx, cy) = self.cell_coords(center.x, center.y);
+        let cx = cx.clamp(0, self.grid_width as isize - 1);
+        let cy = cy.clamp(0, self.grid_height as isize - 1);
+        let max_ring = self.grid_width.max(self.grid_height) as isize;
+
+        let mut visit = |cell_x: isize, cell_y: isize, found: &mut Vec<(f32, usize)>| {
+            let (indices, positions) = match self.cell_index(cell_x, cell_y) {
+                Some(idx) => self.cells.cell(idx),
+                None => return,
+            };
+            for (&index, &position) in indices.iter().zip(positions) {
+                if accept(index) {
+                    let distance_squared = (position - center).length_squared();
+                    push_nearest(found, k, distance_squared, index);
+                }
+            }
+        };
+
+        for ring in 0..=max_ring {
+            if ring > 0 && found.len() == k {
+                // Everything in this ring lies outside the block of cells
+                // already searched, so at least this far from `center`. Sides
+                // of the block at the edge of the grid have nothing beyond.
+                let (first_x, last_x) = (cx - ring + 1, cx + ring - 1);
+                let (first_y, last_y) = (cy - ring + 1, cy + ring - 1);
+                let edge = |outside: bool, cells: isize, start: f32, beyond: f32| {
+                    if outside {
+                        beyond
+                    } else {
+                        start + cells as f32 * self.cell_size
                     }
+                };
+                let left = edge(first_x <= 0, first_x, self.bounds.x, f32::NEG_INFINITY);
+                let right = edge(
+                    last_x >= self.grid_width as isize - 1,
+                    last_x + 1,
+                    self.bounds.x,
+                    f32::INFINITY,
+                );
+                let bottom = edge(first_y <= 0, first_y, self.bounds.y, f32::NEG_INFINITY);
+                let top = edge(
+                    last_y >= self.grid_height as isize - 1,
+                    last_y + 1,
+                    self.bounds.y,
+                    f32::INFINITY,
+                );
+                let reach = (center.x - left)
+                    .min(right - center.x)
+                    .min(center.y - bottom)
+                    .min(top - center.y)
+                    .max(0.0);
+                if found[k - 1].0 <= reach * reach {
+                    break;
+                }
+            }
+            if ring == 0 {
+                visit(cx, cy, found);
+                continue;
+            }
+            for dx in -ring..=ring {
+                visit(cx + dx, cy - ring, found);
+                visit(cx + dx, cy + ring, found);
+            }
+            for dy in (-ring + 1)..ring {
+                visit(cx - ring, cy + dy, found);
+                visit(cx + ring, cy + dy, found);
+            }
+        }
+    }
+}
+
+impl SpatialIndex for SpatialHashGrid {
+    fn clear(&mut self) {
+        SpatialHashGrid::clear(self);
+    }
+
+    fn insert(&mut self, position: Vec2, index: usize) {
+        SpatialHashGrid::insert(self, position, index);
+    }
+
+    fn rebuild<P>(&mut self, count: usize, position: P)
+    where
+        P: Fn(usize) -> Vec2 + Sync,
+    {
+        SpatialHashGrid::rebuild(self, count, position);
+    }
+
+    fn query<F>(&self, rect: Rectangle, callback: F)
+    where
+        F: FnMut(usize),
+    {
+        SpatialHashGrid::query(self, rect, callback);
+    }
+
+    fn query_radius<P, F>(&self, center: Vec2, radius: f32, _position: P, callback: F)
+    where
+        P: Fn(usize) -> Vec2,
+        F: FnMut(usize, f32),
+    {
+        SpatialHashGrid::query_radius(self, center, radius, callback);
+    }
+
+    fn query_nearest<P, A>(
+        &self,
+        center: Vec2,
+        k: usize,
+        _position: P,
+        accept: A,
+        found: &mut Vec<(f32, usize)>,
+    ) where
+        P: Fn(usize) -> Vec2,
+        A: FnMut(usize) -> bool,
+    {
+        SpatialHashGrid::query_nearest(self, center, k, accept, found);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    fn bounds() -> Rectangle {
+        Rectangle::new(-100.0, -50.0, 200.0, 100.0)
+    }
+
+    fn random_points(count: usize, seed: u64) -> Vec<Vec2> {
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let bounds = bounds();
+        (0..count)
+            .map(|_| {
+                Vec2::new(
+                    rng.gen_range(bounds.x..bounds.x + bounds.width),
+                    rng.gen_range(bounds.y..bounds.y + bounds.height),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn query_radius_matches_brute_force() {
+        let points = random_points(2000, 1);
+        let mut grid = SpatialHashGrid::new(bounds(), 12.0);
+        grid.rebuild(points.len(), |i| points[i]);
+        let mut rng = ChaCha8Rng::seed_from_u64(2);
+        for _ in 0..200 {
+            let center = Vec2::new(rng.gen_range(-120.0..120.0), rng.gen_range(-70.0..70.0));
+            let radius = rng.gen_range(0.0..40.0);
+            let mut found = Vec::new();
+            grid.query_radius(center, radius, |i, distance_squared| {
+                assert_eq!(distance_squared, (points[i] - center).length_squared());
+                found.push(i);
+            });
+            found.sort_unstable();
+            let expected: Vec<usize> = (0..points.len())
+                .filter(|&i| (points[i] - center).length_squared() <= radius * radius)
+                .collect();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn query_reports_every_point_in_the_rect() {
+        let points = random_points(2000, 3);
+        let mut grid = SpatialHashGrid::new(bounds(), 12.0);
+        for (i, &point) in points.iter().enumerate() {
+            grid.insert(point, i);
+        }
+        let mut rng = ChaCha8Rng::seed_from_u64(4);
+        for _ in 0..200 {
+            let rect = Rectangle::new(
+                rng.gen_range(-120.0..100.0),
+                rng.gen_range(-70.0..50.0),
+                rng.gen_range(0.0..60.0),
+                rng.gen_range(0.0..60.0),
+            );
+            let mut found = Vec::new();
+            grid.query(rect, |i| found.push(i));
+            found.sort_unstable();
+            found.dedup();
+            for (i, &point) in points.iter().enumerate() {
+                if rect.point_inside_rect(point) {
+                    assert!(found.binary_search(&i).is_ok());
                 }
             }
         }
     }
+
+    #[test]
+    fn points_outside_the_bounds_are_dropped() {
+        let mut grid = SpatialHashGrid::new(bounds(), 12.0);
+        grid.insert(Vec2::new(150.0, 0.0), 0);
+        grid.insert(Vec2::new(0.0, 0.0), 1);
+        let mut found = Vec::new();
+        grid.query_radius(Vec2::new(0.0, 0.0), 1000.0, |i, _| found.push(i));
+        assert_eq!(found, [1]);
+    }
+
+    fn rebuild_cells(threads: usize, points: &[Vec2], num_cells: usize) -> CellLists {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        let mut cells = CellLists::new(num_cells);
+        pool.install(|| {
+            cells.rebuild(
+                points.len(),
+                |i| points[i],
+                |position| {
+                    let cell = ((position.x + 100.0) / 200.0 * num_cells as f32).floor();
+                    (0.0..num_cells as f32)
+                        .contains(&cell)
+                        .then_some(cell as usize)
+                },
+            )
+        });
+        cells
+    }
+
+    #[test]
+    fn cell_lists_rebuild_the_same_with_any_task_count() {
+        // Enough items for four tasks, some of them outside every cell.
+        let mut points = random_points(5 * MIN_ITEMS_PER_TASK, 5);
+        points.extend(
+            random_points(1000, 6)
+                .iter()
+                .map(|p| *p + Vec2::new(250.0, 0.0)),
+        );
+        for num_cells in [1, 3, 64, 1000] {
+            let serial = rebuild_cells(1, &points, num_cells);
+            let parallel = rebuild_cells(4, &points, num_cells);
+            assert_eq!((serial.tasks.len(), parallel.tasks.len()), (1, 4));
+            assert_eq!(serial.cell_start, parallel.cell_start);
+            assert_eq!(serial.indices, parallel.indices);
+            assert_eq!(serial.positions, parallel.positions);
+            assert_eq!(serial.indices.len(), 5 * MIN_ITEMS_PER_TASK);
+            for cell in 0..num_cells {
+                let (indices, positions) = parallel.cell(cell);
+                assert!(indices.windows(2).all(|pair| pair[0] < pair[1]));
+                assert!(indices.iter().zip(positions).all(|(&i, &p)| points[i] == p));
+            }
+        }
+    }
 }
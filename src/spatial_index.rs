@@ -0,0 +1,545 @@
+use crate::hashed_grid::HashedGrid;
+use crate::quadtree::{Indexed, QuadTree};
+use crate::spatial_hash::{Rectangle, SpatialHashGrid};
+use glam::Vec2;
+
+/// A structure for finding items near a point. Items are identified by index;
+/// an index may keep its own copy of their positions or look them up through
+/// the `position` callbacks, so callers must always pass one.
+pub trait SpatialIndex {
+    fn clear(&mut self);
+
+    fn insert(&mut self, position: Vec2, index: usize);
+
+    /// Empties the index and files items `0..count`, item `i` at
+    /// `position(i)`. Indexes that can build in bulk override this.
+    fn rebuild<P>(&mut self, count: usize, position: P)
+    where
+        P: Fn(usize) -> Vec2 + Sync,
+    {
+        self.clear();
+        for index in 0..count {
+            self.insert(position(index), index);
+        }
+    }
+
+    /// Calls `callback(index)` for every item inside `rect`. May also report
+    /// items just outside it.
+    fn query<F>(&self, rect: Rectangle, callback: F)
+    where
+        F: FnMut(usize);
+
+    /// Calls `callback(index, distance_squared)` for every item within
+    /// `radius` of `center`.
+    fn query_radius<P, F>(&self, center: Vec2, radius: f32, position: P, callback: F)
+    where
+        P: Fn(usize) -> Vec2,
+        F: FnMut(usize, f32);
+
+    /// Refills `found` with the `k` items nearest to `center` that `accept`
+    /// lets through, as `(distance_squared, index)` pairs, nearest first.
+    fn query_nearest<P, A>(
+        &self,
+        center: Vec2,
+        k: usize,
+        position: P,
+        accept: A,
+        found: &mut Vec<(f32, usize)>,
+    ) where
+        P: Fn(usize) -> Vec2,
+        A: FnMut(usize) -> bool;
+}
+
+/// Adds `item` to `found`, a list of at most `k` items sorted by distance,
+/// if it is nearer than the current k-th. Equally distant items keep the
+/// order they were offered in.
+pub(crate) fn push_nearest<T>(found: &mut Vec<(f32, T)>, k: usize, distance_squared: f32, item: T) {
+    if found.len() == k && distance_squared >= found[k - 1].0 {
+        return;
+    }
+    let at = found.partition_point(|&(d, _)| d <= distance_squared);
+    found.insert(at, (distance_squared, item));
+    found.truncate(k);
+}
+
+/// The space boids move in, which decides the offset from one point to
+/// another and which items a query reaches.
+#[derive(Debug, Copy, Clone)]
+pub enum Topology {
+    Plane,
+    /// `bounds` with opposite edges joined, as `BoundaryMode::Wrap` makes it.
+    /// Offsets take the shortest way round, and queries near an edge also
+    /// reach items just across it.
+    Torus(Rectangle),
+}
+
+impl Topology {
+    /// Where `point` lies in the space: on a torus, the copy of it inside
+    /// the bounds.
+    pub fn wrap(&self, point: Vec2) -> Vec2 {
+        match *self {
+            Topology::Plane => point,
+            Topology::Torus(bounds) => Vec2::new(
+                wrap_coordinate(point.x, bounds.x, bounds.width),
+                wrap_coordinate(point.y, bounds.y, bounds.height),
+            ),
+        }
+    }
+
+    /// The shortest vector from `from` to `to`.
+    pub fn offset(&self, from: Vec2, to: Vec2) -> Vec2 {
+        let offset = to - from;
+        match *self {
+            Topology::Plane => offset,
+            Topology::Torus(bounds) => Vec2::new(
+                shortest(offset.x, bounds.width),
+                shortest(offset.y, bounds.height),
+            ),
+        }
+    }
+
+    /// The copies of `center` a whole period apart, each with its squared
+    /// distance to the bounds, starting with the wrapped `center` itself.
+    /// Querying around every copy within reach of the bounds also finds the
+    /// items across an edge.
+    fn images(&self, center: Vec2) -> impl Iterator<Item = (Vec2, f32)> {
+        let center = self.wrap(center);
+        let (bounds, shifts): (_, &[f32]) = match *self {
+            Topology::Plane => (None, &[0.0]),
+            Topology::Torus(bounds) => (Some(bounds), &[0.0, -1.0, 1.0]),
+        };
+        shifts.iter().flat_map(move |&dy| {
+            shifts.iter().map(move |&dx| match bounds {
+                None => (center, 0.0),
+                Some(bounds) => {
+                    let image = center + Vec2::new(dx * bounds.width, dy * bounds.height);
+                    (image, bounds.distance_squared_to(image))
+                }
+            })
+        })
+    }
+
+    /// `SpatialIndex::query_radius` in this space; `distance_squared` is
+    /// measured the shortest way round. On a torus the radius is capped at
+    /// half the shorter side of the bounds, so nothing is reported twice.
+    pub fn query_radius<I, P, F>(
+        &self,
+        index: &I,
+        center: Vec2,
+        radius: f32,
+        position: P,
+        mut callback: F,
+    ) where
+        I: SpatialIndex + ?Sized,
+        P: Fn(usize) -> Vec2,
+        F: FnMut(usize, f32),
+    {
+        let radius = match *self {
+            Topology::Plane => radius,
+            Topology::Torus(bounds) => {
+                let center = self.wrap(center);
+                let clearance = (center.x - bounds.x)
+                    .min(bounds.x + bounds.width - center.x)
+                    .min(center.y - bounds.y)
+                    .min(bounds.y + bounds.height - center.y);
+                if radius < clearance {
+                    // Clear of every edge; no need to look across one.
+                    index.query_radius(center, radius, position, callback);
+                    return;
+                }
+                radius.min(0.5 * bounds.width.min(bounds.height))
+            }
+        };
+        for (image, distance_squared) in self.images(center) {
+            if distance_squared <= radius * radius {
+                index.query_radius(image, radius, &position, &mut callback);
+            }
+        }
+    }
+
+    /// `SpatialIndex::query_nearest` in this space; distances are measured
+    /// the shortest way round.
+    pub fn query_nearest<I, P, A>(
+        &self,
+        index: &I,
+        center: Vec2,
+        k: usize,
+        position: P,
+        mut accept: A,
+        found: &mut Vec<(f32, usize)>,
+    ) where
+        I: SpatialIndex + ?Sized,
+        P: Fn(usize) -> Vec2,
+        A: FnMut(usize) -> bool,
+    {
+        found.clear();
+        if k == 0 {
+            return;
+        }
+        let mut images = self.images(center);
+        if let Some((image, _)) = images.next() {
+            index.query_nearest(image, k, &position, &mut accept, found);
+        }
+        let mut across = Vec::new();
+        for (image, distance_squared) in images {
+            // Only look across an edge nearer than the k-th item found.
+            if found.len() == k && distance_squared >= found[k - 1].0 {
+                continue;
+            }
+            index.query_nearest(image, k, &position, &mut accept, &mut across);
+            for &(distance_squared, item) in &across {
+                // With few items the same one may turn up in several images;
+                // keep the nearest.
+                if let Some(at) = found.iter().position(|&(_, other)| other == item) {
+                    if distance_squared >= found[at].0 {
+                        continue;
+                    }
+                    found.remove(at);
+                }
+                push_nearest(found, k, distance_squared, item);
+            }
+        }
+    }
+}
+
+/// `distance` plus or minus whole multiples of `period`, whichever is
+/// nearest zero.
+fn shortest(distance: f32, period: f32) -> f32 {
+    if distance.abs() <= 0.5 * period {
+        distance
+    } else {
+        distance - period * (distance / period).round()
+    }
+}
+
+/// `value` moved by whole multiples of `length` into
+/// `[start, start + length)`.
+fn wrap_coordinate(value: f32, start: f32, length: f32) -> f32 {
+    let wrapped = start + (value - start).rem_euclid(length);
+    // Rounding can land a value just below `start` on the far edge.
+    if wrapped >= start + length {
+        start
+    } else {
+        wrapped
+    }
+}
+
+/// Which `SpatialIndex` a `Simulation` files its boids in.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum IndexKind {
+    /// A uniform grid of cells the size of the largest perception radius.
+    #[default]
+    Grid,
+    QuadTree,
+    /// A grid over the whole plane, for boids that leave the bounds.
+    Hashed,
+}
+
+/// One of the built-in indexes, chosen at runtime.
+pub enum AnyIndex {
+    Grid(SpatialHashGrid),
+    QuadTree(QuadTree<Indexed>),
+    Hashed(HashedGrid),
+}
+
+impl AnyIndex {
+    /// An empty index of `kind` covering `bounds`. `cell_size` is only used by
+    /// kinds that have cells.
+    pub fn new(kind: IndexKind, bounds: Rectangle, cell_size: f32) -> Self {
+        match kind {
+            IndexKind::Grid => AnyIndex::Grid(SpatialHashGrid::new(bounds, cell_size)),
+            IndexKind::QuadTree => AnyIndex::QuadTree(QuadTree::new(bounds)),
+            IndexKind::Hashed => AnyIndex::Hashed(HashedGrid::covering(bounds, cell_size)),
+        }
+    }
+
+    pub fn kind(&self) -> IndexKind {
+        match self {
+            AnyIndex::Grid(_) => IndexKind::Grid,
+            AnyIndex::QuadTree(_) => IndexKind::QuadTree,
+            AnyIndex::Hashed(_) => IndexKind::Hashed,
+        }
+    }
+
+    /// The cell size, for kinds that have cells.
+    pub fn cell_size(&self) -> Option<f32> {
+        match self {
+            AnyIndex::Grid(grid) => Some(grid.cell_size()),
+            AnyIndex::QuadTree(_) => None,
+            AnyIndex::Hashed(grid) => Some(grid.cell_size()),
+        }
+    }
+}
+
+impl SpatialIndex for AnyIndex {
+    fn clear(&mut self) {
+        match self {
+            AnyIndex::Grid(grid) => SpatialIndex::clear(grid),
+            AnyIndex::QuadTree(tree) => SpatialIndex::clear(tree),
+            AnyIndex::Hashed(grid) => SpatialIndex::clear(grid),
+        }
+    }
+
+    fn insert(&mut self, position: Vec2, index: usize) {
+        match self {
+            AnyIndex::Grid(grid) => SpatialIndex::insert(grid, position, index),
+            AnyIndex::QuadTree(tree) => SpatialIndex::insert(tree, position, index),
+            AnyIndex::Hashed(grid) => SpatialIndex::insert(grid, position, index),
+        }
+    }
+
+    fn rebuild<P>(&mut self, count: usize, position: P)
+    where
+        P: Fn(usize) -> Vec2 + Sync,
+    {
+        match self {
+            AnyIndex::Grid(grid) => SpatialIndex::rebuild(grid, count, position),
+            AnyIndex::QuadTree(tree) => SpatialIndex::rebuild(tree, count, position),
+            AnyIndex::Hashed(grid) => SpatialIndex::rebuild(grid, count, position),
+        }
+    }
+
+    fn query<F>(&self, rect: Rectangle, callback: F)
+    where
+        F: FnMut(usize),
+    {
+        match self {
+            AnyIndex::Grid(grid) => SpatialIndex::query(grid, rect, callback),
+            AnyIndex::QuadTree(tree) => SpatialIndex::query(tree, rect, callback),
+            AnyIndex::Hashed(grid) => SpatialIndex::query(grid, rect, callback),
+        }
+    }
+
+    fn query_radius<P, F>(&self, center: Vec2, radius: f32, position: P, callback: F)
+    where
+        P: Fn(usize) -> Vec2,
+        F: FnMut(usize, f32),
+    {
+        match self {
+            AnyIndex::Grid(grid) => {
+                SpatialIndex::query_radius(grid, center, radius, position, callback)
+            }
+            AnyIndex::QuadTree(tree) => {
+                SpatialIndex::query_radius(tree, center, radius, position, callback)
+            }
+            AnyIndex::Hashed(grid) => {
+                SpatialIndex::query_radius(grid, center, radius, position, callback)
+            }
+        }
+    }
+
+    fn query_nearest<P, A>(
+        &self,
+        center: Vec2,
+        k: usize,
+        position: P,
+        accept: A,
+        found: &mut Vec<(f32, usize)>,
+    ) where
+        P: Fn(usize) -> Vec2,
+        A: FnMut(usize) -> bool,
+    {
+        match self {
+            AnyIndex::Grid(grid) => {
+                SpatialIndex::query_nearest(grid, center, k, position, accept, found)
+            }
+            AnyIndex::QuadTree(tree) => {
+                SpatialIndex::query_nearest(tree, center, k, position, accept, found)
+            }
+            AnyIndex::Hashed(grid) => {
+                SpatialIndex::query_nearest(grid, center, k, position, accept, found)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    const KINDS: [IndexKind; 3] = [IndexKind::Grid, IndexKind::QuadTree, IndexKind::Hashed];
+    const CELL_SIZE: f32 = 10.0;
+
+    fn bounds() -> Rectangle {
+        Rectangle::new(-100.0, -50.0, 200.0, 100.0)
+    }
+
+    fn index(kind: IndexKind, points: &[Vec2]) -> AnyIndex {
+        let mut index = AnyIndex::new(kind, bounds(), CELL_SIZE);
+        index.rebuild(points.len(), |i| points[i]);
+        index
+    }
+
+    fn random_points(count: usize, seed: u64) -> Vec<Vec2> {
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let bounds = bounds();
+        (0..count)
+            .map(|_| {
+                Vec2::new(
+                    rng.gen_range(bounds.x..bounds.x + bounds.width),
+                    rng.gen_range(bounds.y..bounds.y + bounds.height),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn torus_offsets_take_the_shortest_way_round() {
+        let torus = Topology::Torus(bounds());
+        let offset = |from: (f32, f32), to: (f32, f32)| {
+            torus.offset(Vec2::new(from.0, from.1), Vec2::new(to.0, to.1))
+        };
+        assert_eq!(offset((-98.0, 0.0), (97.0, 0.0)), Vec2::new(-5.0, 0.0));
+        assert_eq!(offset((97.0, 0.0), (-98.0, 0.0)), Vec2::new(5.0, 0.0));
+        assert_eq!(offset((0.0, -49.0), (0.0, 48.0)), Vec2::new(0.0, -3.0));
+        assert_eq!(offset((-99.0, -49.0), (99.0, 49.0)), Vec2::new(-2.0, -2.0));
+        assert_eq!(offset((-10.0, 0.0), (10.0, 0.0)), Vec2::new(20.0, 0.0));
+        assert_eq!(
+            Topology::Plane.offset(Vec2::new(-98.0, 0.0), Vec2::new(97.0, 0.0)),
+            Vec2::new(195.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn torus_queries_find_points_across_the_edges() {
+        let torus = Topology::Torus(bounds());
+        // Pairs on opposite edges and corners, each a few units apart the
+        // short way round.
+        let points = [
+            Vec2::new(-98.0, 0.0),
+            Vec2::new(97.0, 0.0),
+            Vec2::new(30.0, -49.0),
+            Vec2::new(30.0, 48.0),
+            Vec2::new(-99.0, -49.0),
+            Vec2::new(99.0, 49.0),
+        ];
+        for kind in KINDS {
+            let index = index(kind, &points);
+            for (i, pair) in [(0, 1), (1, 0), (2, 3), (3, 2), (4, 5), (5, 4)] {
+                let center = points[i];
+                let expected = torus.offset(center, points[pair]).length_squared();
+                let mut found = Vec::new();
+                torus.query_radius(
+                    &index,
+                    center,
+                    6.0,
+                    |j| points[j],
+                    |j, d| found.push((j, d)),
+                );
+                found.sort_by_key(|&(j, _)| j);
+                let mut want = vec![(i, 0.0), (pair, expected)];
+                want.sort_by_key(|&(j, _)| j);
+                assert_eq!(found, want, "{:?} around {}", kind, i);
+
+                let mut nearest = Vec::new();
+                torus.query_nearest(&index, center, 1, |j| points[j], |j| j != i, &mut nearest);
+                assert_eq!(nearest, [(expected, pair)], "{:?} around {}", kind, i);
+                assert!(torus.offset(center, points[pair]).length() < 6.0);
+            }
+        }
+    }
+
+    #[test]
+    fn torus_query_radius_matches_brute_force() {
+        let torus = Topology::Torus(bounds());
+        let points = random_points(1500, 7);
+        let mut rng = ChaCha8Rng::seed_from_u64(8);
+        for kind in KINDS {
+            let index = index(kind, &points);
+            for _ in 0..100 {
+                let center = Vec2::new(rng.gen_range(-100.0..100.0), rng.gen_range(-50.0..50.0));
+                let radius: f32 = rng.gen_range(0.0..30.0);
+                let mut found = Vec::new();
+                torus.query_radius(&index, center, radius, |j| points[j], |j, _| found.push(j));
+                found.sort_unstable();
+                let expected: Vec<usize> = (0..points.len())
+                    .filter(|&j| torus.offset(center, points[j]).length() <= radius)
+                    .collect();
+                assert_eq!(found, expected, "{:?}", kind);
+            }
+        }
+    }
+
+    /// Checks `found` against every point: the right count, true distances,
+    /// no repeats, and no accepted point left out that is nearer than the
+    /// farthest one found.
+    fn assert_nearest(
+        points: &[Vec2],
+        center: Vec2,
+        k: usize,
+        accept: impl Fn(usize) -> bool,
+        found: &[(f32, usize)],
+    ) {
+        let mut expected: Vec<f32> = (0..points.len())
+            .filter(|&j| accept(j))
+            .map(|j| (points[j] - center).length_squared())
+            .collect();
+        expected.sort_by(f32::total_cmp);
+        expected.truncate(k);
+        let distances: Vec<f32> = found.iter().map(|&(d, _)| d).collect();
+        assert_eq!(distances, expected);
+        let mut seen: Vec<usize> = found.iter().map(|&(_, j)| j).collect();
+        seen.sort_unstable();
+        seen.dedup();
+        assert_eq!(seen.len(), found.len());
+        for &(d, j) in found {
+            assert!(accept(j));
+            assert_eq!(d, (points[j] - center).length_squared());
+        }
+    }
+
+    #[test]
+    fn query_nearest_matches_brute_force() {
+        let points = random_points(1000, 10);
+        let mut rng = ChaCha8Rng::seed_from_u64(11);
+        let odd = |j: usize| j % 2 == 1;
+        for kind in KINDS {
+            let index = index(kind, &points);
+            let mut found = Vec::new();
+            for _ in 0..100 {
+                let center = Vec2::new(rng.gen_range(-120.0..120.0), rng.gen_range(-70.0..70.0));
+                let k = rng.gen_range(0..40);
+                index.query_nearest(center, k, |j| points[j], |_| true, &mut found);
+                assert_nearest(&points, center, k, |_| true, &found);
+                index.query_nearest(center, k, |j| points[j], odd, &mut found);
+                assert_nearest(&points, center, k, odd, &found);
+            }
+        }
+    }
+
+    #[test]
+    fn query_nearest_handles_ties_and_small_populations() {
+        // A lattice, so many points sit at exactly the same distance.
+        let points: Vec<Vec2> = (-5..5)
+            .flat_map(|y| (-5..5).map(move |x| Vec2::new(x as f32 * 4.0, y as f32 * 4.0)))
+            .collect();
+        for kind in KINDS {
+            let lattice = index(kind, &points);
+            let mut found = Vec::new();
+            for center in [
+                Vec2::new(0.0, 0.0),
+                Vec2::new(2.0, 2.0),
+                Vec2::new(-90.0, 40.0),
+            ] {
+                for k in [1, 4, 5, 9, 99, 100, 150] {
+                    lattice.query_nearest(center, k, |j| points[j], |_| true, &mut found);
+                    assert_nearest(&points, center, k, |_| true, &found);
+                }
+            }
+            // More wanted than there are points to find.
+            let few = [
+                Vec2::new(1.0, 1.0),
+                Vec2::new(-1.0, -1.0),
+                Vec2::new(60.0, 0.0),
+            ];
+            let sparse = index(kind, &few);
+            sparse.query_nearest(Vec2::new(0.0, 0.0), 10, |j| few[j], |_| true, &mut found);
+            assert_nearest(&few, Vec2::new(0.0, 0.0), 10, |_| true, &found);
+            assert_eq!(found.len(), 3);
+            sparse.query_nearest(Vec2::new(0.0, 0.0), 10, |j| few[j], |j| j != 0, &mut found);
+            assert_eq!(found.len(), 2);
+        }
+    }
+}
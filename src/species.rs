@@ -0,0 +1,202 @@
+use crate::config::SimulationConfig;
+
+/// Index into a `SpeciesTable`. Species 0 always exists.
+pub type SpeciesId = u16;
+
+/// How a boid reacts to a neighbour of some species.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Interaction {
+    /// The neighbour is invisible.
+    Ignore,
+    /// Keep away from the neighbour (out to `follow_radius`) but don't
+    /// flock with it.
+    Avoid,
+    /// Separate from, cohere with and align to the neighbour.
+    #[default]
+    Flock,
+}
+
+/// Interaction used between a newly added species and every existing one of
+/// the same role. Predators and prey ignore each other's flocking rules and
+/// interact through `Predation` instead.
+pub const DEFAULT_CROSS_INTERACTION: Interaction = Interaction::Avoid;
+
+/// Whether a species hunts or is hunted.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Role {
+    #[default]
+    Prey,
+    Predator,
+}
+
+/// Per-species parameters plus the matrix of how each species reacts to the
+/// others. The matrix need not be symmetric: `interaction(a, b)` is how boids
+/// of species `a` treat boids of species `b`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpeciesTable {
+    configs: Vec<SimulationConfig>,
+    roles: Vec<Role>,
+    interactions: Vec<Interaction>,
+}
+
+impl SpeciesTable {
+    pub fn new(config: SimulationConfig) -> Self {
+        SpeciesTable {
+            configs: vec![config],
+            roles: vec![Role::Prey],
+            interactions: vec![Interaction::Flock],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.configs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.configs.is_empty()
+    }
+
+    /// Adds a prey species that flocks with its own kind and treats every
+    /// other species (and is treated by them) as `DEFAULT_CROSS_INTERACTION`.
+    pub fn add(&mut self, config: SimulationConfig) -> SpeciesId {
+        self.add_with_role(config, Role::Prey)
+    }
+
+    /// Adds a species with the given role. It flocks with its own kind, uses
+    /// `DEFAULT_CROSS_INTERACTION` with species of the same role and ignores
+    /// species of the other role.
+    pub fn add_with_role(&mut self, config: SimulationConfig, role: Role) -> SpeciesId {
+        let old = self.len();
+        let new = old + 1;
+        let mut interactions = vec![DEFAULT_CROSS_INTERACTION; new * new];
+        for a in 0..old {
+            for b in 0..old {
+                interactions[a * new + b] = self.interactions[a * old + b];
+            }
+            if self.roles[a] != role {
+                interactions[a * new + old] = Interaction::Ignore;
+                interactions[old * new + a] = Interaction::Ignore;
+            }
+        }
+        interactions[old * new + old] = Interaction::Flock;
+        self.interactions = interactions;
+        self.configs.push(config);
+        self.roles.push(role);
+        old as SpeciesId
+    }
+
+    pub fn config(&self, species: SpeciesId) -> &SimulationConfig {
+        &self.configs[species as usize]
+    }
+
+    pub fn configs(&self) -> &[SimulationConfig] {
+        &self.configs
+    }
+
+    pub(crate) fn set_config(&mut self, species: SpeciesId, config: SimulationConfig) {
+        self.configs[species as usize] = config;
+    }
+
+    pub fn role(&self, species: SpeciesId) -> Role {
+        self.roles[species as usize]
+    }
+
+    /// Changes a species' role without touching the interaction matrix.
+    pub fn set_role(&mut self, species: SpeciesId, role: Role) {
+        self.roles[species as usize] = role;
+    }
+
+    pub fn has_predators(&self) -> bool {
+        self.roles.contains(&Role::Predator)
+    }
+
+    /// Panics if either species is not in the table.
+    pub fn interaction(&self, species: SpeciesId, other: SpeciesId) -> Interaction {
+        self.interactions[self.cell(species, other)]
+    }
+
+    /// Panics if either species is not in the table.
+    pub fn set_interaction(
+        &mut self,
+        species: SpeciesId,
+        other: SpeciesId,
+        interaction: Interaction,
+    ) {
+        let cell = self.cell(species, other);
+        self.interactions[cell] = interaction;
+    }
+
+    /// Where `interaction(species, other)` lives in the row-major matrix.
+    fn cell(&self, species: SpeciesId, other: SpeciesId) -> usize {
+        let len = self.len();
+        assert!(
+            (species as usize) < len && (other as usize) < len,
+            "species {species} or {other} not in a table of {len}"
+        );
+        species as usize * len + other as usize
+    }
+
+    /// The largest perception radius of any species, which the grid's cells
+    /// must cover.
+    pub fn max_perception_radius(&self) -> f32 {
+        self.configs
+            .iter()
+            .map(SimulationConfig::perception_radius)
+            .fold(0.0, f32::max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_species_get_default_interactions() {
+        let mut table = SpeciesTable::new(SimulationConfig::default());
+        let second = table.add(SimulationConfig::default());
+        let predators = table.add_with_role(SimulationConfig::default(), Role::Predator);
+        assert_eq!((second, predators), (1, 2));
+        for species in 0..3 {
+            assert_eq!(table.interaction(species, species), Interaction::Flock);
+        }
+        assert_eq!(table.interaction(0, 1), DEFAULT_CROSS_INTERACTION);
+        assert_eq!(table.interaction(1, 0), DEFAULT_CROSS_INTERACTION);
+        for prey in 0..2 {
+            assert_eq!(table.interaction(prey, predators), Interaction::Ignore);
+            assert_eq!(table.interaction(predators, prey), Interaction::Ignore);
+        }
+    }
+
+    #[test]
+    fn interactions_are_directed_and_survive_growth() {
+        let mut table = SpeciesTable::new(SimulationConfig::default());
+        table.add(SimulationConfig::default());
+        table.set_interaction(0, 1, Interaction::Ignore);
+        table.set_interaction(1, 0, Interaction::Flock);
+        assert_eq!(table.interaction(0, 1), Interaction::Ignore);
+        assert_eq!(table.interaction(1, 0), Interaction::Flock);
+
+        // Growing the matrix keeps every existing entry in its place.
+        table.add(SimulationConfig::default());
+        assert_eq!(table.interaction(0, 1), Interaction::Ignore);
+        assert_eq!(table.interaction(1, 0), Interaction::Flock);
+        assert_eq!(table.interaction(0, 2), DEFAULT_CROSS_INTERACTION);
+        assert_eq!(table.interaction(2, 1), DEFAULT_CROSS_INTERACTION);
+    }
+
+    #[test]
+    #[should_panic(expected = "not in a table of 2")]
+    fn interaction_rejects_unknown_species() {
+        let mut table = SpeciesTable::new(SimulationConfig::default());
+        table.add(SimulationConfig::default());
+        // In a flat matrix (0, 2) would silently read (1, 0).
+        table.interaction(0, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "not in a table of 1")]
+    fn set_interaction_rejects_unknown_species() {
+        let mut table = SpeciesTable::new(SimulationConfig::default());
+        table.set_interaction(1, 0, Interaction::Avoid);
+    }
+}